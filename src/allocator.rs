@@ -1,7 +1,14 @@
-use core::{alloc::Layout, ptr::NonNull};
+use core::{alloc::Layout, ptr, ptr::NonNull};
 
+pub mod buddy;
 pub mod bump;
+pub mod debug;
 pub mod free_list;
+pub mod leak;
+pub mod pool;
+pub mod segregated;
+pub mod stats;
+pub mod tlsf;
 
 /// `Layout` に基づいてメモリを確保/解放するための、低レベルなアロケータ。selfの可変参照を引数にとる。
 ///
@@ -38,6 +45,92 @@ pub trait MutAllocator {
     /// - すでに `dealloc` された領域を再度 `dealloc` してはいけません（二重解放は禁止）。
     /// - `dealloc` 呼び出し後、`ptr` が指していた領域へアクセスしてはいけません（use-after-free 禁止）。
     unsafe fn dealloc(&mut self, ptr: NonNull<u8>, layout: Layout);
+
+    /// `ptr` が指す領域を `new_layout` の大きさに拡張します。
+    ///
+    /// 成功した場合、先頭の `old_layout.size()` バイトの内容は保持されます。
+    /// 返ったポインタが `ptr` と異なる場合、古い領域はすでに解放されています。
+    /// 失敗（`None`）した場合、古い領域はそのまま有効です。
+    ///
+    /// デフォルト実装は「新しく確保 → コピー → 古い領域を解放」です。
+    /// その場で拡張できるアロケータは上書きしてください。
+    ///
+    /// # Safety
+    /// - `ptr` は同じアロケータの `alloc`（または `grow`/`shrink`）が返したポインタでなければなりません。
+    /// - `old_layout` は、その `ptr` を得たときの `Layout` でなければなりません。
+    /// - `new_layout.size()` は `old_layout.size()` 以上でなければなりません。
+    unsafe fn grow(
+        &mut self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Option<NonNull<[u8]>> {
+        debug_assert!(new_layout.size() >= old_layout.size());
+
+        let new_ptr = unsafe { self.alloc(new_layout)? };
+        unsafe {
+            ptr::copy_nonoverlapping(
+                ptr.as_ptr(),
+                new_ptr.cast::<u8>().as_ptr(),
+                old_layout.size(),
+            );
+            self.dealloc(ptr, old_layout);
+        }
+
+        Some(new_ptr)
+    }
+
+    /// `grow` と同じですが、拡張された部分（`old_layout.size()` 以降）を 0 で埋めます。
+    ///
+    /// # Safety
+    /// `grow` と同じです。
+    unsafe fn grow_zeroed(
+        &mut self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Option<NonNull<[u8]>> {
+        let new_ptr = unsafe { self.grow(ptr, old_layout, new_layout)? };
+        unsafe {
+            new_ptr
+                .cast::<u8>()
+                .add(old_layout.size())
+                .write_bytes(0, new_layout.size() - old_layout.size());
+        }
+
+        Some(new_ptr)
+    }
+
+    /// `ptr` が指す領域を `new_layout` の大きさに縮小します。
+    ///
+    /// 成功した場合、先頭の `new_layout.size()` バイトの内容は保持されます。
+    /// 返ったポインタが `ptr` と異なる場合、古い領域はすでに解放されています。
+    /// 失敗（`None`）した場合、古い領域はそのまま有効です。
+    ///
+    /// # Safety
+    /// - `ptr` は同じアロケータの `alloc`（または `grow`/`shrink`）が返したポインタでなければなりません。
+    /// - `old_layout` は、その `ptr` を得たときの `Layout` でなければなりません。
+    /// - `new_layout.size()` は `old_layout.size()` 以下でなければなりません。
+    unsafe fn shrink(
+        &mut self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Option<NonNull<[u8]>> {
+        debug_assert!(new_layout.size() <= old_layout.size());
+
+        let new_ptr = unsafe { self.alloc(new_layout)? };
+        unsafe {
+            ptr::copy_nonoverlapping(
+                ptr.as_ptr(),
+                new_ptr.cast::<u8>().as_ptr(),
+                new_layout.size(),
+            );
+            self.dealloc(ptr, old_layout);
+        }
+
+        Some(new_ptr)
+    }
 }
 
 impl<A: MutAllocator + ?Sized> MutAllocator for &mut A {
@@ -48,4 +141,47 @@ impl<A: MutAllocator + ?Sized> MutAllocator for &mut A {
     unsafe fn dealloc(&mut self, ptr: NonNull<u8>, layout: Layout) {
         unsafe { <A as MutAllocator>::dealloc(&mut **self, ptr, layout) }
     }
+
+    unsafe fn grow(
+        &mut self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Option<NonNull<[u8]>> {
+        unsafe {
+            <A as MutAllocator>::grow(&mut **self, ptr, old_layout, new_layout)
+        }
+    }
+
+    unsafe fn grow_zeroed(
+        &mut self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Option<NonNull<[u8]>> {
+        unsafe {
+            <A as MutAllocator>::grow_zeroed(
+                &mut **self,
+                ptr,
+                old_layout,
+                new_layout,
+            )
+        }
+    }
+
+    unsafe fn shrink(
+        &mut self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Option<NonNull<[u8]>> {
+        unsafe {
+            <A as MutAllocator>::shrink(
+                &mut **self,
+                ptr,
+                old_layout,
+                new_layout,
+            )
+        }
+    }
 }
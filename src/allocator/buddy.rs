@@ -0,0 +1,523 @@
+use core::{
+    alloc::Layout,
+    mem,
+    ptr::{self, NonNull},
+};
+
+use crate::{
+    allocator::{MutAllocator, stats::SourceUsage},
+    source::MemorySource,
+};
+
+/// 指定できる最小のオーダー。空きブロックにリンク（`FreeBlock`）を置ける大きさ。
+pub const MIN_ORDER: u32 = mem::size_of::<FreeBlock>().trailing_zeros();
+
+/// オーダーの数の上限（free list の配列の長さ）
+const ORDERS: usize = usize::BITS as usize;
+
+/// 2 の冪のブロックを分割・結合するバディアロケータ。
+///
+/// source から `2^max_order` バイトのアリーナを `2^max_order` でアラインして取り、
+/// 要求を満たす最小の `2^k`（`min_order <= k <= max_order`）のブロックになるまで半分に分割する。
+/// 解放時は、相方（バディ）も同じオーダーで空いていれば結合していく。
+/// `2^max_order` を超える確保は `None` を返す。
+///
+/// 各アリーナの後ろにはヘッダと「オーダーごとの空きブロック」を表すビットマップを置くので、
+/// 結合の判定はユーザのデータに依存しない。
+pub struct BuddyAllocator<S: MemorySource> {
+    source: S,
+    min_order: u32,
+    max_order: u32,
+
+    /// オーダーごとの空きブロックのリスト（双方向）
+    free: [Option<NonNull<FreeBlock>>; ORDERS],
+
+    arenas: Option<NonNull<ArenaHeader>>,
+}
+
+/// 空きブロックの先頭に置くリンク
+struct FreeBlock {
+    prev: Option<NonNull<FreeBlock>>,
+    next: Option<NonNull<FreeBlock>>,
+}
+
+/// アリーナのブロック領域の直後に置くヘッダ。この後ろにビットマップが続く。
+struct ArenaHeader {
+    next: Option<NonNull<ArenaHeader>>,
+    ptr: NonNull<u8>,
+    layout: Layout,
+}
+
+unsafe impl<S: MemorySource + Send> Send for BuddyAllocator<S> {}
+
+impl<S: MemorySource> BuddyAllocator<S> {
+    /// 最小ブロック `2^min_order` バイト、アリーナ `2^max_order` バイトのバディアロケータを作る。
+    ///
+    /// # Panics
+    /// `min_order < MIN_ORDER`、`min_order > max_order`、または `max_order` が大きすぎる場合。
+    pub const fn new(source: S, min_order: u32, max_order: u32) -> Self {
+        assert!(min_order >= MIN_ORDER, "min_order is too small");
+        assert!(min_order <= max_order, "min_order must be <= max_order");
+        assert!(max_order < usize::BITS - 1, "max_order is too large");
+
+        Self {
+            source,
+            min_order,
+            max_order,
+            free: [None; ORDERS],
+            arenas: None,
+        }
+    }
+
+    #[inline]
+    const fn arena_size(&self) -> usize {
+        1 << self.max_order
+    }
+
+    /// `layout` を満たすブロックのオーダー。大きすぎれば `None`。
+    #[inline]
+    fn order_of(&self, layout: Layout) -> Option<u32> {
+        let size = layout
+            .size()
+            .max(layout.align())
+            .checked_next_power_of_two()?;
+        let order = size.trailing_zeros().max(self.min_order);
+        (order <= self.max_order).then_some(order)
+    }
+
+    /// ビットマップのビット数。オーダー k のブロックは `2^(max - k)` 個あるので、
+    /// 全オーダー合わせて `2^(max - min + 1) - 1` 個。
+    #[inline]
+    fn bitmap_bits(&self) -> usize {
+        (1 << (self.max_order - self.min_order + 1)) - 1
+    }
+
+    /// オーダー `order`、アリーナ先頭からのオフセット `offset` のブロックのビット番号
+    #[inline]
+    fn bit_index(&self, order: u32, offset: usize) -> usize {
+        (1 << (self.max_order - order)) - 1 + (offset >> order)
+    }
+
+    /// アリーナの中の `ptr` から、アリーナのヘッダを得る
+    #[inline]
+    fn arena_of(&self, ptr: NonNull<u8>) -> NonNull<ArenaHeader> {
+        let base = ptr.as_ptr().addr() & !(self.arena_size() - 1);
+        let offset = ptr.as_ptr().addr() - base;
+        unsafe { ptr.sub(offset).add(self.arena_size()) }.cast::<ArenaHeader>()
+    }
+
+    #[inline]
+    unsafe fn bitmap(arena: NonNull<ArenaHeader>) -> NonNull<u64> {
+        unsafe { arena.add(1) }.cast::<u64>()
+    }
+
+    unsafe fn is_free(
+        &self,
+        arena: NonNull<ArenaHeader>,
+        order: u32,
+        offset: usize,
+    ) -> bool {
+        let i = self.bit_index(order, offset);
+        let word = unsafe { Self::bitmap(arena).add(i / 64).read() };
+        word & (1 << (i % 64)) != 0
+    }
+
+    unsafe fn set_free(
+        &self,
+        arena: NonNull<ArenaHeader>,
+        order: u32,
+        offset: usize,
+        free: bool,
+    ) {
+        let i = self.bit_index(order, offset);
+        let mut word = unsafe { Self::bitmap(arena).add(i / 64) };
+        let bit = 1 << (i % 64);
+        unsafe {
+            if free {
+                *word.as_mut() |= bit;
+            } else {
+                *word.as_mut() &= !bit;
+            }
+        }
+    }
+
+    /// ブロックをオーダー `order` の free list に積み、空きとして印を付ける
+    unsafe fn push_free(&mut self, block: NonNull<u8>, order: u32) {
+        let arena = self.arena_of(block);
+        let base = unsafe { arena.cast::<u8>().sub(self.arena_size()) };
+        let offset = block.as_ptr().addr() - base.as_ptr().addr();
+
+        let node = block.cast::<FreeBlock>();
+        let head = self.free[order as usize];
+        unsafe {
+            node.write(FreeBlock {
+                prev: None,
+                next: head,
+            });
+            if let Some(mut h) = head {
+                h.as_mut().prev = Some(node);
+            }
+            self.set_free(arena, order, offset, true);
+        }
+        self.free[order as usize] = Some(node);
+    }
+
+    /// ブロックをオーダー `order` の free list から外し、使用中として印を付ける
+    unsafe fn remove_free(&mut self, block: NonNull<u8>, order: u32) {
+        let arena = self.arena_of(block);
+        let base = unsafe { arena.cast::<u8>().sub(self.arena_size()) };
+        let offset = block.as_ptr().addr() - base.as_ptr().addr();
+
+        let node = unsafe { block.cast::<FreeBlock>().read() };
+        unsafe {
+            match node.prev {
+                None => self.free[order as usize] = node.next,
+                Some(mut p) => p.as_mut().next = node.next,
+            }
+            if let Some(mut n) = node.next {
+                n.as_mut().prev = node.prev;
+            }
+            self.set_free(arena, order, offset, false);
+        }
+    }
+
+    /// source から新しいアリーナを取り、最大オーダーの空きブロックとして積む
+    unsafe fn add_arena(&mut self) -> Option<()> {
+        let bitmap_bytes = self.bitmap_bits().div_ceil(64) * 8;
+        let size = self
+            .arena_size()
+            .checked_add(mem::size_of::<ArenaHeader>())?
+            .checked_add(bitmap_bytes)?;
+        let request = Layout::from_size_align(size, self.arena_size()).ok()?;
+
+        let chunk = unsafe { self.source.request_chunk(request) }?;
+        let base = chunk.cast::<u8>();
+        let actual_layout =
+            Layout::from_size_align(chunk.len(), request.align()).ok()?;
+
+        // アドレスのマスクでアリーナを求めるので、アラインされていないと使えない
+        if base.as_ptr().addr() & (self.arena_size() - 1) != 0 {
+            unsafe { self.source.release_chunk(base, actual_layout) };
+            return None;
+        }
+
+        let arena =
+            unsafe { base.add(self.arena_size()) }.cast::<ArenaHeader>();
+        unsafe {
+            arena.write(ArenaHeader {
+                next: self.arenas,
+                ptr: base,
+                layout: actual_layout,
+            });
+            Self::bitmap(arena)
+                .cast::<u8>()
+                .write_bytes(0, bitmap_bytes);
+        }
+        self.arenas = Some(arena);
+
+        unsafe { self.push_free(base, self.max_order) };
+        Some(())
+    }
+
+    /// オーダー `order` のブロックを 1 つ取り出す。足りなければ上のオーダーを分割する
+    unsafe fn take_block(&mut self, order: u32) -> Option<NonNull<u8>> {
+        let mut from = order;
+        while from <= self.max_order && self.free[from as usize].is_none() {
+            from += 1;
+        }
+        if from > self.max_order {
+            unsafe { self.add_arena()? };
+            from = self.max_order;
+        }
+
+        let block = self.free[from as usize]?.cast::<u8>();
+        unsafe { self.remove_free(block, from) };
+
+        // 後ろ半分を空きとして戻しながら半分にしていく
+        while from > order {
+            from -= 1;
+            unsafe { self.push_free(block.add(1 << from), from) };
+        }
+
+        Some(block)
+    }
+
+    /// オーダー `order` のブロックを、空いているバディと結合しながら free list に戻す
+    unsafe fn free_block(&mut self, block: NonNull<u8>, mut order: u32) {
+        let arena = self.arena_of(block);
+        let base = unsafe { arena.cast::<u8>().sub(self.arena_size()) };
+        let mut offset = block.as_ptr().addr() - base.as_ptr().addr();
+
+        while order < self.max_order {
+            let buddy = offset ^ (1 << order);
+            if !unsafe { self.is_free(arena, order, buddy) } {
+                break;
+            }
+
+            unsafe { self.remove_free(base.add(buddy), order) };
+            offset &= !(1 << order);
+            order += 1;
+        }
+
+        unsafe { self.push_free(base.add(offset), order) };
+    }
+
+    /// 使用中のブロックをその場で `from` から `to` のオーダーに広げられるか
+    unsafe fn can_grow_in_place(
+        &self,
+        block: NonNull<u8>,
+        from: u32,
+        to: u32,
+    ) -> bool {
+        let arena = self.arena_of(block);
+        let base = unsafe { arena.cast::<u8>().sub(self.arena_size()) };
+        let offset = block.as_ptr().addr() - base.as_ptr().addr();
+
+        // 各段で自分が前半で、後半のバディが空いていること
+        (from..to).all(|order| {
+            offset & (1 << order) == 0
+                && unsafe { self.is_free(arena, order, offset | (1 << order)) }
+        })
+    }
+}
+
+impl<S: MemorySource> MutAllocator for BuddyAllocator<S> {
+    unsafe fn alloc(&mut self, layout: Layout) -> Option<NonNull<[u8]>> {
+        // ZST は適当な non-null を返す
+        if layout.size() == 0 {
+            let p = ptr::without_provenance_mut::<u8>(layout.align());
+            let nn = unsafe { NonNull::new_unchecked(p) };
+            return Some(NonNull::slice_from_raw_parts(nn, 0));
+        }
+
+        let order = self.order_of(layout)?;
+        let block = unsafe { self.take_block(order)? };
+
+        Some(NonNull::slice_from_raw_parts(block, layout.size()))
+    }
+
+    unsafe fn dealloc(&mut self, ptr: NonNull<u8>, layout: Layout) {
+        if layout.size() == 0 {
+            return;
+        }
+
+        let Some(order) = self.order_of(layout) else {
+            return;
+        };
+        unsafe { self.free_block(ptr, order) };
+    }
+
+    unsafe fn grow(
+        &mut self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Option<NonNull<[u8]>> {
+        if old_layout.size() != 0
+            && let Some(from) = self.order_of(old_layout)
+            && let Some(to) = self.order_of(new_layout)
+            && from <= to
+            && unsafe { self.can_grow_in_place(ptr, from, to) }
+        {
+            // 後半のバディを吸収する
+            for order in from..to {
+                unsafe { self.remove_free(ptr.add(1 << order), order) };
+            }
+            return Some(NonNull::slice_from_raw_parts(ptr, new_layout.size()));
+        }
+
+        let new_ptr = unsafe { self.alloc(new_layout)? };
+        unsafe {
+            ptr::copy_nonoverlapping(
+                ptr.as_ptr(),
+                new_ptr.cast::<u8>().as_ptr(),
+                old_layout.size(),
+            );
+            self.dealloc(ptr, old_layout);
+        }
+
+        Some(new_ptr)
+    }
+
+    unsafe fn shrink(
+        &mut self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Option<NonNull<[u8]>> {
+        if new_layout.size() != 0
+            && let Some(from) = self.order_of(old_layout)
+            && let Some(to) = self.order_of(new_layout)
+            && to <= from
+        {
+            // 後ろ半分を空きとして戻していく（相方の前半は使用中なので結合は起きない）
+            for order in (to..from).rev() {
+                unsafe { self.push_free(ptr.add(1 << order), order) };
+            }
+            return Some(NonNull::slice_from_raw_parts(ptr, new_layout.size()));
+        }
+
+        let new_ptr = unsafe { self.alloc(new_layout)? };
+        unsafe {
+            ptr::copy_nonoverlapping(
+                ptr.as_ptr(),
+                new_ptr.cast::<u8>().as_ptr(),
+                new_layout.size(),
+            );
+            self.dealloc(ptr, old_layout);
+        }
+
+        Some(new_ptr)
+    }
+}
+
+impl<S: MemorySource> SourceUsage for BuddyAllocator<S> {
+    fn source_bytes(&self) -> usize {
+        let mut total = 0;
+        let mut current = self.arenas;
+
+        while let Some(node_ptr) = current {
+            let node = unsafe { node_ptr.as_ref() };
+            total += node.layout.size();
+            current = node.next;
+        }
+
+        total
+    }
+}
+
+impl<S: MemorySource> Drop for BuddyAllocator<S> {
+    fn drop(&mut self) {
+        let mut current = self.arenas;
+
+        while let Some(arena) = current {
+            unsafe {
+                let header = arena.read();
+                current = header.next;
+
+                self.source.release_chunk(header.ptr, header.layout);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::source::os_heap::OsHeap;
+
+    fn addr(p: NonNull<[u8]>) -> usize {
+        p.cast::<u8>().as_ptr().addr()
+    }
+
+    fn free_count<S: MemorySource>(a: &BuddyAllocator<S>, order: u32) -> usize {
+        let mut n = 0;
+        let mut cur = a.free[order as usize];
+        while let Some(b) = cur {
+            n += 1;
+            cur = unsafe { b.as_ref().next };
+        }
+        n
+    }
+
+    #[test]
+    fn split_and_merge_back() {
+        let mut a = BuddyAllocator::new(OsHeap, 4, 12);
+
+        let l = Layout::from_size_align(16, 8).unwrap();
+        let p = unsafe { a.alloc(l).unwrap() };
+
+        // 4096 を 16 まで分割すると、各オーダーに 1 つずつ後ろ半分が残る
+        for order in 4..12 {
+            assert_eq!(free_count(&a, order), 1);
+        }
+        assert_eq!(free_count(&a, 12), 0);
+
+        unsafe { a.dealloc(p.cast::<u8>(), l) };
+        for order in 4..12 {
+            assert_eq!(free_count(&a, order), 0);
+        }
+        assert_eq!(free_count(&a, 12), 1);
+    }
+
+    #[test]
+    fn buddies_are_adjacent_and_aligned() {
+        let mut a = BuddyAllocator::new(OsHeap, 4, 12);
+
+        let l = Layout::from_size_align(100, 8).unwrap();
+        let p1 = unsafe { a.alloc(l).unwrap() };
+        let p2 = unsafe { a.alloc(l).unwrap() };
+
+        assert_eq!(addr(p1) % 128, 0);
+        assert_eq!(addr(p1) ^ addr(p2), 128);
+    }
+
+    #[test]
+    fn does_not_merge_with_allocated_buddy() {
+        let mut a = BuddyAllocator::new(OsHeap, 4, 12);
+
+        let l = Layout::from_size_align(32, 8).unwrap();
+        let p1 = unsafe { a.alloc(l).unwrap() };
+        let _p2 = unsafe { a.alloc(l).unwrap() };
+
+        unsafe { a.dealloc(p1.cast::<u8>(), l) };
+        assert_eq!(free_count(&a, 5), 1);
+        assert_eq!(free_count(&a, 12), 0);
+    }
+
+    #[test]
+    fn too_large_is_rejected() {
+        let mut a = BuddyAllocator::new(OsHeap, 4, 12);
+
+        let l = Layout::from_size_align(4097, 8).unwrap();
+        assert!(unsafe { a.alloc(l) }.is_none());
+    }
+
+    #[test]
+    fn new_arena_when_exhausted() {
+        let mut a = BuddyAllocator::new(OsHeap, 4, 12);
+
+        let l = Layout::from_size_align(4096, 8).unwrap();
+        let p1 = unsafe { a.alloc(l).unwrap() };
+        let p2 = unsafe { a.alloc(l).unwrap() };
+        assert_ne!(addr(p1), addr(p2));
+
+        unsafe {
+            a.dealloc(p1.cast::<u8>(), l);
+            a.dealloc(p2.cast::<u8>(), l);
+        }
+        assert_eq!(free_count(&a, 12), 2);
+    }
+
+    #[test]
+    fn grow_in_place_absorbs_free_buddy() {
+        let mut a = BuddyAllocator::new(OsHeap, 4, 12);
+
+        let old = Layout::from_size_align(16, 8).unwrap();
+        let new = Layout::from_size_align(64, 8).unwrap();
+        let p = unsafe { a.alloc(old).unwrap() }.cast::<u8>();
+
+        let q = unsafe { a.grow(p, old, new).unwrap() };
+        assert_eq!(q.cast::<u8>(), p);
+        assert_eq!(free_count(&a, 4), 0);
+        assert_eq!(free_count(&a, 5), 0);
+
+        unsafe { a.dealloc(p, new) };
+        assert_eq!(free_count(&a, 12), 1);
+    }
+
+    #[test]
+    fn shrink_in_place_frees_upper_halves() {
+        let mut a = BuddyAllocator::new(OsHeap, 4, 12);
+
+        let old = Layout::from_size_align(256, 8).unwrap();
+        let new = Layout::from_size_align(16, 8).unwrap();
+        let p = unsafe { a.alloc(old).unwrap() }.cast::<u8>();
+
+        let q = unsafe { a.shrink(p, old, new).unwrap() };
+        assert_eq!(q.cast::<u8>(), p);
+
+        unsafe { a.dealloc(p, new) };
+        assert_eq!(free_count(&a, 12), 1);
+    }
+}
@@ -1,6 +1,12 @@
 use core::{alloc::Layout, ptr, ptr::NonNull};
 
-use crate::{allocator::MutAllocator, source::MemorySource};
+use crate::{
+    allocator::{MutAllocator, stats::SourceUsage},
+    source::MemorySource,
+};
+
+#[cfg(all(feature = "std", target_os = "linux"))]
+mod file;
 
 pub struct BumpAllocator<S: MemorySource> {
     source: S,
@@ -60,6 +66,68 @@ impl<S: MemorySource> MutAllocator for BumpAllocator<S> {
     }
 
     unsafe fn dealloc(&mut self, _ptr: NonNull<u8>, _layout: Layout) {}
+
+    unsafe fn grow(
+        &mut self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Option<NonNull<[u8]>> {
+        // 直前の確保で、カーソルの手前にある場合はその場で伸ばす
+        if self.is_last(ptr, old_layout)
+            && ptr.as_ptr().align_offset(new_layout.align()) == 0
+        {
+            let remaining = self.end.as_ptr().addr() - ptr.as_ptr().addr();
+            if new_layout.size() <= remaining {
+                self.ptr = unsafe { ptr.add(new_layout.size()) };
+                return Some(NonNull::slice_from_raw_parts(
+                    ptr,
+                    new_layout.size(),
+                ));
+            }
+        }
+
+        let new_ptr = unsafe { self.alloc(new_layout)? };
+        unsafe {
+            ptr::copy_nonoverlapping(
+                ptr.as_ptr(),
+                new_ptr.cast::<u8>().as_ptr(),
+                old_layout.size(),
+            );
+        }
+
+        Some(new_ptr)
+    }
+
+    unsafe fn shrink(
+        &mut self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Option<NonNull<[u8]>> {
+        if new_layout.size() == 0 {
+            return unsafe { self.alloc(new_layout) };
+        }
+
+        if ptr.as_ptr().align_offset(new_layout.align()) != 0 {
+            let new_ptr = unsafe { self.alloc(new_layout)? };
+            unsafe {
+                ptr::copy_nonoverlapping(
+                    ptr.as_ptr(),
+                    new_ptr.cast::<u8>().as_ptr(),
+                    new_layout.size(),
+                );
+            }
+            return Some(new_ptr);
+        }
+
+        // 直前の確保なら、縮めた分だけカーソルを戻す
+        if self.is_last(ptr, old_layout) {
+            self.ptr = unsafe { ptr.add(new_layout.size()) };
+        }
+
+        Some(NonNull::slice_from_raw_parts(ptr, new_layout.size()))
+    }
 }
 
 impl<S: MemorySource> BumpAllocator<S> {
@@ -71,6 +139,12 @@ impl<S: MemorySource> BumpAllocator<S> {
             head: None,
         }
     }
+
+    /// `ptr` が直前の確保（末尾がカーソルに一致する確保）かどうか
+    fn is_last(&self, ptr: NonNull<u8>, layout: Layout) -> bool {
+        layout.size() != 0
+            && ptr.as_ptr().addr() + layout.size() == self.ptr.as_ptr().addr()
+    }
     /// バッファ内に空きがない場合、新しいチャンクを作成し、データ部のポインタを返す
     fn new_chunk(&mut self, layout: Layout) -> Option<NonNull<[u8]>> {
         debug_assert!(self.ptr.as_ptr().addr() <= self.end.as_ptr().addr());
@@ -118,6 +192,106 @@ impl<S: MemorySource> BumpAllocator<S> {
 
         Some(NonNull::slice_from_raw_parts(user_start_ptr, layout.size()))
     }
+
+    /// 現在のチャンクとカーソルの位置を記録したマーカーを返す。
+    pub fn checkpoint(&self) -> Checkpoint {
+        Checkpoint {
+            chunk: self.head,
+            ptr: self.ptr,
+            end: self.end,
+        }
+    }
+
+    /// カーソルを `checkpoint` の位置まで戻し、それ以降に取得したチャンクを source に返す。
+    ///
+    /// # Safety
+    /// - `checkpoint` はこのアロケータの `checkpoint` が返したものでなければなりません。
+    /// - `checkpoint` 以降に確保した領域へは、以後アクセスしてはいけません。
+    /// - `checkpoint` より前に戻す `rewind` や `reset` を呼んだ後に、
+    ///   その `checkpoint` を使ってはいけません。
+    pub unsafe fn rewind(&mut self, checkpoint: Checkpoint) {
+        while let Some(node_ptr) = self.head {
+            if Some(node_ptr) == checkpoint.chunk {
+                break;
+            }
+
+            unsafe {
+                let node = node_ptr.read();
+                self.head = node.next;
+
+                self.source
+                    .release_chunk(node.ptr.cast::<u8>(), node.layout);
+            }
+        }
+
+        debug_assert!(self.head == checkpoint.chunk);
+
+        self.ptr = checkpoint.ptr;
+        self.end = checkpoint.end;
+    }
+
+    /// 一番大きいチャンクだけを残して他を source に返し、カーソルをその先頭に戻す。
+    ///
+    /// # Safety
+    /// - これまでに確保したすべての領域へは、以後アクセスしてはいけません。
+    pub unsafe fn reset(&mut self) {
+        let mut largest: Option<NonNull<ChunkNode>> = None;
+        let mut largest_size = 0;
+        let mut current = self.head;
+
+        while let Some(node_ptr) = current {
+            let node = unsafe { node_ptr.as_ref() };
+            if largest.is_none() || node.layout.size() > largest_size {
+                largest = Some(node_ptr);
+                largest_size = node.layout.size();
+            }
+            current = node.next;
+        }
+
+        let mut current = self.head;
+        while let Some(node_ptr) = current {
+            unsafe {
+                let node = node_ptr.read();
+                current = node.next;
+
+                if Some(node_ptr) != largest {
+                    self.source
+                        .release_chunk(node.ptr.cast::<u8>(), node.layout);
+                }
+            }
+        }
+
+        self.head = largest;
+        match largest {
+            Some(mut node_ptr) => unsafe {
+                let node = node_ptr.as_mut();
+                node.next = None;
+
+                let chunk_ptr = node.ptr;
+                self.ptr = chunk_ptr.add(size_of::<ChunkNode>());
+                self.end = chunk_ptr.add(node.layout.size());
+            },
+            None => {
+                self.ptr = NonNull::dangling();
+                self.end = NonNull::dangling();
+            }
+        }
+    }
+}
+
+impl<S: MemorySource> SourceUsage for BumpAllocator<S> {
+    fn source_bytes(&self) -> usize {
+        let mut total = 0;
+        let mut current = self.head;
+
+        while let Some(node_ptr) = current {
+            let node = unsafe { node_ptr.as_ref() };
+            total += node.layout.size();
+            current = node.next;
+        }
+
+        total
+    }
 }
 
 impl<S: MemorySource> Drop for BumpAllocator<S> {
@@ -142,36 +316,33 @@ pub struct ChunkNode {
     layout: Layout,
 }
 
+/// `BumpAllocator::checkpoint` が返す、確保位置のマーカー。
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Checkpoint {
+    chunk: Option<NonNull<ChunkNode>>,
+    ptr: NonNull<u8>,
+    end: NonNull<u8>,
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use core::alloc::Layout;
     use core::ptr::NonNull;
     use std::alloc::{alloc, dealloc};
-    use std::cell::RefCell;
-    use std::rc::Rc;
     use std::vec;
     use std::vec::Vec;
 
-    #[derive(Default, Debug)]
-    struct Stats {
-        requested: usize,
-        released: usize,
-    }
+    use crate::source::stats::CountingSource;
 
+    /// 返されたチャンクが本当に渡したものかを確かめる source
     struct MockSource {
-        stats: Rc<RefCell<Stats>>,
         /// 生きてるチャンク
         live: Vec<(NonNull<u8>, Layout)>,
     }
 
-    impl MockSource {
-        fn new(stats: Rc<RefCell<Stats>>) -> Self {
-            Self {
-                stats,
-                live: vec![],
-            }
-        }
+    fn mock() -> CountingSource<MockSource> {
+        CountingSource::new(MockSource { live: vec![] })
     }
 
     impl MemorySource for MockSource {
@@ -188,7 +359,6 @@ mod tests {
             let ptr = unsafe { alloc(layout) };
             let nn = NonNull::new(ptr)?;
             self.live.push((nn, layout));
-            self.stats.borrow_mut().requested += 1;
 
             Some(NonNull::slice_from_raw_parts(nn, layout.size()))
         }
@@ -207,15 +377,13 @@ mod tests {
 
             let (p, l) = self.live.swap_remove(idx);
             unsafe { dealloc(p.as_ptr(), l) };
-            self.stats.borrow_mut().released += 1;
         }
     }
 
-    fn make_allocator_with_initial_chunk(
-        stats: Rc<RefCell<Stats>>,
+    fn make_allocator_with_initial_chunk<S: MemorySource>(
+        mut source: S,
         chunk_size: usize,
-    ) -> BumpAllocator<MockSource> {
-        let mut source = MockSource::new(stats);
+    ) -> BumpAllocator<S> {
         let head_layout = Layout::new::<ChunkNode>();
 
         // 少なくとも ChunkNode を置けるサイズにする
@@ -253,8 +421,7 @@ mod tests {
 
     #[test]
     fn alloc_respects_alignment() {
-        let stats = Rc::new(RefCell::new(Stats::default()));
-        let mut a = make_allocator_with_initial_chunk(stats, 4096);
+        let mut a = make_allocator_with_initial_chunk(mock(), 4096);
 
         let layout = Layout::from_size_align(24, 64).unwrap();
         let p = unsafe { a.alloc(layout).unwrap() };
@@ -265,8 +432,7 @@ mod tests {
 
     #[test]
     fn bump_allocates_monotonically_when_it_fits() {
-        let stats = Rc::new(RefCell::new(Stats::default()));
-        let mut a = make_allocator_with_initial_chunk(stats, 4096);
+        let mut a = make_allocator_with_initial_chunk(mock(), 4096);
 
         let l1 = Layout::from_size_align(16, 8).unwrap();
         let l2 = Layout::from_size_align(32, 8).unwrap();
@@ -281,28 +447,26 @@ mod tests {
 
     #[test]
     fn alloc_grows_into_new_chunks_when_out_of_space() {
-        let stats = Rc::new(RefCell::new(Stats::default()));
-        let mut a = make_allocator_with_initial_chunk(stats.clone(), 128);
+        let mut a = make_allocator_with_initial_chunk(mock(), 128);
 
         // 128だとヘッダ + ちょっとで埋まるので、数回 alloc で増えるはず
         let l = Layout::from_size_align(80, 8).unwrap();
         let _ = unsafe { a.alloc(l).unwrap() };
         let _ = unsafe { a.alloc(l).unwrap() };
 
-        let st = stats.borrow();
+        let st = a.source.stats();
         assert!(
-            st.requested >= 2,
+            st.requests >= 2,
             "should have requested at least 2 chunks, got {}",
-            st.requested
+            st.requests
         );
     }
 
     #[test]
     fn drop_releases_all_chunks() {
-        let stats = Rc::new(RefCell::new(Stats::default()));
-
+        let mut source = mock();
         {
-            let mut a = make_allocator_with_initial_chunk(stats.clone(), 128);
+            let mut a = make_allocator_with_initial_chunk(&mut source, 128);
             let l = Layout::from_size_align(80, 8).unwrap();
 
             // 複数チャンクを作る
@@ -311,18 +475,17 @@ mod tests {
             let _ = unsafe { a.alloc(l).unwrap() };
         } // drop で release_chunk が走る
 
-        let st = stats.borrow();
+        let st = source.stats();
         assert_eq!(
-            st.released, st.requested,
+            st.releases, st.requests,
             "all requested chunks should be released (requested={}, released={})",
-            st.requested, st.released
+            st.requests, st.releases
         );
     }
 
     #[test]
     fn zst_allocation_returns_len_zero_slice() {
-        let stats = Rc::new(RefCell::new(Stats::default()));
-        let mut a = make_allocator_with_initial_chunk(stats, 4096);
+        let mut a = make_allocator_with_initial_chunk(mock(), 4096);
 
         let l = Layout::from_size_align(0, 8).unwrap();
         let p = unsafe { a.alloc(l).unwrap() };
@@ -331,9 +494,8 @@ mod tests {
 
     #[test]
     fn alloc_huge_object() {
-        let stats = Rc::new(RefCell::new(Stats::default()));
         // 最初は小さいチャンクしか持ってないアロケーターを作る
-        let mut a = make_allocator_with_initial_chunk(stats.clone(), 128);
+        let mut a = make_allocator_with_initial_chunk(mock(), 128);
 
         let huge_layout = Layout::from_size_align(10000, 16).unwrap();
         let p = unsafe { a.alloc(huge_layout).unwrap() };
@@ -342,20 +504,18 @@ mod tests {
         // ちゃんと確保できてること
         assert!(addr(p) > 0);
 
-        let st = stats.borrow();
-        assert!(st.requested >= 2);
+        let st = a.source.stats();
+        assert!(st.requests >= 2);
     }
 
     #[test]
     fn alloc_fits_exact_remaining_space() {
-        let stats = Rc::new(RefCell::new(Stats::default()));
         // わかりやすく、ユーザー領域がぴったり 64バイト ある状態を作る
         // (ヘッダサイズ + 64バイト)
         let head_size = Layout::new::<ChunkNode>().size();
         let total_size = head_size + 64;
 
-        let mut a =
-            make_allocator_with_initial_chunk(stats.clone(), total_size);
+        let mut a = make_allocator_with_initial_chunk(mock(), total_size);
 
         // 32バイト確保 (残り32)
         let l32 = Layout::from_size_align(32, 1).unwrap();
@@ -366,7 +526,7 @@ mod tests {
 
         // ここまででチャンク追加は発生してないはず (requested == 1)
         assert_eq!(
-            stats.borrow().requested,
+            a.source.stats().requests,
             1,
             "Should fit exactly without new chunk"
         );
@@ -375,16 +535,134 @@ mod tests {
         let _ = unsafe { a.alloc(l1).unwrap() };
 
         assert_eq!(
-            stats.borrow().requested,
+            a.source.stats().requests,
             2,
             "Should allocate new chunk now"
         );
     }
 
+    #[test]
+    fn grow_last_allocation_in_place() {
+        let mut a = make_allocator_with_initial_chunk(mock(), 4096);
+
+        let old = Layout::from_size_align(16, 8).unwrap();
+        let new = Layout::from_size_align(64, 8).unwrap();
+        let p = unsafe { a.alloc(old).unwrap() }.cast::<u8>();
+        unsafe { p.write_bytes(0xAB, 16) };
+
+        let q = unsafe { a.grow(p, old, new).unwrap() };
+        assert_eq!(addr(q), p.as_ptr().addr());
+        assert_eq!(q.len(), 64);
+        assert_eq!(unsafe { q.cast::<u8>().add(15).read() }, 0xAB);
+
+        // 伸ばした分の直後から次の確保が始まる
+        let r = unsafe { a.alloc(old).unwrap() };
+        assert_eq!(addr(r), addr(q) + 64);
+        assert_eq!(a.source.stats().requests, 1);
+    }
+
+    #[test]
+    fn grow_non_last_allocation_copies() {
+        let mut a = make_allocator_with_initial_chunk(mock(), 4096);
+
+        let old = Layout::from_size_align(16, 8).unwrap();
+        let new = Layout::from_size_align(64, 8).unwrap();
+        let p = unsafe { a.alloc(old).unwrap() }.cast::<u8>();
+        unsafe { p.write_bytes(0xCD, 16) };
+        let _ = unsafe { a.alloc(old).unwrap() };
+
+        let q = unsafe { a.grow(p, old, new).unwrap() };
+        assert_ne!(addr(q), p.as_ptr().addr());
+        assert_eq!(unsafe { q.cast::<u8>().add(15).read() }, 0xCD);
+    }
+
+    #[test]
+    fn shrink_last_allocation_moves_cursor_back() {
+        let mut a = make_allocator_with_initial_chunk(mock(), 4096);
+
+        let old = Layout::from_size_align(64, 8).unwrap();
+        let new = Layout::from_size_align(16, 8).unwrap();
+        let p = unsafe { a.alloc(old).unwrap() }.cast::<u8>();
+
+        let q = unsafe { a.shrink(p, old, new).unwrap() };
+        assert_eq!(addr(q), p.as_ptr().addr());
+        assert_eq!(q.len(), 16);
+
+        let r = unsafe { a.alloc(new).unwrap() };
+        assert_eq!(addr(r), addr(q) + 16);
+    }
+
+    #[test]
+    fn rewind_restores_cursor() {
+        let mut a = make_allocator_with_initial_chunk(mock(), 4096);
+
+        let l = Layout::from_size_align(32, 8).unwrap();
+        let _ = unsafe { a.alloc(l).unwrap() };
+
+        let cp = a.checkpoint();
+        let p1 = unsafe { a.alloc(l).unwrap() };
+        let _ = unsafe { a.alloc(l).unwrap() };
+
+        unsafe { a.rewind(cp) };
+        let p2 = unsafe { a.alloc(l).unwrap() };
+        assert_eq!(addr(p1), addr(p2));
+    }
+
+    #[test]
+    fn rewind_releases_later_chunks() {
+        let mut a = make_allocator_with_initial_chunk(mock(), 128);
+
+        let cp = a.checkpoint();
+        // 1回ごとに新しいチャンクが必要になるサイズ
+        let l = Layout::from_size_align(3000, 8).unwrap();
+        let _ = unsafe { a.alloc(l).unwrap() };
+        let _ = unsafe { a.alloc(l).unwrap() };
+        assert_eq!(a.source.stats().requests, 3);
+
+        unsafe { a.rewind(cp) };
+        let st = a.source.stats();
+        assert_eq!(st.requests - st.releases, 1);
+        assert_eq!(a.checkpoint(), cp);
+    }
+
+    #[test]
+    fn rewind_to_empty_releases_everything() {
+        let mut a = BumpAllocator::new(mock());
+
+        let cp = a.checkpoint();
+        let l = Layout::from_size_align(5000, 8).unwrap();
+        let _ = unsafe { a.alloc(l).unwrap() };
+        let _ = unsafe { a.alloc(l).unwrap() };
+
+        unsafe { a.rewind(cp) };
+        let st = a.source.stats();
+        assert_eq!(st.requests, st.releases);
+    }
+
+    #[test]
+    fn reset_keeps_largest_chunk() {
+        let mut a = make_allocator_with_initial_chunk(mock(), 128);
+
+        let small = Layout::from_size_align(80, 8).unwrap();
+        let huge = Layout::from_size_align(10000, 8).unwrap();
+        let _ = unsafe { a.alloc(small).unwrap() };
+        let big = unsafe { a.alloc(huge).unwrap() };
+        let _ = unsafe { a.alloc(small).unwrap() };
+
+        unsafe { a.reset() };
+        let st = a.source.stats();
+        assert_eq!(st.requests - st.releases, 1);
+
+        // 残したチャンクの先頭から再利用される
+        let requested = a.source.stats().requests;
+        let p = unsafe { a.alloc(huge).unwrap() };
+        assert_eq!(addr(p), addr(big));
+        assert_eq!(a.source.stats().requests, requested);
+    }
+
     #[test]
     fn zst_with_large_alignment() {
-        let stats = Rc::new(RefCell::new(Stats::default()));
-        let mut a = make_allocator_with_initial_chunk(stats, 4096);
+        let mut a = make_allocator_with_initial_chunk(mock(), 4096);
 
         // サイズ0 だが アラインメント128
         let layout = Layout::from_size_align(0, 128).unwrap();
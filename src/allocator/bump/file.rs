@@ -0,0 +1,82 @@
+//! `FileMapped` の上のヒープを、プロセスをまたいで使い続ける。
+
+use core::{
+    borrow::BorrowMut,
+    mem::{self, ManuallyDrop},
+    ptr::{self, NonNull},
+};
+use std::io;
+
+use super::{BumpAllocator, ChunkNode};
+use crate::source::{
+    MemorySource,
+    file::{AllocatorKind, FileMapped},
+};
+
+impl<S: MemorySource + BorrowMut<FileMapped>> BumpAllocator<S> {
+    /// `detach` でヘッダに残した管理情報から、`source` の上のアロケータを作り直す。
+    ///
+    /// ファイルが固定のベースアドレスでなければ `InvalidInput`、
+    /// 管理情報がないか、ヘッダのチャンクの一覧と辻褄が合わなければ `InvalidData` を返す。
+    /// チャンクの中に置いたノードの大きさは確かめずに信用する。
+    pub fn reopen(mut source: S) -> io::Result<Self> {
+        let file = source.borrow_mut();
+        let [head, ptr, end, _] = file.take_allocator(AllocatorKind::Bump)?;
+        if head == 0 {
+            return Ok(Self::new(source));
+        }
+
+        let is_chunk_list = file.is_chunk_list(head, |node| {
+            let node = node.cast::<ChunkNode>().as_ptr();
+            unsafe { ((*node).ptr, (*node).next.map(NonNull::cast)) }
+        });
+        let chunk = file.live_chunk_at(head);
+        let invalid = || io::Error::from(io::ErrorKind::InvalidData);
+        let Some(chunk) = chunk.filter(|_| is_chunk_list) else {
+            return Err(invalid());
+        };
+
+        // カーソルは先頭のチャンクのデータ部を指している
+        let data = head + mem::size_of::<ChunkNode>();
+        if !(data <= ptr && ptr <= end && end == head + chunk.len()) {
+            return Err(invalid());
+        }
+
+        let base = chunk.cast::<u8>();
+        Ok(Self {
+            ptr: unsafe { base.add(ptr - head) },
+            end: unsafe { base.add(end - head) },
+            head: Some(base.cast::<ChunkNode>()),
+            source,
+        })
+    }
+
+    /// 管理情報をヘッダに残し、チャンクを返さずに `source` を返す。
+    ///
+    /// 確保した領域はファイルに残るので、開き直したファイルを `reopen` に渡せば続きから割り当てられる。
+    pub fn detach(self) -> S {
+        let mut this = ManuallyDrop::new(self);
+
+        let file = this.source.borrow();
+        let words = match this.head {
+            None => [0; 4],
+            Some(_) => [
+                file.offset_of(this.head),
+                file.offset_of(Some(this.ptr)),
+                file.offset_of(Some(this.end)),
+                0,
+            ],
+        };
+        this.source
+            .borrow_mut()
+            .save_allocator(AllocatorKind::Bump, words);
+
+        // drop は走らないので、チャンクは返されない
+        unsafe { ptr::read(&this.source) }
+    }
+
+    /// チャンクを切り出しているファイル
+    pub fn file(&self) -> &FileMapped {
+        self.source.borrow()
+    }
+}
@@ -0,0 +1,969 @@
+//! メモリの破壊を見つけるためのアロケータ。
+
+use core::{alloc::Layout, fmt, ptr::NonNull};
+
+use crate::allocator::MutAllocator;
+
+/// レッドゾーンのバイト数
+pub const ZONE_SIZE: usize = 16;
+
+/// レッドゾーンを埋めるバイト
+pub const CANARY: u8 = 0xFD;
+
+/// どちらのレッドゾーンが書き換えられていたか
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Side {
+    /// 確保した領域の前
+    Front,
+    /// 確保した領域の後ろ
+    Back,
+    /// 前と後ろの両方
+    Both,
+}
+
+/// `RedZone` が見つけた破壊
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Corruption {
+    /// 解放しようとしたポインタ
+    pub ptr: NonNull<u8>,
+    /// その確保の `Layout`
+    pub layout: Layout,
+    /// 書き換えられていたレッドゾーン
+    pub side: Side,
+}
+
+impl fmt::Display for Corruption {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let side = match self.side {
+            Side::Front => "front",
+            Side::Back => "back",
+            Side::Both => "front and back",
+        };
+        write!(
+            f,
+            "red zone overwritten ({side}) at {:p}, size {} align {}",
+            self.ptr,
+            self.layout.size(),
+            self.layout.align(),
+        )
+    }
+}
+
+/// 確保した領域の前後にレッドゾーンを置く `MutAllocator`。
+///
+/// 前後の `ZONE_SIZE` バイトを `CANARY` で埋めておき、`dealloc` のときに書き換えられていないか確かめる。
+/// 書き換えられていたら、`with_handler` で設定したハンドラに `Corruption` を渡す。
+/// ハンドラがなければ panic する。
+///
+/// 内側のアロケータには、前後のゾーンの分だけ大きな `Layout` で要求する。
+/// 前のゾーンは `layout.align()` に揃えるため、`max(ZONE_SIZE, align)` バイトになる。
+pub struct RedZone<A: MutAllocator, F = fn(Corruption)>
+where
+    F: FnMut(Corruption),
+{
+    inner: A,
+    on_corruption: Option<F>,
+}
+
+impl<A: MutAllocator> RedZone<A> {
+    pub const fn new(inner: A) -> Self {
+        Self {
+            inner,
+            on_corruption: None,
+        }
+    }
+}
+
+impl<A: MutAllocator, F: FnMut(Corruption)> RedZone<A, F> {
+    /// 破壊を見つけたときに呼ばれるハンドラを設定する。
+    pub fn with_handler<G: FnMut(Corruption)>(
+        self,
+        on_corruption: G,
+    ) -> RedZone<A, G> {
+        RedZone {
+            inner: self.inner,
+            on_corruption: Some(on_corruption),
+        }
+    }
+
+    /// 内側のアロケータ
+    pub fn inner(&self) -> &A {
+        &self.inner
+    }
+
+    /// 内側のアロケータ
+    pub fn inner_mut(&mut self) -> &mut A {
+        &mut self.inner
+    }
+
+    /// 前のゾーンの大きさと、内側のアロケータに渡す `Layout`
+    fn padded(layout: Layout) -> Option<(usize, Layout)> {
+        let front = ZONE_SIZE.max(layout.align());
+        let size = front.checked_add(layout.size())?.checked_add(ZONE_SIZE)?;
+        let padded = Layout::from_size_align(size, layout.align()).ok()?;
+        Some((front, padded))
+    }
+
+    fn report(&mut self, corruption: Corruption) {
+        match &mut self.on_corruption {
+            Some(f) => f(corruption),
+            None => panic!("{corruption}"),
+        }
+    }
+}
+
+/// `ptr` から `ZONE_SIZE` バイトが `CANARY` のままか
+///
+/// # Safety
+/// `ptr` から `ZONE_SIZE` バイトが読めること
+unsafe fn intact(ptr: *const u8) -> bool {
+    let zone = unsafe { core::slice::from_raw_parts(ptr, ZONE_SIZE) };
+    zone.iter().all(|&b| b == CANARY)
+}
+
+impl<A: MutAllocator, F: FnMut(Corruption)> MutAllocator for RedZone<A, F> {
+    unsafe fn alloc(&mut self, layout: Layout) -> Option<NonNull<[u8]>> {
+        let (front, padded) = Self::padded(layout)?;
+        let base = unsafe { self.inner.alloc(padded)? }.cast::<u8>();
+
+        unsafe {
+            let ptr = base.add(front);
+            ptr.sub(ZONE_SIZE).write_bytes(CANARY, ZONE_SIZE);
+            ptr.add(layout.size()).write_bytes(CANARY, ZONE_SIZE);
+            Some(NonNull::slice_from_raw_parts(ptr, layout.size()))
+        }
+    }
+
+    unsafe fn dealloc(&mut self, ptr: NonNull<u8>, layout: Layout) {
+        // alloc で作れた Layout なので、ここでも作れる
+        let (front, padded) = Self::padded(layout).unwrap();
+
+        let (front_ok, back_ok) = unsafe {
+            (
+                intact(ptr.sub(ZONE_SIZE).as_ptr()),
+                intact(ptr.add(layout.size()).as_ptr()),
+            )
+        };
+        let side = match (front_ok, back_ok) {
+            (true, true) => None,
+            (false, true) => Some(Side::Front),
+            (true, false) => Some(Side::Back),
+            (false, false) => Some(Side::Both),
+        };
+        if let Some(side) = side {
+            self.report(Corruption { ptr, layout, side });
+        }
+
+        unsafe { self.inner.dealloc(ptr.sub(front), padded) };
+    }
+}
+
+/// 解放した領域を埋めるバイト
+pub const POISON: u8 = 0xDD;
+
+/// `with_junk_fill` のとき、確保した領域を埋めるバイト
+pub const JUNK: u8 = 0xCD;
+
+/// `Quarantine` が見つけた、解放後の書き込み
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct UseAfterFree {
+    /// 解放された領域のポインタ
+    pub ptr: NonNull<u8>,
+    /// その確保の `Layout`
+    pub layout: Layout,
+    /// 書き換えられていた最初のバイトの、`ptr` からのオフセット
+    pub offset: usize,
+}
+
+impl fmt::Display for UseAfterFree {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "write after free at {:p}+{}, size {} align {}",
+            self.ptr,
+            self.offset,
+            self.layout.size(),
+            self.layout.align(),
+        )
+    }
+}
+
+/// 解放した領域をすぐには返さず、`N` 個までの FIFO に留めておく `MutAllocator`。
+///
+/// `dealloc` された領域は `POISON` で埋めて FIFO の末尾に入れ、あふれた先頭の領域を内側のアロケータに返す。
+/// 返す前に `POISON` のままか確かめ、書き換えられていたら `with_handler` で設定したハンドラに
+/// `UseAfterFree` を渡す。ハンドラがなければ panic する。
+///
+/// `with_junk_fill(true)` にすると、確保した領域を `JUNK` で埋めて返す。
+///
+/// 破棄するときは FIFO に残った領域を確かめずに返す。確かめたいなら先に `flush` を呼ぶ。
+pub struct Quarantine<A: MutAllocator, const N: usize, F = fn(UseAfterFree)>
+where
+    F: FnMut(UseAfterFree),
+{
+    inner: A,
+    on_use_after_free: Option<F>,
+    junk_fill: bool,
+
+    /// 解放された領域のリングバッファ。`head` から `len` 個が使われている
+    entries: [Option<(NonNull<u8>, Layout)>; N],
+    head: usize,
+    len: usize,
+}
+
+unsafe impl<A: MutAllocator + Send, const N: usize, F> Send
+    for Quarantine<A, N, F>
+where
+    F: FnMut(UseAfterFree) + Send,
+{
+}
+
+impl<A: MutAllocator, const N: usize> Quarantine<A, N> {
+    pub const fn new(inner: A) -> Self {
+        Self {
+            inner,
+            on_use_after_free: None,
+            junk_fill: false,
+            entries: [None; N],
+            head: 0,
+            len: 0,
+        }
+    }
+}
+
+impl<A: MutAllocator, const N: usize, F: FnMut(UseAfterFree)>
+    Quarantine<A, N, F>
+{
+    /// 解放後の書き込みを見つけたときに呼ばれるハンドラを設定する。
+    pub fn with_handler<G: FnMut(UseAfterFree)>(
+        self,
+        on_use_after_free: G,
+    ) -> Quarantine<A, N, G> {
+        // FIFO に領域が残っていても、そのまま引き継ぐ
+        let mut this = core::mem::ManuallyDrop::new(self);
+        unsafe {
+            core::ptr::drop_in_place(&mut this.on_use_after_free);
+            Quarantine {
+                inner: core::ptr::read(&this.inner),
+                on_use_after_free: Some(on_use_after_free),
+                junk_fill: this.junk_fill,
+                entries: this.entries,
+                head: this.head,
+                len: this.len,
+            }
+        }
+    }
+
+    /// 確保した領域を `JUNK` で埋めるかどうか
+    pub fn with_junk_fill(mut self, junk_fill: bool) -> Self {
+        self.junk_fill = junk_fill;
+        self
+    }
+
+    /// 内側のアロケータ
+    pub fn inner(&self) -> &A {
+        &self.inner
+    }
+
+    /// 内側のアロケータ
+    pub fn inner_mut(&mut self) -> &mut A {
+        &mut self.inner
+    }
+
+    /// FIFO に留めている領域の数
+    pub fn quarantined(&self) -> usize {
+        self.len
+    }
+
+    /// FIFO に留めている領域をすべて確かめて、内側のアロケータに返す。
+    pub fn flush(&mut self) {
+        while let Some((ptr, layout)) = self.pop() {
+            unsafe { self.release(ptr, layout) };
+        }
+    }
+
+    fn pop(&mut self) -> Option<(NonNull<u8>, Layout)> {
+        if self.len == 0 {
+            return None;
+        }
+
+        let entry = self.entries[self.head].take();
+        self.head = (self.head + 1) % N;
+        self.len -= 1;
+        entry
+    }
+
+    /// `ptr` が `POISON` のままか確かめて、内側のアロケータに返す。
+    ///
+    /// # Safety
+    /// `ptr` と `layout` は `dealloc` に渡されたもの
+    unsafe fn release(&mut self, ptr: NonNull<u8>, layout: Layout) {
+        let bytes =
+            unsafe { core::slice::from_raw_parts(ptr.as_ptr(), layout.size()) };
+
+        if let Some(offset) = bytes.iter().position(|&b| b != POISON) {
+            let found = UseAfterFree {
+                ptr,
+                layout,
+                offset,
+            };
+            match &mut self.on_use_after_free {
+                Some(f) => f(found),
+                None => panic!("{found}"),
+            }
+        }
+
+        unsafe { self.inner.dealloc(ptr, layout) };
+    }
+}
+
+impl<A: MutAllocator, const N: usize, F: FnMut(UseAfterFree)> MutAllocator
+    for Quarantine<A, N, F>
+{
+    unsafe fn alloc(&mut self, layout: Layout) -> Option<NonNull<[u8]>> {
+        let ptr = unsafe { self.inner.alloc(layout)? };
+        if self.junk_fill {
+            unsafe { ptr.cast::<u8>().write_bytes(JUNK, layout.size()) };
+        }
+        Some(ptr)
+    }
+
+    unsafe fn dealloc(&mut self, ptr: NonNull<u8>, layout: Layout) {
+        unsafe { ptr.write_bytes(POISON, layout.size()) };
+
+        if N == 0 {
+            unsafe { self.release(ptr, layout) };
+            return;
+        }
+
+        if self.len == N
+            && let Some((old_ptr, old_layout)) = self.pop()
+        {
+            unsafe { self.release(old_ptr, old_layout) };
+        }
+
+        self.entries[(self.head + self.len) % N] = Some((ptr, layout));
+        self.len += 1;
+    }
+}
+
+impl<A: MutAllocator, const N: usize, F: FnMut(UseAfterFree)> Drop
+    for Quarantine<A, N, F>
+{
+    fn drop(&mut self) {
+        // drop 中に panic しないよう、ここでは確かめない
+        while let Some((ptr, layout)) = self.pop() {
+            unsafe { self.inner.dealloc(ptr, layout) };
+        }
+    }
+}
+
+/// `Checked` の表の 1 行の状態
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum State {
+    Empty,
+    Live,
+    Freed,
+}
+
+/// `Checked` の表の 1 行
+#[derive(Clone, Copy)]
+struct Entry {
+    addr: usize,
+    layout: Layout,
+    state: State,
+}
+
+const EMPTY: Entry = Entry {
+    addr: 0,
+    layout: Layout::new::<()>(),
+    state: State::Empty,
+};
+
+/// 表の最小の行数
+const MIN_TABLE: usize = 16;
+
+/// 生きている確保の表を持ち、`dealloc` の契約違反で panic する `MutAllocator`。
+///
+/// 表はアドレスをキーにした開番地法のハッシュ表で、内側のアロケータから確保する。
+/// `dealloc`（と `grow` / `shrink`）では次のときに panic する。
+/// - 確保した覚えのないポインタ
+/// - 確保したときと `size` か `align` が違う `Layout`
+/// - 解放済みの領域をもう一度解放した（二重解放）
+///
+/// 解放した領域も `Freed` として表に残すので、二重解放と知らないポインタを区別できる。
+/// 表を作り直すときに解放済みの行が生きている行より多ければ捨てるので、
+/// 古い領域の二重解放は「知らないポインタ」として報告されることがある。
+///
+/// 大きさ 0 の確保はダングリングポインタなので、表には載せない。
+pub struct Checked<A: MutAllocator> {
+    inner: A,
+
+    table: NonNull<Entry>,
+    /// 表の行数（0 か 2 の冪）
+    cap: usize,
+    /// 空でない行の数
+    used: usize,
+    /// 生きている確保の数
+    live: usize,
+}
+
+unsafe impl<A: MutAllocator + Send> Send for Checked<A> {}
+
+impl<A: MutAllocator> Checked<A> {
+    pub const fn new(inner: A) -> Self {
+        Self {
+            inner,
+            table: NonNull::dangling(),
+            cap: 0,
+            used: 0,
+            live: 0,
+        }
+    }
+
+    /// 内側のアロケータ
+    pub fn inner(&self) -> &A {
+        &self.inner
+    }
+
+    /// 内側のアロケータ
+    pub fn inner_mut(&mut self) -> &mut A {
+        &mut self.inner
+    }
+
+    /// 生きている確保の数
+    pub fn live_allocations(&self) -> usize {
+        self.live
+    }
+
+    fn entries(&mut self) -> &mut [Entry] {
+        unsafe {
+            core::slice::from_raw_parts_mut(self.table.as_ptr(), self.cap)
+        }
+    }
+
+    /// `addr` の行か、`addr` を入れるべき空の行の番号。`cap > 0` のときだけ呼ぶ。
+    fn slot(&mut self, addr: usize) -> usize {
+        let mask = self.cap - 1;
+        let h = (addr ^ (addr >> 16))
+            .wrapping_mul(0x9E37_79B9_7F4A_7C15_u64 as usize);
+        let mut i = (h ^ (h >> 29)) & mask;
+
+        let entries = self.entries();
+        while entries[i].state != State::Empty && entries[i].addr != addr {
+            i = (i + 1) & mask;
+        }
+        i
+    }
+
+    /// 行を 1 つ増やせるようにする。表を作り直せなければ `false`。
+    fn reserve(&mut self) -> bool {
+        if (self.used + 1) * 4 <= self.cap * 3 {
+            return true;
+        }
+
+        let keep_freed = self.used - self.live <= self.live;
+        let kept = if keep_freed { self.used } else { self.live };
+        let cap = ((kept + 1) * 2).next_power_of_two().max(MIN_TABLE);
+
+        let Ok(layout) = Layout::array::<Entry>(cap) else {
+            return false;
+        };
+        let Some(table) = (unsafe { self.inner.alloc(layout) }) else {
+            return false;
+        };
+        let table = table.cast::<Entry>();
+        for i in 0..cap {
+            unsafe { table.add(i).write(EMPTY) };
+        }
+
+        let (old, old_cap) = (self.table, self.cap);
+        self.table = table;
+        self.cap = cap;
+        self.used = 0;
+
+        for i in 0..old_cap {
+            let entry = unsafe { old.add(i).read() };
+            if entry.state == State::Live
+                || (entry.state == State::Freed && keep_freed)
+            {
+                let j = self.slot(entry.addr);
+                self.entries()[j] = entry;
+                self.used += 1;
+            }
+        }
+
+        if old_cap > 0 {
+            unsafe {
+                self.inner.dealloc(
+                    old.cast(),
+                    Layout::array::<Entry>(old_cap).unwrap(),
+                )
+            };
+        }
+        true
+    }
+
+    /// 内側のアロケータが返した領域を表に載せる。
+    fn record(&mut self, ptr: NonNull<u8>, layout: Layout) {
+        let i = self.slot(ptr.addr().get());
+        let entry = &mut self.entries()[i];
+
+        match entry.state {
+            State::Live => panic!(
+                "allocator returned {ptr:p} while it is still live (size {} align {})",
+                entry.layout.size(),
+                entry.layout.align(),
+            ),
+            State::Empty => self.used += 1,
+            State::Freed => {}
+        }
+
+        self.entries()[i] = Entry {
+            addr: ptr.addr().get(),
+            layout,
+            state: State::Live,
+        };
+        self.live += 1;
+    }
+
+    /// `ptr` が `layout` で確保された生きている領域か確かめて、その行の番号を返す。
+    /// 表に載せない大きさ 0 の確保なら `None`。
+    fn lookup(&mut self, ptr: NonNull<u8>, layout: Layout) -> Option<usize> {
+        let found = if self.cap == 0 {
+            None
+        } else {
+            let i = self.slot(ptr.addr().get());
+            let entry = self.entries()[i];
+            (entry.state != State::Empty).then_some((i, entry))
+        };
+
+        let Some((i, entry)) = found else {
+            if layout.size() == 0 {
+                return None;
+            }
+            panic!(
+                "dealloc of unknown pointer {ptr:p} (size {} align {})",
+                layout.size(),
+                layout.align(),
+            );
+        };
+
+        if entry.state == State::Freed {
+            panic!(
+                "double free of {ptr:p} (size {} align {})",
+                entry.layout.size(),
+                entry.layout.align(),
+            );
+        }
+        if entry.layout != layout {
+            panic!(
+                "layout mismatch at {ptr:p}: allocated with size {} align {}, freed with size {} align {}",
+                entry.layout.size(),
+                entry.layout.align(),
+                layout.size(),
+                layout.align(),
+            );
+        }
+        Some(i)
+    }
+
+    fn forget(&mut self, slot: Option<usize>) {
+        if let Some(i) = slot {
+            self.entries()[i].state = State::Freed;
+            self.live -= 1;
+        }
+    }
+
+    /// `grow` / `shrink` の共通部分
+    fn realloc(
+        &mut self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+        f: impl FnOnce(&mut A) -> Option<NonNull<[u8]>>,
+    ) -> Option<NonNull<[u8]>> {
+        if !self.reserve() {
+            return None;
+        }
+        let slot = self.lookup(ptr, old_layout);
+
+        let new_ptr = f(&mut self.inner)?;
+        self.forget(slot);
+        if new_layout.size() > 0 {
+            self.record(new_ptr.cast(), new_layout);
+        }
+        Some(new_ptr)
+    }
+}
+
+impl<A: MutAllocator> MutAllocator for Checked<A> {
+    unsafe fn alloc(&mut self, layout: Layout) -> Option<NonNull<[u8]>> {
+        if layout.size() == 0 {
+            return unsafe { self.inner.alloc(layout) };
+        }
+        if !self.reserve() {
+            return None;
+        }
+
+        let ptr = unsafe { self.inner.alloc(layout)? };
+        self.record(ptr.cast(), layout);
+        Some(ptr)
+    }
+
+    unsafe fn dealloc(&mut self, ptr: NonNull<u8>, layout: Layout) {
+        let slot = self.lookup(ptr, layout);
+        self.forget(slot);
+        unsafe { self.inner.dealloc(ptr, layout) };
+    }
+
+    unsafe fn grow(
+        &mut self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Option<NonNull<[u8]>> {
+        self.realloc(ptr, old_layout, new_layout, |inner| unsafe {
+            inner.grow(ptr, old_layout, new_layout)
+        })
+    }
+
+    unsafe fn grow_zeroed(
+        &mut self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Option<NonNull<[u8]>> {
+        self.realloc(ptr, old_layout, new_layout, |inner| unsafe {
+            inner.grow_zeroed(ptr, old_layout, new_layout)
+        })
+    }
+
+    unsafe fn shrink(
+        &mut self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Option<NonNull<[u8]>> {
+        self.realloc(ptr, old_layout, new_layout, |inner| unsafe {
+            inner.shrink(ptr, old_layout, new_layout)
+        })
+    }
+}
+
+impl<A: MutAllocator> Drop for Checked<A> {
+    fn drop(&mut self) {
+        if self.cap > 0 {
+            unsafe {
+                self.inner.dealloc(
+                    self.table.cast(),
+                    Layout::array::<Entry>(self.cap).unwrap(),
+                )
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        allocator::{bump::BumpAllocator, free_list::FreeList},
+        mutex::Locked,
+        source::os_heap::OsHeap,
+    };
+    use std::vec::Vec;
+
+    #[test]
+    fn untouched_zones_pass() {
+        let mut a = RedZone::new(FreeList::new(OsHeap));
+
+        for align in [1, 8, 64, 4096] {
+            let l = Layout::from_size_align(100, align).unwrap();
+            let p = unsafe { a.alloc(l).unwrap() };
+            assert_eq!(p.len(), 100);
+            assert!(p.cast::<u8>().addr().get().is_multiple_of(align));
+
+            unsafe {
+                p.cast::<u8>().write_bytes(0xAA, 100);
+                a.dealloc(p.cast(), l);
+            }
+        }
+    }
+
+    #[test]
+    fn reports_the_overwritten_side() {
+        let mut hits = Vec::new();
+        let mut a = RedZone::new(BumpAllocator::new(OsHeap))
+            .with_handler(|c: Corruption| hits.push(c));
+        let l = Layout::from_size_align(24, 8).unwrap();
+
+        let p = unsafe { a.alloc(l).unwrap() }.cast::<u8>();
+        let q = unsafe { a.alloc(l).unwrap() }.cast::<u8>();
+        let r = unsafe { a.alloc(l).unwrap() }.cast::<u8>();
+        unsafe {
+            // 1 バイトだけはみ出す
+            p.add(24).write(0);
+            q.sub(1).write(0);
+            r.sub(1).write(0);
+            r.add(24).write(0);
+
+            a.dealloc(p, l);
+            a.dealloc(q, l);
+            a.dealloc(r, l);
+        }
+
+        assert_eq!(
+            hits,
+            [
+                Corruption {
+                    ptr: p,
+                    layout: l,
+                    side: Side::Back
+                },
+                Corruption {
+                    ptr: q,
+                    layout: l,
+                    side: Side::Front
+                },
+                Corruption {
+                    ptr: r,
+                    layout: l,
+                    side: Side::Both
+                },
+            ]
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "red zone overwritten (back)")]
+    fn red_zone_panics_without_a_handler() {
+        let mut a = RedZone::new(BumpAllocator::new(OsHeap));
+        let l = Layout::from_size_align(10, 1).unwrap();
+
+        unsafe {
+            let p = a.alloc(l).unwrap().cast::<u8>();
+            p.write_bytes(0, 11);
+            a.dealloc(p, l);
+        }
+    }
+
+    #[test]
+    fn grow_keeps_the_zones() {
+        let a = Locked::new(RedZone::new(FreeList::new(OsHeap)));
+
+        let mut v: Vec<u64, _> = Vec::new_in(&a);
+        for i in 0..1000 {
+            v.push(i);
+        }
+        v.shrink_to_fit();
+        assert!(v.iter().copied().eq(0..1000));
+    }
+
+    #[test]
+    fn freed_memory_is_poisoned_and_held() {
+        let mut a = Quarantine::<_, 4>::new(FreeList::new(OsHeap));
+        let l = Layout::from_size_align(32, 8).unwrap();
+
+        let p = unsafe { a.alloc(l).unwrap() }.cast::<u8>();
+        unsafe { a.dealloc(p, l) };
+        assert_eq!(a.quarantined(), 1);
+
+        // FIFO にいる間は、同じ領域が返ってこない
+        let q = unsafe { a.alloc(l).unwrap() }.cast::<u8>();
+        assert_ne!(p, q);
+
+        let freed = unsafe { core::slice::from_raw_parts(p.as_ptr(), 32) };
+        assert!(freed.iter().all(|&b| b == POISON));
+
+        unsafe { a.dealloc(q, l) };
+        a.flush();
+        assert_eq!(a.quarantined(), 0);
+    }
+
+    #[test]
+    fn reports_writes_after_free_in_fifo_order() {
+        let mut hits = Vec::new();
+        let mut a = Quarantine::<_, 2>::new(FreeList::new(OsHeap))
+            .with_handler(|u: UseAfterFree| hits.push(u));
+        let l = Layout::from_size_align(16, 8).unwrap();
+
+        let ptrs: Vec<_> = (0..4)
+            .map(|_| unsafe { a.alloc(l).unwrap() }.cast::<u8>())
+            .collect();
+        unsafe {
+            a.dealloc(ptrs[0], l);
+            a.dealloc(ptrs[1], l);
+            ptrs[0].add(5).write(1);
+            ptrs[1].add(9).write(1);
+
+            // あふれた ptrs[0] から順に確かめられる
+            a.dealloc(ptrs[2], l);
+            a.dealloc(ptrs[3], l);
+        }
+        a.flush();
+        drop(a);
+
+        assert_eq!(
+            hits,
+            [
+                UseAfterFree {
+                    ptr: ptrs[0],
+                    layout: l,
+                    offset: 5
+                },
+                UseAfterFree {
+                    ptr: ptrs[1],
+                    layout: l,
+                    offset: 9
+                },
+            ]
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "write after free at")]
+    fn quarantine_panics_without_a_handler() {
+        let mut a = Quarantine::<_, 1>::new(BumpAllocator::new(OsHeap));
+        let l = Layout::from_size_align(8, 8).unwrap();
+
+        unsafe {
+            let p = a.alloc(l).unwrap().cast::<u8>();
+            a.dealloc(p, l);
+            p.write(0);
+        }
+        a.flush();
+    }
+
+    #[test]
+    fn junk_fill_on_alloc() {
+        let mut a =
+            Quarantine::<_, 8>::new(FreeList::new(OsHeap)).with_junk_fill(true);
+        let l = Layout::from_size_align(64, 8).unwrap();
+
+        let p = unsafe { a.alloc(l).unwrap() };
+        let bytes = unsafe { p.as_ref() };
+        assert!(bytes.iter().all(|&b| b == JUNK));
+        unsafe { a.dealloc(p.cast(), l) };
+    }
+
+    #[test]
+    fn works_behind_locked() {
+        let a = Locked::new(Quarantine::<_, 16>::new(FreeList::new(OsHeap)));
+
+        let mut v: Vec<u64, _> = Vec::new_in(&a);
+        v.extend(0..1000);
+        v.shrink_to(10);
+        drop(v);
+
+        a.with_lock(|q| {
+            assert!(q.quarantined() > 0);
+            q.flush();
+        });
+    }
+
+    #[test]
+    fn quarantine_can_be_a_static() {
+        static QUARANTINE: Locked<Quarantine<FreeList<OsHeap>, 8>> =
+            Locked::new(Quarantine::new(FreeList::new(OsHeap)));
+
+        let mut v: Vec<u64, _> = Vec::new_in(&QUARANTINE);
+        v.extend(0..100);
+        drop(v);
+        QUARANTINE.with_lock(|q| {
+            assert_eq!(q.quarantined(), 1);
+            q.flush();
+        });
+    }
+
+    #[test]
+    fn checked_passes_correct_use() {
+        let mut a = Checked::new(FreeList::new(OsHeap));
+
+        let ptrs: Vec<_> = (1..=1000)
+            .map(|i| {
+                let l = Layout::from_size_align(i % 200 + 1, 8).unwrap();
+                (unsafe { a.alloc(l).unwrap() }.cast::<u8>(), l)
+            })
+            .collect();
+        assert_eq!(a.live_allocations(), 1000);
+
+        for &(p, l) in ptrs.iter().rev() {
+            unsafe { a.dealloc(p, l) };
+        }
+        assert_eq!(a.live_allocations(), 0);
+
+        // 解放した番地がまた返ってきても問題ない
+        let l = Layout::from_size_align(16, 8).unwrap();
+        for _ in 0..100 {
+            let p = unsafe { a.alloc(l).unwrap() };
+            unsafe { a.dealloc(p.cast(), l) };
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "dealloc of unknown pointer")]
+    fn checked_panics_on_unknown_pointer() {
+        let mut a = Checked::new(FreeList::new(OsHeap));
+        let l = Layout::from_size_align(32, 8).unwrap();
+
+        unsafe {
+            let p = a.alloc(l).unwrap().cast::<u8>();
+            a.dealloc(p.add(8), l);
+        }
+    }
+
+    #[test]
+    #[should_panic(
+        expected = "allocated with size 32 align 8, freed with size 64 align 8"
+    )]
+    fn checked_panics_on_layout_mismatch() {
+        let mut a = Checked::new(FreeList::new(OsHeap));
+        let l = Layout::from_size_align(32, 8).unwrap();
+
+        unsafe {
+            let p = a.alloc(l).unwrap().cast::<u8>();
+            a.dealloc(p, Layout::from_size_align(64, 8).unwrap());
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "double free")]
+    fn checked_panics_on_double_free() {
+        let mut a = Checked::new(FreeList::new(OsHeap));
+        let l = Layout::from_size_align(32, 8).unwrap();
+
+        unsafe {
+            let p = a.alloc(l).unwrap().cast::<u8>();
+            let _q = a.alloc(l).unwrap();
+            a.dealloc(p, l);
+            a.dealloc(p, l);
+        }
+    }
+
+    #[test]
+    fn checked_follows_grow_and_shrink() {
+        let a = Locked::new(Checked::new(FreeList::new(OsHeap)));
+
+        let mut v: Vec<u64, _> = Vec::new_in(&a);
+        v.extend(0..1000);
+        assert_eq!(a.with_lock(|c| c.live_allocations()), 1);
+        v.shrink_to(10);
+        v.truncate(5);
+        v.shrink_to_fit();
+        assert!(v.iter().copied().eq(0..5));
+        drop(v);
+
+        assert_eq!(a.with_lock(|c| c.live_allocations()), 0);
+    }
+
+    #[test]
+    fn checked_can_be_a_static() {
+        static CHECKED: Locked<Checked<FreeList<OsHeap>>> =
+            Locked::new(Checked::new(FreeList::new(OsHeap)));
+
+        let mut v: Vec<u64, _> = Vec::new_in(&CHECKED);
+        v.extend(0..100);
+        assert_eq!(CHECKED.with_lock(|c| c.live_allocations()), 1);
+    }
+}
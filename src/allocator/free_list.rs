@@ -1,15 +1,37 @@
 use core::{
     alloc::Layout,
-    mem,
+    fmt, mem,
     ptr::{self, NonNull},
 };
 
-use crate::{allocator::MutAllocator, source::MemorySource};
+use crate::{
+    allocator::{MutAllocator, stats::SourceUsage},
+    source::MemorySource,
+};
+
+pub mod fit;
+
+#[cfg(all(feature = "std", target_os = "linux"))]
+mod file;
+
+use fit::{FirstFit, FitPolicy};
 
 /// 雑に作った free list
-pub struct FreeList<S: MemorySource> {
+///
+/// 空き領域はアドレス順に並べ、解放時に隣接する空き領域と結合する。
+/// 丸ごと空いたチャンクは、保持しているチャンクの合計が `retain` バイトを超えていれば source に返す。
+/// どの空き領域から割り当てるかは `P`（[`fit`] を参照）で決まる。
+pub struct FreeList<S: MemorySource, P: FitPolicy = FirstFit> {
     source: S,
     head: Option<NonNull<ListNode>>,
+    policy: P,
+
+    /// source から取得したチャンクのリスト
+    chunks: Option<NonNull<ChunkNode>>,
+    /// 保持しているチャンクの合計バイト数
+    chunk_bytes: usize,
+    /// 空いたチャンクを返さずに保持しておくバイト数
+    retain: usize,
 }
 
 #[repr(C)]
@@ -18,12 +40,103 @@ pub struct ListNode {
     next: Option<NonNull<ListNode>>,
 }
 
+/// チャンクの先頭に置くヘッダ。ヘッダは空き領域にならないので、
+/// 隣り合ったチャンク同士の空きが結合されることはない。
+struct ChunkNode {
+    next: Option<NonNull<ChunkNode>>,
+    ptr: NonNull<u8>,
+    layout: Layout,
+}
+
 // Send は source が Send のときだけに絞るのが筋
-unsafe impl<S: MemorySource + Send> Send for FreeList<S> {}
+unsafe impl<S: MemorySource + Send, P: FitPolicy + Send> Send
+    for FreeList<S, P>
+{
+}
+
+/// `FreeList::verify` が数えた free list の状態
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HeapReport {
+    /// 空き領域の合計バイト数
+    pub free_bytes: usize,
+    /// 空き領域の数
+    pub holes: usize,
+    /// 一番大きな空き領域のバイト数
+    pub largest_hole: usize,
+}
+
+/// `FreeList::verify` が見つけた free list の壊れ方。`addr` は問題のノードのアドレス。
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HeapError {
+    /// ノードが `ListNode` の align に揃っていない
+    Misaligned { addr: usize },
+    /// 空き領域がどのチャンクのデータ部にも収まっていない
+    OutsideChunk { addr: usize },
+    /// 空き領域が `ListNode` より小さい
+    TooSmall { addr: usize, size: usize },
+    /// 空き領域が前の空き領域の終わり `prev_end` より前から始まっている
+    Overlap { addr: usize, prev_end: usize },
+    /// 前のノード `prev` よりアドレスが小さい（アドレス順に並んでいない）
+    Unordered { addr: usize, prev: usize },
+    /// 一度辿ったノードにまた来た
+    Cycle { addr: usize },
+}
+
+impl fmt::Display for HeapError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            HeapError::Misaligned { addr } => {
+                write!(f, "free list node {addr:#x} is misaligned")
+            }
+            HeapError::OutsideChunk { addr } => {
+                write!(f, "hole {addr:#x} lies outside every chunk")
+            }
+            HeapError::TooSmall { addr, size } => {
+                write!(f, "hole {addr:#x} is too small ({size} bytes)")
+            }
+            HeapError::Overlap { addr, prev_end } => write!(
+                f,
+                "hole {addr:#x} overlaps the previous hole ending at {prev_end:#x}"
+            ),
+            HeapError::Unordered { addr, prev } => write!(
+                f,
+                "hole {addr:#x} comes after {prev:#x} in the free list"
+            ),
+            HeapError::Cycle { addr } => {
+                write!(f, "free list has a cycle at {addr:#x}")
+            }
+        }
+    }
+}
 
 impl<S: MemorySource> FreeList<S> {
+    /// first-fit で割り当てる `FreeList` を作る。
     pub const fn new(source: S) -> Self {
-        Self { source, head: None }
+        Self::with_policy(source, FirstFit)
+    }
+}
+
+impl<S: MemorySource, P: FitPolicy> FreeList<S, P> {
+    /// 空いたチャンクを保持しておくバイト数の既定値
+    pub const DEFAULT_RETAIN: usize = 64 * 1024;
+
+    /// 探索戦略 `policy` で割り当てる `FreeList` を作る。
+    pub const fn with_policy(source: S, policy: P) -> Self {
+        Self {
+            source,
+            head: None,
+            policy,
+            chunks: None,
+            chunk_bytes: 0,
+            retain: Self::DEFAULT_RETAIN,
+        }
+    }
+
+    /// 丸ごと空いたチャンクを、保持しているチャンクの合計が `retain` バイト以下になるまで
+    /// source に返すようにする。
+    pub const fn with_retain(mut self, retain: usize) -> Self {
+        self.retain = retain;
+        self
     }
 
     #[inline]
@@ -45,20 +158,69 @@ impl<S: MemorySource> FreeList<S> {
         Some(l.pad_to_align())
     }
 
-    unsafe fn push_free(&mut self, start: NonNull<u8>, size: usize) {
+    /// 空き領域 `[start, start + size)` をアドレス順に free list へ挿入する。
+    /// 前後の空き領域と隣接していれば結合する。
+    /// `start` を含むことになった空き領域のノードを返す。
+    unsafe fn insert_free(
+        &mut self,
+        start: NonNull<u8>,
+        size: usize,
+    ) -> NonNull<ListNode> {
         debug_assert!(size >= Self::node_layout().size());
         debug_assert!(
             start.as_ptr().align_offset(Self::node_layout().align()) == 0
         );
 
-        let node_ptr = start.as_ptr() as *mut ListNode;
-        unsafe {
-            node_ptr.write(ListNode {
-                size,
-                next: self.head,
-            })
-        };
-        self.head = Some(unsafe { NonNull::new_unchecked(node_ptr) });
+        let start_addr = start.as_ptr().addr();
+
+        // start より後ろにある最初のノードを探す
+        let mut prev: Option<NonNull<ListNode>> = None;
+        let mut cur = self.head;
+        while let Some(node) = cur {
+            if node.as_ptr().addr() > start_addr {
+                break;
+            }
+            prev = cur;
+            cur = unsafe { node.as_ref().next };
+        }
+
+        let mut node = start.cast::<ListNode>();
+        unsafe { node.write(ListNode { size, next: cur }) };
+
+        // 後ろと結合
+        if let Some(next) = cur {
+            debug_assert!(start_addr + size <= next.as_ptr().addr());
+
+            if start_addr + size == next.as_ptr().addr() {
+                unsafe {
+                    let next = next.read();
+                    node.as_mut().size += next.size;
+                    node.as_mut().next = next.next;
+                }
+            }
+        }
+
+        // 前と結合
+        match prev {
+            None => {
+                self.head = Some(node);
+                node
+            }
+            Some(mut p) => unsafe {
+                let p_ref = p.as_mut();
+                let prev_end = p.as_ptr().addr() + p_ref.size;
+                debug_assert!(prev_end <= start_addr);
+
+                if prev_end == start_addr {
+                    p_ref.size += node.as_ref().size;
+                    p_ref.next = node.as_ref().next;
+                    p
+                } else {
+                    p_ref.next = Some(node);
+                    node
+                }
+            },
+        }
     }
 
     /// prevを使って、nodeをfree listから外す
@@ -75,7 +237,7 @@ impl<S: MemorySource> FreeList<S> {
         v
     }
 
-    /// 空き領域 node から first-fit で割当を試す。
+    /// 空き領域 node から割当を試す。
     /// 成功なら (alloc_ptr, alloc_size, prefix_size, suffix_size, next) を返す。
     fn try_take_from(node: NonNull<ListNode>, need: Layout) -> Option<Fit> {
         let node_ref = unsafe { node.as_ref() };
@@ -102,7 +264,7 @@ impl<S: MemorySource> FreeList<S> {
         let prefix = alloc_start_addr - hole_start_addr;
         let suffix = hole_end_addr - alloc_end_addr;
 
-        // 「結合しない」ので、残りを free list に戻すには ListNode を置ける必要がある
+        // 残りを free list に戻すには ListNode を置ける必要がある
         let min_free = mem::size_of::<ListNode>();
         let prefix_ok = prefix == 0 || prefix >= min_free;
         let suffix_ok = suffix == 0 || suffix >= min_free;
@@ -120,29 +282,329 @@ impl<S: MemorySource> FreeList<S> {
         })
     }
 
+    /// `[ptr, ptr + old_size)` の直後に接する空き領域を吸収して、
+    /// `new_size` バイトまでその場で拡張する。成功すれば `true` を返す。
+    unsafe fn try_grow_in_place(
+        &mut self,
+        ptr: NonNull<u8>,
+        old_size: usize,
+        new_size: usize,
+    ) -> bool {
+        let extra = new_size - old_size;
+        if extra == 0 {
+            return true;
+        }
+
+        let block_end = ptr.as_ptr().addr() + old_size;
+
+        let mut prev: Option<NonNull<ListNode>> = None;
+        let mut cur = self.head;
+
+        while let Some(node) = cur {
+            // アドレス順なので、block_end を超えたら隣接する空きはない
+            if node.as_ptr().addr() > block_end {
+                return false;
+            }
+
+            if node.as_ptr().addr() == block_end {
+                let hole_size = unsafe { node.as_ref().size };
+                if hole_size < extra {
+                    return false;
+                }
+
+                let rest = hole_size - extra;
+                if rest != 0 && rest < mem::size_of::<ListNode>() {
+                    return false;
+                }
+
+                unsafe { self.unlink(prev, node) };
+                if rest != 0 {
+                    unsafe { self.insert_free(ptr.add(new_size), rest) };
+                }
+                return true;
+            }
+
+            prev = cur;
+            cur = unsafe { node.as_ref().next };
+        }
+
+        false
+    }
+
+    /// `self.policy` に従って、`need` を割り当てる空き領域を選ぶ。
+    /// 成功なら (直前のノード, 選んだノード, Fit) を返す。
+    fn find_fit(
+        &self,
+        need: Layout,
+    ) -> Option<(Option<NonNull<ListNode>>, NonNull<ListNode>, Fit)> {
+        let start = self.policy.start();
+
+        let mut best: Option<(
+            Option<NonNull<ListNode>>,
+            NonNull<ListNode>,
+            Fit,
+        )> = None;
+        // STOP_AT_FIRST のとき、start より前で最初に見つかった候補（折り返し用）
+        let mut wrapped = None;
+
+        let mut prev: Option<NonNull<ListNode>> = None;
+        let mut cur = self.head;
+
+        while let Some(node) = cur {
+            if let Some(fit) = Self::try_take_from(node, need) {
+                if P::STOP_AT_FIRST {
+                    if node.as_ptr().addr() >= start {
+                        best = Some((prev, node, fit));
+                        break;
+                    }
+                    if wrapped.is_none() {
+                        wrapped = Some((prev, node, fit));
+                    }
+                } else {
+                    let size = unsafe { node.as_ref().size };
+                    let take = match best {
+                        None => true,
+                        Some((_, b, _)) => self
+                            .policy
+                            .is_better(size, unsafe { b.as_ref().size }),
+                    };
+                    if take {
+                        best = Some((prev, node, fit));
+                    }
+                }
+            }
+
+            prev = cur;
+            cur = unsafe { node.as_ref().next };
+        }
+
+        best.or(wrapped)
+    }
+
     /// source から新チャンクを取って free list に追加
-    unsafe fn grow(&mut self, need: Layout) -> Option<()> {
+    unsafe fn add_chunk(&mut self, need: Layout) -> Option<()> {
+        let (with_header, _) = Layout::new::<ChunkNode>().extend(need).ok()?;
+
         // source に対して最低限の要求（大きめに取る）
-        let request =
-            Layout::from_size_align(need.size().max(4096), need.align())
-                .ok()?;
+        // 後ろに ListNode 1つ分の余裕を持たせ、割当後の残りが必ず free list に戻せるようにする
+        let request = Layout::from_size_align(
+            with_header
+                .size()
+                .checked_add(Self::node_layout().size())?
+                .max(4096),
+            with_header.align(),
+        )
+        .ok()?;
         let chunk = unsafe { self.source.request_chunk(request) }?;
 
+        let actual_layout =
+            Layout::from_size_align(chunk.len(), request.align()).ok()?;
+
         // 返ってきた len を node_align で切り下げ（ノードを書けるように）
         let node_align = Self::node_layout().align();
         let usable = chunk.len() & !(node_align - 1);
 
-        if usable < Self::node_layout().size() {
+        if usable < with_header.size() {
+            // 使えないチャンクは返しておく
+            unsafe {
+                self.source.release_chunk(chunk.cast::<u8>(), actual_layout)
+            };
             return None;
         }
 
-        let start = chunk.cast::<u8>();
-        unsafe { self.push_free(start, usable) };
+        let chunk_ptr = chunk.cast::<u8>();
+        let node_ptr = chunk_ptr.cast::<ChunkNode>();
+        unsafe {
+            node_ptr.write(ChunkNode {
+                next: self.chunks,
+                ptr: chunk_ptr,
+                layout: actual_layout,
+            })
+        };
+        self.chunks = Some(node_ptr);
+        self.chunk_bytes += actual_layout.size();
+
+        let start = unsafe { chunk_ptr.add(CHUNK_HEADER_SIZE) };
+        unsafe { self.insert_free(start, usable - CHUNK_HEADER_SIZE) };
         Some(())
     }
+
+    /// 空き領域 `hole` がチャンクのデータ部全体を覆っていれば、
+    /// そのチャンクを free list とチャンクリストから外して source に返す。
+    unsafe fn release_if_whole(&mut self, hole: NonNull<ListNode>) {
+        if self.chunk_bytes <= self.retain {
+            return;
+        }
+
+        let hole_addr = hole.as_ptr().addr();
+        let hole_size = unsafe { hole.as_ref().size };
+        let node_align = Self::node_layout().align();
+
+        let mut prev_chunk: Option<NonNull<ChunkNode>> = None;
+        let mut cur_chunk = self.chunks;
+
+        while let Some(chunk) = cur_chunk {
+            let chunk_ref = unsafe { chunk.as_ref() };
+            let data_start = chunk.as_ptr().addr() + CHUNK_HEADER_SIZE;
+
+            if data_start == hole_addr {
+                let usable = chunk_ref.layout.size() & !(node_align - 1);
+                if hole_size != usable - CHUNK_HEADER_SIZE {
+                    return;
+                }
+
+                // free list から外す
+                let mut prev: Option<NonNull<ListNode>> = None;
+                let mut cur = self.head;
+                while let Some(node) = cur {
+                    if node == hole {
+                        break;
+                    }
+                    prev = cur;
+                    cur = unsafe { node.as_ref().next };
+                }
+                unsafe { self.unlink(prev, hole) };
+
+                // チャンクリストから外す
+                let chunk_node = unsafe { chunk.read() };
+                match prev_chunk {
+                    None => self.chunks = chunk_node.next,
+                    Some(mut p) => unsafe { p.as_mut().next = chunk_node.next },
+                }
+                self.chunk_bytes -= chunk_node.layout.size();
+
+                unsafe {
+                    self.source.release_chunk(chunk_node.ptr, chunk_node.layout)
+                };
+                return;
+            }
+
+            prev_chunk = cur_chunk;
+            cur_chunk = chunk_ref.next;
+        }
+    }
+
+    /// free list を先頭から辿って、壊れていないか確かめる。
+    ///
+    /// ノードが揃っていること、空き領域が `ListNode` 以上の大きさでチャンクのデータ部に収まっていること、
+    /// アドレス順に重ならず並んでいること、循環していないことを確かめる。
+    /// ノードを読む前にその場所がチャンクの中か確かめるので、壊れたリストでも読み外すことはない。
+    /// 空き領域の数とチャンクの数の積に比例する時間がかかる。
+    pub fn verify(&self) -> Result<HeapReport, HeapError> {
+        let node = Self::node_layout();
+        let mut report = HeapReport {
+            free_bytes: 0,
+            holes: 0,
+            largest_hole: 0,
+        };
+
+        // (前のノードのアドレス, その終わり)
+        let mut prev: Option<(usize, usize)> = None;
+        let mut cur = self.head;
+
+        while let Some(hole) = cur {
+            let addr = hole.as_ptr().addr();
+
+            if !addr.is_multiple_of(node.align()) {
+                return Err(HeapError::Misaligned { addr });
+            }
+            let Some(data_end) = self.chunk_data_end(addr, node.size()) else {
+                return Err(HeapError::OutsideChunk { addr });
+            };
+
+            if let Some((prev_addr, prev_end)) = prev {
+                if addr <= prev_addr {
+                    return Err(if self.visited_before(hole, prev_addr) {
+                        HeapError::Cycle { addr }
+                    } else {
+                        HeapError::Unordered {
+                            addr,
+                            prev: prev_addr,
+                        }
+                    });
+                }
+                if addr < prev_end {
+                    return Err(HeapError::Overlap { addr, prev_end });
+                }
+            }
+
+            let hole_ref = unsafe { hole.as_ref() };
+            let size = hole_ref.size;
+            if size < node.size() {
+                return Err(HeapError::TooSmall { addr, size });
+            }
+            if addr.checked_add(size).is_none_or(|end| end > data_end) {
+                return Err(HeapError::OutsideChunk { addr });
+            }
+
+            report.free_bytes += size;
+            report.holes += 1;
+            report.largest_hole = report.largest_hole.max(size);
+
+            prev = Some((addr, addr + size));
+            cur = hole_ref.next;
+        }
+
+        Ok(report)
+    }
+
+    /// `[addr, addr + size)` がデータ部に収まるチャンクがあれば、そのデータ部の終わりを返す。
+    fn chunk_data_end(&self, addr: usize, size: usize) -> Option<usize> {
+        let node_align = Self::node_layout().align();
+
+        let mut cur = self.chunks;
+        while let Some(chunk) = cur {
+            let chunk_ref = unsafe { chunk.as_ref() };
+            let data_start = chunk.as_ptr().addr() + CHUNK_HEADER_SIZE;
+            let data_end = chunk.as_ptr().addr()
+                + (chunk_ref.layout.size() & !(node_align - 1));
+
+            if data_start <= addr && addr.checked_add(size)? <= data_end {
+                return Some(data_end);
+            }
+            cur = chunk_ref.next;
+        }
+        None
+    }
+
+    /// 先頭から `last`（ここまでは確かめ済み）までの間に `node` があるか
+    fn visited_before(&self, node: NonNull<ListNode>, last: usize) -> bool {
+        let mut cur = self.head;
+        while let Some(n) = cur {
+            if n == node {
+                return true;
+            }
+            if n.as_ptr().addr() == last {
+                return false;
+            }
+            cur = unsafe { n.as_ref().next };
+        }
+        false
+    }
+}
+
+impl<S: MemorySource, P: FitPolicy> SourceUsage for FreeList<S, P> {
+    fn source_bytes(&self) -> usize {
+        self.chunk_bytes
+    }
+}
+
+impl<S: MemorySource, P: FitPolicy> Drop for FreeList<S, P> {
+    fn drop(&mut self) {
+        let mut current = self.chunks;
+
+        while let Some(node_ptr) = current {
+            unsafe {
+                let node = node_ptr.read();
+                current = node.next;
+
+                self.source.release_chunk(node.ptr, node.layout);
+            }
+        }
+    }
 }
 
-impl<S: MemorySource> MutAllocator for FreeList<S> {
+impl<S: MemorySource, P: FitPolicy> MutAllocator for FreeList<S, P> {
     unsafe fn alloc(&mut self, layout: Layout) -> Option<NonNull<[u8]>> {
         // ZST は適当な non-null を返す
         if layout.size() == 0 {
@@ -153,59 +615,46 @@ impl<S: MemorySource> MutAllocator for FreeList<S> {
 
         let need = Self::normalized(layout)?;
 
-        loop {
-            // first-fit
-            let mut prev: Option<NonNull<ListNode>> = None;
-            let mut cur = self.head;
-
-            while let Some(node) = cur {
-                // if let Some((alloc_ptr, alloc_size, prefix, suffix, _next)) =
-                if let Some(Fit {
-                    alloc_ptr,
-                    alloc_size,
-                    prefix_size: prefix,
-                    suffix_size: suffix,
-                }) = Self::try_take_from(node, need)
-                {
-                    // node を list から外す
-                    let _old = unsafe { self.unlink(prev, node) };
-
-                    let hole_start = node.as_ptr().cast::<u8>() as usize;
-                    let alloc_start = alloc_ptr.as_ptr() as usize;
-                    let alloc_end = alloc_start + alloc_size;
-
-                    // suffix を head に戻す
-                    if suffix != 0 {
-                        let suffix_ptr = unsafe {
-                            NonNull::new_unchecked(alloc_end as *mut u8)
-                        };
-                        unsafe { self.push_free(suffix_ptr, suffix) };
-                    }
+        let (prev, node, fit) = match self.find_fit(need) {
+            Some(found) => found,
+            None => {
+                // 見つからない → チャンクを追加して再試行
+                unsafe { self.add_chunk(need)? };
+                self.find_fit(need)?
+            }
+        };
+        let Fit {
+            alloc_ptr,
+            alloc_size,
+            prefix_size: prefix,
+            suffix_size: suffix,
+        } = fit;
 
-                    // prefix を head に戻す
-                    if prefix != 0 {
-                        let prefix_ptr = unsafe {
-                            NonNull::new_unchecked(hole_start as *mut u8)
-                        };
-                        unsafe { self.push_free(prefix_ptr, prefix) };
-                    }
+        // node を list から外す
+        let _old = unsafe { self.unlink(prev, node) };
 
-                    // alloc で返す長さは「要求サイズ」
-                    return Some(NonNull::slice_from_raw_parts(
-                        alloc_ptr,
-                        layout.size(),
-                    ));
-                }
+        let hole_start = node.as_ptr().cast::<u8>() as usize;
+        let alloc_start = alloc_ptr.as_ptr() as usize;
+        let alloc_end = alloc_start + alloc_size;
 
-                prev = cur;
-                cur = unsafe { node.as_ref().next };
-            }
+        // suffix を free list に戻す
+        if suffix != 0 {
+            let suffix_ptr =
+                unsafe { NonNull::new_unchecked(alloc_end as *mut u8) };
+            unsafe { self.insert_free(suffix_ptr, suffix) };
+        }
 
-            // 見つからない → grow して再試行
-            unsafe {
-                self.grow(need)?;
-            }
+        // prefix を free list に戻す
+        if prefix != 0 {
+            let prefix_ptr =
+                unsafe { NonNull::new_unchecked(hole_start as *mut u8) };
+            unsafe { self.insert_free(prefix_ptr, prefix) };
         }
+
+        self.policy.on_alloc(alloc_end);
+
+        // alloc で返す長さは「要求サイズ」
+        Some(NonNull::slice_from_raw_parts(alloc_ptr, layout.size()))
     }
 
     unsafe fn dealloc(&mut self, ptr: NonNull<u8>, layout: Layout) {
@@ -213,7 +662,7 @@ impl<S: MemorySource> MutAllocator for FreeList<S> {
             return;
         }
 
-        // alloc と同じ正規化サイズで free に戻す（結合なし、push するだけ）
+        // alloc と同じ正規化サイズで free に戻す（隣接する空きとは結合される）
         let need = match Self::normalized(layout) {
             Some(x) => x,
             None => return,
@@ -225,11 +674,88 @@ impl<S: MemorySource> MutAllocator for FreeList<S> {
         );
 
         unsafe {
-            self.push_free(ptr, need.size());
+            let hole = self.insert_free(ptr, need.size());
+            self.release_if_whole(hole);
+        }
+    }
+
+    unsafe fn grow(
+        &mut self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Option<NonNull<[u8]>> {
+        if old_layout.size() != 0
+            && let Some(old) = Self::normalized(old_layout)
+            && let Some(new) = Self::normalized(new_layout)
+            && ptr.as_ptr().align_offset(new.align()) == 0
+            // align が変わると、正規化したサイズが逆に小さくなることがある
+            && new.size() >= old.size()
+            && unsafe { self.try_grow_in_place(ptr, old.size(), new.size()) }
+        {
+            return Some(NonNull::slice_from_raw_parts(ptr, new_layout.size()));
         }
+
+        let new_ptr = unsafe { self.alloc(new_layout)? };
+        unsafe {
+            ptr::copy_nonoverlapping(
+                ptr.as_ptr(),
+                new_ptr.cast::<u8>().as_ptr(),
+                old_layout.size(),
+            );
+            self.dealloc(ptr, old_layout);
+        }
+
+        Some(new_ptr)
+    }
+
+    unsafe fn shrink(
+        &mut self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Option<NonNull<[u8]>> {
+        if new_layout.size() != 0
+            && let Some(old) = Self::normalized(old_layout)
+            && let Some(new) = Self::normalized(new_layout)
+            && ptr.as_ptr().align_offset(new.align()) == 0
+            && new.size() <= old.size()
+        {
+            // 縮めた後ろの部分を空き領域として戻せるならその場で縮める
+            let tail = old.size() - new.size();
+            if tail == 0 {
+                return Some(NonNull::slice_from_raw_parts(
+                    ptr,
+                    new_layout.size(),
+                ));
+            }
+            if tail >= mem::size_of::<ListNode>() {
+                unsafe { self.insert_free(ptr.add(new.size()), tail) };
+                return Some(NonNull::slice_from_raw_parts(
+                    ptr,
+                    new_layout.size(),
+                ));
+            }
+        }
+
+        let new_ptr = unsafe { self.alloc(new_layout)? };
+        unsafe {
+            ptr::copy_nonoverlapping(
+                ptr.as_ptr(),
+                new_ptr.cast::<u8>().as_ptr(),
+                new_layout.size(),
+            );
+            self.dealloc(ptr, old_layout);
+        }
+
+        Some(new_ptr)
     }
 }
 
+/// チャンク先頭のヘッダが占めるバイト数（ListNode の align の倍数）
+const CHUNK_HEADER_SIZE: usize =
+    mem::size_of::<ChunkNode>().next_multiple_of(mem::align_of::<ListNode>());
+
 #[derive(Clone, Copy)]
 struct Fit {
     /// ユーザに返す先頭。（内部サイズぶん確保）
@@ -241,3 +767,430 @@ struct Fit {
     /// 後ろに残る空き。
     suffix_size: usize,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::fit::{BestFit, NextFit, WorstFit};
+    use super::*;
+    use core::alloc::Layout;
+    use core::ptr::NonNull;
+    use std::alloc::{alloc, dealloc};
+    use std::vec;
+    use std::vec::Vec;
+
+    use crate::source::stats::CountingSource;
+
+    /// 返されたチャンクが本当に渡したものかを確かめる source
+    struct MockSource {
+        /// 生きてるチャンク
+        live: Vec<(NonNull<u8>, Layout)>,
+    }
+
+    fn mock() -> CountingSource<MockSource> {
+        CountingSource::new(MockSource { live: vec![] })
+    }
+
+    impl MemorySource for MockSource {
+        unsafe fn request_chunk(
+            &mut self,
+            layout: Layout,
+        ) -> Option<NonNull<[u8]>> {
+            if layout.size() == 0 {
+                return None;
+            }
+
+            let ptr = unsafe { alloc(layout) };
+            let nn = NonNull::new(ptr)?;
+            self.live.push((nn, layout));
+
+            Some(NonNull::slice_from_raw_parts(nn, layout.size()))
+        }
+
+        unsafe fn release_chunk(&mut self, ptr: NonNull<u8>, layout: Layout) {
+            let idx = self
+                .live
+                .iter()
+                .position(|(p, l)| p == &ptr && l == &layout)
+                .expect("release_chunk called with unknown ptr/layout");
+
+            let (p, l) = self.live.swap_remove(idx);
+            unsafe { dealloc(p.as_ptr(), l) };
+        }
+    }
+
+    /// free list を先頭から辿って (アドレス, サイズ) を集める
+    fn holes<S: MemorySource, P: FitPolicy>(
+        a: &FreeList<S, P>,
+    ) -> Vec<(usize, usize)> {
+        let mut v = vec![];
+        let mut cur = a.head;
+        while let Some(node) = cur {
+            let node_ref = unsafe { node.as_ref() };
+            v.push((node.as_ptr().addr(), node_ref.size));
+            cur = node_ref.next;
+        }
+        v
+    }
+
+    /// テスト用の決定的な疑似乱数 (xorshift)
+    fn shuffle<T>(v: &mut [T], mut seed: u64) {
+        for i in (1..v.len()).rev() {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            let j = (seed % (i as u64 + 1)) as usize;
+            v.swap(i, j);
+        }
+    }
+
+    #[test]
+    fn dealloc_merges_with_both_neighbours() {
+        let mut a = FreeList::new(mock());
+
+        let l = Layout::from_size_align(64, 8).unwrap();
+        let p1 = unsafe { a.alloc(l).unwrap() }.cast::<u8>();
+        let p2 = unsafe { a.alloc(l).unwrap() }.cast::<u8>();
+        let p3 = unsafe { a.alloc(l).unwrap() }.cast::<u8>();
+        let before = holes(&a).len();
+
+        unsafe {
+            a.dealloc(p1, l);
+            a.dealloc(p3, l);
+        }
+        assert!(holes(&a).len() > before);
+
+        // 真ん中を解放すると前後とまとめて 1 つの空きになる
+        unsafe { a.dealloc(p2, l) };
+        assert_eq!(holes(&a).len(), 1);
+    }
+
+    #[test]
+    fn free_list_is_address_ordered() {
+        let mut a = FreeList::new(mock());
+
+        let l = Layout::from_size_align(32, 8).unwrap();
+        let ptrs: Vec<_> = (0..16)
+            .map(|_| unsafe { a.alloc(l).unwrap() }.cast::<u8>())
+            .collect();
+        for p in ptrs.iter().step_by(2).rev() {
+            unsafe { a.dealloc(*p, l) };
+        }
+
+        let hs = holes(&a);
+        assert!(hs.windows(2).all(|w| w[0].0 + w[0].1 < w[1].0));
+    }
+
+    #[test]
+    fn freeing_everything_in_random_order_leaves_one_hole_per_chunk() {
+        let mut a = FreeList::new(mock()).with_retain(usize::MAX);
+
+        let mut blocks = vec![];
+        for i in 0..500usize {
+            let l = Layout::from_size_align(16 + (i * 37) % 200, 8).unwrap();
+            let p = unsafe { a.alloc(l).unwrap() }.cast::<u8>();
+            blocks.push((p, l));
+        }
+        shuffle(&mut blocks, 0x2545_f491_4f6c_dd1d);
+
+        for (p, l) in blocks {
+            unsafe { a.dealloc(p, l) };
+        }
+
+        assert_eq!(holes(&a).len(), a.source.stats().requests);
+    }
+
+    #[test]
+    fn grow_absorbs_adjacent_free_block() {
+        let mut a = FreeList::new(mock());
+
+        let old = Layout::from_size_align(32, 8).unwrap();
+        let new = Layout::from_size_align(128, 8).unwrap();
+        let p = unsafe { a.alloc(old).unwrap() }.cast::<u8>();
+        unsafe { p.write_bytes(0x5A, 32) };
+
+        let q = unsafe { a.grow(p, old, new).unwrap() };
+        assert_eq!(q.cast::<u8>(), p);
+        assert_eq!(q.len(), 128);
+        assert_eq!(unsafe { p.add(31).read() }, 0x5A);
+    }
+
+    #[test]
+    fn grow_to_a_smaller_align_falls_back_to_copy() {
+        let mut a = FreeList::new(mock()).with_retain(usize::MAX);
+
+        // 正規化すると old は 32 バイト、new は 24 バイトになる
+        let old = Layout::from_size_align(17, 32).unwrap();
+        let new = Layout::from_size_align(20, 8).unwrap();
+        let p = unsafe { a.alloc(old).unwrap() }.cast::<u8>();
+        unsafe { p.write_bytes(0x5A, 17) };
+
+        let q = unsafe { a.grow(p, old, new).unwrap() };
+        assert_eq!(q.len(), 20);
+        assert_eq!(unsafe { q.cast::<u8>().add(16).read() }, 0x5A);
+        unsafe { a.dealloc(q.cast(), new) };
+        // 全部解放したので、チャンクごとに空き領域が 1 つずつ
+        assert_eq!(a.verify().unwrap().holes, a.source.stats().requests);
+    }
+
+    #[test]
+    fn shrink_to_a_larger_align_falls_back_to_copy() {
+        let mut a = FreeList::new(mock()).with_retain(usize::MAX);
+
+        // 正規化すると old は 24 バイト、new は 64 バイトになる
+        let old = Layout::from_size_align(24, 8).unwrap();
+        let new = Layout::from_size_align(20, 64).unwrap();
+        let p = unsafe { a.alloc(old).unwrap() }.cast::<u8>();
+        unsafe { p.write_bytes(0x5A, 24) };
+
+        let q = unsafe { a.shrink(p, old, new).unwrap() };
+        assert_eq!(q.len(), 20);
+        assert!(q.cast::<u8>().addr().get().is_multiple_of(64));
+        assert_eq!(unsafe { q.cast::<u8>().add(19).read() }, 0x5A);
+        unsafe { a.dealloc(q.cast(), new) };
+        // 全部解放したので、チャンクごとに空き領域が 1 つずつ
+        assert_eq!(a.verify().unwrap().holes, a.source.stats().requests);
+    }
+
+    #[test]
+    fn alloc_just_below_minimum_chunk_size() {
+        let mut a = FreeList::new(mock()).with_retain(0);
+
+        // ヘッダ込みで 4096 - 8 バイトになるサイズ
+        let size = 4096 - 8 - CHUNK_HEADER_SIZE;
+        let l = Layout::from_size_align(size, 8).unwrap();
+        let p = unsafe { a.alloc(l).unwrap() };
+        assert_eq!(p.len(), size);
+        unsafe { a.dealloc(p.cast::<u8>(), l) };
+    }
+
+    #[test]
+    fn wholly_free_chunk_is_released() {
+        let mut a = FreeList::new(mock()).with_retain(0);
+
+        let l = Layout::from_size_align(64, 8).unwrap();
+        let p1 = unsafe { a.alloc(l).unwrap() }.cast::<u8>();
+        let p2 = unsafe { a.alloc(l).unwrap() }.cast::<u8>();
+
+        unsafe { a.dealloc(p1, l) };
+        assert_eq!(a.source.stats().releases, 0);
+
+        unsafe { a.dealloc(p2, l) };
+        assert_eq!(a.source.stats().releases, 1);
+        assert!(holes(&a).is_empty());
+        assert_eq!(a.chunk_bytes, 0);
+    }
+
+    #[test]
+    fn free_chunks_within_retain_are_kept() {
+        let mut a = FreeList::new(mock()).with_retain(8192);
+
+        let l = Layout::from_size_align(64, 8).unwrap();
+        let p = unsafe { a.alloc(l).unwrap() }.cast::<u8>();
+        unsafe { a.dealloc(p, l) };
+
+        assert_eq!(a.source.stats().releases, 0);
+        assert_eq!(holes(&a).len(), 1);
+    }
+
+    #[test]
+    fn drop_releases_all_chunks() {
+        let mut source = mock();
+        {
+            let mut a = FreeList::new(&mut source);
+            let l = Layout::from_size_align(3000, 8).unwrap();
+
+            // 複数チャンクを作る
+            let _ = unsafe { a.alloc(l).unwrap() };
+            let _ = unsafe { a.alloc(l).unwrap() };
+            let _ = unsafe { a.alloc(l).unwrap() };
+        } // drop で release_chunk が走る
+
+        let st = source.stats();
+        assert!(st.requests >= 3);
+        assert_eq!(st.releases, st.requests);
+    }
+
+    /// 64, 128, 32 バイトの空き領域を持つ FreeList を作り、
+    /// 最初の空き領域の先頭アドレスを返す
+    fn scripted_holes<P: FitPolicy>(
+        a: &mut FreeList<CountingSource<MockSource>, P>,
+    ) -> usize {
+        let sizes = [64, 16, 128, 16, 32, 16];
+        let blocks: Vec<_> = sizes
+            .iter()
+            .map(|&size| {
+                let l = Layout::from_size_align(size, 8).unwrap();
+                (unsafe { a.alloc(l).unwrap() }.cast::<u8>(), l)
+            })
+            .collect();
+
+        // チャンクの残りを埋める
+        let (_, rest) = holes(a)[0];
+        let l = Layout::from_size_align(rest, 8).unwrap();
+        let _ = unsafe { a.alloc(l).unwrap() };
+        assert!(holes(a).is_empty());
+
+        for i in [0, 2, 4] {
+            let (p, l) = blocks[i];
+            unsafe { a.dealloc(p, l) };
+        }
+
+        blocks[0].0.as_ptr().addr()
+    }
+
+    /// 32, 96, 16 バイトを順に確保し、基準アドレスからのオフセットを返す
+    fn scripted_offsets<P: FitPolicy>(policy: P) -> [usize; 3] {
+        let mut a = FreeList::with_policy(mock(), policy);
+        let base = scripted_holes(&mut a);
+
+        [32, 96, 16].map(|size| {
+            let l = Layout::from_size_align(size, 8).unwrap();
+            addr(unsafe { a.alloc(l).unwrap() }) - base
+        })
+    }
+
+    fn addr(p: NonNull<[u8]>) -> usize {
+        p.cast::<u8>().as_ptr().addr()
+    }
+
+    // 空き領域は [0, 64), [80, 208), [224, 256)
+
+    #[test]
+    fn first_fit_picks_lowest_address() {
+        assert_eq!(scripted_offsets(FirstFit), [0, 80, 32]);
+    }
+
+    #[test]
+    fn next_fit_continues_from_rover() {
+        assert_eq!(scripted_offsets(NextFit::new()), [0, 80, 176]);
+    }
+
+    #[test]
+    fn best_fit_picks_smallest_hole() {
+        assert_eq!(scripted_offsets(BestFit), [224, 80, 176]);
+    }
+
+    #[test]
+    fn worst_fit_picks_largest_hole() {
+        assert_eq!(scripted_offsets(WorstFit), [80, 112, 0]);
+    }
+
+    #[test]
+    fn verify_reports_holes() {
+        let mut a = FreeList::new(mock()).with_retain(usize::MAX);
+        assert_eq!(
+            a.verify(),
+            Ok(HeapReport {
+                free_bytes: 0,
+                holes: 0,
+                largest_hole: 0
+            })
+        );
+
+        let mut blocks = vec![];
+        for i in 0..300usize {
+            let l = Layout::from_size_align(8 + (i * 53) % 300, 8 << (i % 4))
+                .unwrap();
+            let p = unsafe { a.alloc(l).unwrap() }.cast::<u8>();
+            blocks.push((p, l));
+        }
+        shuffle(&mut blocks, 0x9e37_79b9_7f4a_7c15);
+
+        for (i, (p, l)) in blocks.into_iter().enumerate() {
+            unsafe { a.dealloc(p, l) };
+            if i % 10 == 0 {
+                let report = a.verify().unwrap();
+                let hs = holes(&a);
+                assert_eq!(report.holes, hs.len());
+                assert_eq!(report.free_bytes, hs.iter().map(|h| h.1).sum());
+                assert_eq!(
+                    report.largest_hole,
+                    hs.iter().map(|h| h.1).max().unwrap()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn verify_finds_broken_lists() {
+        let mut a = FreeList::new(mock());
+
+        // 空き領域を 3 つ作る
+        let l = Layout::from_size_align(64, 8).unwrap();
+        let ptrs: Vec<_> = (0..5)
+            .map(|_| unsafe { a.alloc(l).unwrap() }.cast::<u8>())
+            .collect();
+        unsafe {
+            a.dealloc(ptrs[0], l);
+            a.dealloc(ptrs[2], l);
+        }
+        assert_eq!(a.verify().unwrap().holes, 3);
+
+        let nodes: Vec<_> = {
+            let mut v = vec![];
+            let mut cur = a.head;
+            while let Some(n) = cur {
+                v.push(n);
+                cur = unsafe { n.as_ref().next };
+            }
+            v
+        };
+        let addr = |i: usize| nodes[i].as_ptr().addr();
+        let head = a.head;
+
+        unsafe {
+            (*nodes[1].as_ptr()).size = 8;
+            assert_eq!(
+                a.verify(),
+                Err(HeapError::TooSmall {
+                    addr: addr(1),
+                    size: 8
+                })
+            );
+
+            (*nodes[1].as_ptr()).size = 64 * 4;
+            assert_eq!(
+                a.verify(),
+                Err(HeapError::Overlap {
+                    addr: addr(2),
+                    prev_end: addr(1) + 64 * 4
+                })
+            );
+            (*nodes[1].as_ptr()).size = 64;
+
+            (*nodes[2].as_ptr()).next = Some(nodes[1]);
+            assert_eq!(a.verify(), Err(HeapError::Cycle { addr: addr(1) }));
+            (*nodes[2].as_ptr()).next = None;
+
+            // 1 → 0 の順に並べ替える
+            a.head = Some(nodes[1]);
+            (*nodes[1].as_ptr()).next = Some(nodes[0]);
+            (*nodes[0].as_ptr()).next = None;
+            assert_eq!(
+                a.verify(),
+                Err(HeapError::Unordered {
+                    addr: addr(0),
+                    prev: addr(1)
+                })
+            );
+            (*nodes[0].as_ptr()).next = Some(nodes[1]);
+            (*nodes[1].as_ptr()).next = Some(nodes[2]);
+
+            a.head = Some(nodes[0].byte_add(4));
+            assert_eq!(
+                a.verify(),
+                Err(HeapError::Misaligned { addr: addr(0) + 4 })
+            );
+
+            let mut outside = ListNode {
+                size: 64,
+                next: None,
+            };
+            a.head = Some(NonNull::from(&mut outside));
+            assert!(matches!(a.verify(), Err(HeapError::OutsideChunk { .. })));
+
+            a.head = head;
+        }
+        assert_eq!(a.verify().unwrap().holes, 3);
+    }
+}
@@ -0,0 +1,106 @@
+//! `FileMapped` の上のヒープを、プロセスをまたいで使い続ける。
+
+use core::{
+    borrow::BorrowMut,
+    mem::{self, ManuallyDrop},
+    ptr::{self, NonNull},
+};
+use std::io;
+
+use super::{ChunkNode, FreeList, ListNode, fit::FirstFit, fit::FitPolicy};
+use crate::source::{
+    MemorySource,
+    file::{AllocatorKind, FileMapped},
+};
+
+impl<S: MemorySource + BorrowMut<FileMapped>> FreeList<S, FirstFit> {
+    /// `detach` でヘッダに残した管理情報から、first-fit で割り当てる `FreeList` を作り直す。
+    pub fn reopen(source: S) -> io::Result<Self> {
+        Self::reopen_with_policy(source, FirstFit)
+    }
+}
+
+impl<S: MemorySource + BorrowMut<FileMapped>, P: FitPolicy> FreeList<S, P> {
+    /// `detach` でヘッダに残した管理情報から、探索戦略 `policy` で割り当てる `FreeList` を作り直す。
+    ///
+    /// ファイルが固定のベースアドレスでなければ `InvalidInput`、
+    /// 管理情報がないか、ヘッダのチャンクの一覧と辻褄が合わないか、`verify` が失敗すれば `InvalidData` を返す。
+    /// チャンクの中に置いたノードの大きさは確かめずに信用する。
+    pub fn reopen_with_policy(mut source: S, policy: P) -> io::Result<Self> {
+        let file = source.borrow_mut();
+        let [head, chunks, retain, _] =
+            file.take_allocator(AllocatorKind::FreeList)?;
+        let invalid = || io::Error::from(io::ErrorKind::InvalidData);
+
+        let is_chunk_list = file.is_chunk_list(chunks, |node| {
+            let node = node.cast::<ChunkNode>().as_ptr();
+            unsafe { ((*node).ptr, (*node).next.map(NonNull::cast)) }
+        });
+        if !is_chunk_list {
+            return Err(invalid());
+        }
+        let chunks = file.live_chunk_at(chunks).map(|c| c.cast::<ChunkNode>());
+
+        let head = match head {
+            0 => None,
+            head => Some(
+                file.live_range(head, mem::size_of::<ListNode>())
+                    .ok_or_else(invalid)?
+                    .cast::<ListNode>(),
+            ),
+        };
+
+        let mut chunk_bytes = 0;
+        let mut cur = chunks;
+        while let Some(node) = cur {
+            let node = unsafe { node.as_ref() };
+            chunk_bytes += node.layout.size();
+            cur = node.next;
+        }
+
+        let list = Self {
+            source,
+            head,
+            policy,
+            chunks,
+            chunk_bytes,
+            retain,
+        };
+        if list.verify().is_err() {
+            // チャンクはファイルに残したまま手放す
+            drop(list.into_source());
+            return Err(invalid());
+        }
+        Ok(list)
+    }
+
+    /// 管理情報をヘッダに残し、チャンクを返さずに `source` を返す。
+    ///
+    /// 確保した領域はファイルに残るので、開き直したファイルを `reopen` に渡せば、
+    /// 同じヒープで割当や解放を続けられる。
+    pub fn detach(mut self) -> S {
+        let file = self.source.borrow();
+        let words = [
+            file.offset_of(self.head),
+            file.offset_of(self.chunks),
+            self.retain,
+            0,
+        ];
+        self.source
+            .borrow_mut()
+            .save_allocator(AllocatorKind::FreeList, words);
+
+        self.into_source()
+    }
+
+    /// チャンクを切り出しているファイル
+    pub fn file(&self) -> &FileMapped {
+        self.source.borrow()
+    }
+
+    /// drop を走らせずに（チャンクを返さずに）`source` を取り出す。
+    fn into_source(self) -> S {
+        let this = ManuallyDrop::new(self);
+        unsafe { ptr::read(&this.source) }
+    }
+}
@@ -0,0 +1,90 @@
+//! `FreeList` がどの空き領域から割り当てるかを決める探索戦略。
+
+/// 空き領域の探索戦略。
+///
+/// `FreeList` は free list を常に先頭から末尾まで走査し、`start` が返すアドレス以降のノードを優先します。
+/// `start` 以降に割当可能な空き領域がなければ、`start` より前で最初に見つかったものを使います。
+/// 割当可能な空き領域が見つかるたびに、その大きさで候補を比較します。
+///
+/// free list は片方向リストなので、`start` から走査を始めることはできません。
+/// どの戦略でも、1 回の割当の走査は free list の長さに比例します。
+pub trait FitPolicy {
+    /// `true` なら、最初に見つかった割当可能な空き領域をそのまま採用します。
+    const STOP_AT_FIRST: bool;
+
+    /// 走査を始めるアドレス。
+    fn start(&self) -> usize {
+        0
+    }
+
+    /// 大きさ `candidate` の空き領域を、これまでの候補（大きさ `best`）より優先するかどうか。
+    /// `STOP_AT_FIRST` が `true` のときは呼ばれません。
+    fn is_better(&self, _candidate: usize, _best: usize) -> bool {
+        false
+    }
+
+    /// 割当が決まったときに、割り当てた領域の末尾のアドレスを受け取ります。
+    fn on_alloc(&mut self, _end: usize) {}
+}
+
+/// 先頭から走査して、最初に見つかった空き領域を使う。
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FirstFit;
+
+impl FitPolicy for FirstFit {
+    const STOP_AT_FIRST: bool = true;
+}
+
+/// 割当可能な中で一番小さい空き領域を使う。
+#[derive(Clone, Copy, Debug, Default)]
+pub struct BestFit;
+
+impl FitPolicy for BestFit {
+    const STOP_AT_FIRST: bool = false;
+
+    fn is_better(&self, candidate: usize, best: usize) -> bool {
+        candidate < best
+    }
+}
+
+/// 割当可能な中で一番大きい空き領域を使う。
+#[derive(Clone, Copy, Debug, Default)]
+pub struct WorstFit;
+
+impl FitPolicy for WorstFit {
+    const STOP_AT_FIRST: bool = false;
+
+    fn is_better(&self, candidate: usize, best: usize) -> bool {
+        candidate > best
+    }
+}
+
+/// 前回割り当てた位置（ローバー）から走査して、最初に見つかった空き領域を使う。
+///
+/// ローバーはノードへのポインタではなくアドレスで持つので、
+/// 空き領域が結合・分割されてもダングリングにならない。
+///
+/// ここでの next-fit が変えるのは割り当てる場所だけで、探索の手間は減らない。
+/// ローバーより前のノードも先頭から辿るので、走査は first-fit と同じく free list の長さに比例する。
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NextFit {
+    rover: usize,
+}
+
+impl NextFit {
+    pub const fn new() -> Self {
+        Self { rover: 0 }
+    }
+}
+
+impl FitPolicy for NextFit {
+    const STOP_AT_FIRST: bool = true;
+
+    fn start(&self) -> usize {
+        self.rover
+    }
+
+    fn on_alloc(&mut self, end: usize) {
+        self.rover = end;
+    }
+}
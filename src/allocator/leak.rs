@@ -0,0 +1,420 @@
+//! 解放されていない確保を一覧にする。
+
+use core::{alloc::Layout, marker::PhantomData, mem, ptr::NonNull};
+
+use crate::{align::align_up, allocator::MutAllocator};
+
+/// 1 つの確保について記録するバックトレースの最大の深さ
+pub const BACKTRACE_FRAMES: usize = 16;
+
+/// 確保した領域の直前に置くヘッダ。
+/// バックトレースを取るときは、その前に `BACKTRACE_FRAMES` 個のアドレスを置く。
+struct Header {
+    prev: Option<NonNull<Header>>,
+    next: Option<NonNull<Header>>,
+    seq: u64,
+    layout: Layout,
+    /// 記録したバックトレースの深さ
+    frames: usize,
+}
+
+/// 生きている確保をすべてリストにつないでおく `MutAllocator`。
+///
+/// 確保した領域の直前にヘッダを置いて双方向リストにつなぎ、`dealloc` で外す。
+/// `live` で、まだ解放されていない確保を古い順に列挙できる。
+/// 各確保には通し番号が付くので、`checkpoint` で取った番号と比べれば、
+/// ある時点より後に確保されてまだ生きているものだけを見られる。
+///
+/// `with_backtrace(true)` にすると、確保したときのバックトレース（戻りアドレスの列）も記録する。
+/// 記録できるのは `std` が有効な linux（glibc）だけで、それ以外では空になる。
+///
+/// `#[global_allocator]` として使うときは、`with_lock` の中で確保しないこと
+/// （同じロックを取ろうとして止まる）。そのために `std` では `write_report` を用意している。
+pub struct LeakTracker<A: MutAllocator> {
+    inner: A,
+    backtrace: bool,
+
+    head: Option<NonNull<Header>>,
+    tail: Option<NonNull<Header>>,
+    next_seq: u64,
+    live_count: usize,
+    live_bytes: usize,
+}
+
+unsafe impl<A: MutAllocator + Send> Send for LeakTracker<A> {}
+
+/// `LeakTracker` が記録している、生きている確保
+#[derive(Clone, Copy)]
+pub struct LiveAllocation<'a> {
+    header: &'a Header,
+}
+
+impl<'a> LiveAllocation<'a> {
+    /// 確保した領域の先頭
+    pub fn ptr(&self) -> NonNull<u8> {
+        let header = NonNull::from(self.header);
+        unsafe { header.add(1).cast() }
+    }
+
+    /// 確保したときの `Layout`
+    pub fn layout(&self) -> Layout {
+        self.header.layout
+    }
+
+    /// 確保の通し番号
+    pub fn seq(&self) -> u64 {
+        self.header.seq
+    }
+
+    /// 確保したときのバックトレース。記録していなければ空。
+    pub fn backtrace(&self) -> &'a [usize] {
+        // 記録していないときは、ヘッダの前に置き場所がない
+        if self.header.frames == 0 {
+            return &[];
+        }
+
+        let header = NonNull::from(self.header);
+        unsafe {
+            let frames = header.cast::<usize>().sub(BACKTRACE_FRAMES);
+            core::slice::from_raw_parts(frames.as_ptr(), self.header.frames)
+        }
+    }
+}
+
+/// `LeakTracker::live` が返すイテレータ
+pub struct Live<'a> {
+    current: Option<NonNull<Header>>,
+    _marker: PhantomData<&'a Header>,
+}
+
+impl<'a> Iterator for Live<'a> {
+    type Item = LiveAllocation<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let header = unsafe { self.current?.as_ref() };
+        self.current = header.next;
+        Some(LiveAllocation { header })
+    }
+}
+
+impl<A: MutAllocator> LeakTracker<A> {
+    pub const fn new(inner: A) -> Self {
+        Self {
+            inner,
+            backtrace: false,
+            head: None,
+            tail: None,
+            next_seq: 0,
+            live_count: 0,
+            live_bytes: 0,
+        }
+    }
+
+    /// 確保したときのバックトレースを記録するかどうか。
+    /// 途中で変えると `dealloc` でヘッダを見つけられなくなるので、確保を始める前に決めること。
+    pub const fn with_backtrace(mut self, backtrace: bool) -> Self {
+        self.backtrace = backtrace;
+        self
+    }
+
+    /// 内側のアロケータ
+    pub fn inner(&self) -> &A {
+        &self.inner
+    }
+
+    /// 内側のアロケータ
+    pub fn inner_mut(&mut self) -> &mut A {
+        &mut self.inner
+    }
+
+    /// 生きている確保を古い順に列挙する。
+    pub fn live(&self) -> Live<'_> {
+        Live {
+            current: self.head,
+            _marker: PhantomData,
+        }
+    }
+
+    /// 生きている確保の数
+    pub fn live_count(&self) -> usize {
+        self.live_count
+    }
+
+    /// 生きている確保の合計バイト数
+    pub fn live_bytes(&self) -> usize {
+        self.live_bytes
+    }
+
+    /// 次の確保に付く通し番号。
+    /// `seq() >= checkpoint` の確保が、この時点より後に確保されたもの。
+    pub fn checkpoint(&self) -> u64 {
+        self.next_seq
+    }
+
+    /// ヘッダ（とバックトレース）を置く前の余白の大きさと、内側のアロケータに渡す `Layout`
+    fn padded(&self, layout: Layout) -> Option<(usize, Layout)> {
+        let align = layout.align().max(mem::align_of::<Header>());
+        let mut header = mem::size_of::<Header>();
+        if self.backtrace {
+            header += BACKTRACE_FRAMES * mem::size_of::<usize>();
+        }
+
+        let front = align_up(header, align);
+        let size = front.checked_add(layout.size())?;
+        let padded = Layout::from_size_align(size, align).ok()?;
+        Some((front, padded))
+    }
+}
+
+#[cfg(all(feature = "std", target_os = "linux", target_env = "gnu"))]
+fn capture(frames: &mut [usize; BACKTRACE_FRAMES]) -> usize {
+    let n = unsafe {
+        libc::backtrace(frames.as_mut_ptr().cast(), BACKTRACE_FRAMES as _)
+    };
+    n.max(0) as usize
+}
+
+#[cfg(not(all(feature = "std", target_os = "linux", target_env = "gnu")))]
+fn capture(_frames: &mut [usize; BACKTRACE_FRAMES]) -> usize {
+    0
+}
+
+impl<A: MutAllocator> MutAllocator for LeakTracker<A> {
+    unsafe fn alloc(&mut self, layout: Layout) -> Option<NonNull<[u8]>> {
+        let (front, padded) = self.padded(layout)?;
+        let base = unsafe { self.inner.alloc(padded)? }.cast::<u8>();
+
+        unsafe {
+            let ptr = base.add(front);
+            let header = ptr.cast::<Header>().sub(1);
+
+            let frames = if self.backtrace {
+                let mut frames =
+                    header.cast::<[usize; BACKTRACE_FRAMES]>().sub(1);
+                capture(frames.as_mut())
+            } else {
+                0
+            };
+
+            header.write(Header {
+                prev: self.tail,
+                next: None,
+                seq: self.next_seq,
+                layout,
+                frames,
+            });
+            match self.tail {
+                Some(mut tail) => tail.as_mut().next = Some(header),
+                None => self.head = Some(header),
+            }
+            self.tail = Some(header);
+
+            self.next_seq += 1;
+            self.live_count += 1;
+            self.live_bytes += layout.size();
+
+            Some(NonNull::slice_from_raw_parts(ptr, layout.size()))
+        }
+    }
+
+    unsafe fn dealloc(&mut self, ptr: NonNull<u8>, layout: Layout) {
+        // alloc で作れた Layout なので、ここでも作れる
+        let (front, padded) = self.padded(layout).unwrap();
+
+        unsafe {
+            let header = ptr.cast::<Header>().sub(1).read();
+            match header.prev {
+                Some(mut prev) => prev.as_mut().next = header.next,
+                None => self.head = header.next,
+            }
+            match header.next {
+                Some(mut next) => next.as_mut().prev = header.prev,
+                None => self.tail = header.prev,
+            }
+
+            self.live_count -= 1;
+            self.live_bytes -= layout.size();
+
+            self.inner.dealloc(ptr.sub(front), padded);
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<A: MutAllocator> LeakTracker<A> {
+    /// 生きている確保を `fd` に書き出す。メモリを確保しないので、ロックを持ったまま呼べる。
+    ///
+    /// glibc ではバックトレースも `backtrace_symbols_fd` で書き出す。
+    pub fn write_report(&self, fd: libc::c_int) {
+        use core::fmt::Write;
+
+        struct Fd(libc::c_int);
+
+        impl Write for Fd {
+            fn write_str(&mut self, s: &str) -> core::fmt::Result {
+                let mut bytes = s.as_bytes();
+                while !bytes.is_empty() {
+                    let n = unsafe {
+                        libc::write(self.0, bytes.as_ptr().cast(), bytes.len())
+                    };
+                    if n <= 0 {
+                        return Err(core::fmt::Error);
+                    }
+                    bytes = &bytes[n as usize..];
+                }
+                Ok(())
+            }
+        }
+
+        let mut out = Fd(fd);
+        let _ = writeln!(
+            out,
+            "{} live allocations, {} bytes",
+            self.live_count, self.live_bytes
+        );
+        for a in self.live() {
+            let _ = writeln!(
+                out,
+                "  #{} {:p} size {} align {}",
+                a.seq(),
+                a.ptr(),
+                a.layout().size(),
+                a.layout().align(),
+            );
+
+            #[cfg(all(target_os = "linux", target_env = "gnu"))]
+            if !a.backtrace().is_empty() {
+                let frames = a.backtrace();
+                unsafe {
+                    libc::backtrace_symbols_fd(
+                        frames.as_ptr().cast(),
+                        frames.len() as _,
+                        fd,
+                    )
+                };
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        allocator::{bump::BumpAllocator, free_list::FreeList},
+        mutex::Locked,
+        source::os_heap::OsHeap,
+    };
+    use std::vec::Vec;
+
+    #[test]
+    fn lists_live_allocations_in_order() {
+        let mut a = LeakTracker::new(FreeList::new(OsHeap));
+
+        let layouts: Vec<_> =
+            [(10, 1), (100, 8), (64, 64), (0, 1), (5000, 4096)]
+                .iter()
+                .map(|&(size, align)| {
+                    Layout::from_size_align(size, align).unwrap()
+                })
+                .collect();
+        let ptrs: Vec<_> = layouts
+            .iter()
+            .map(|&l| unsafe { a.alloc(l).unwrap() }.cast::<u8>())
+            .collect();
+
+        for (&p, &l) in ptrs.iter().zip(&layouts) {
+            assert!(p.addr().get().is_multiple_of(l.align()));
+        }
+        assert_eq!(a.live_count(), 5);
+        assert_eq!(a.live_bytes(), 10 + 100 + 64 + 5000);
+
+        unsafe {
+            a.dealloc(ptrs[1], layouts[1]);
+            a.dealloc(ptrs[4], layouts[4]);
+        }
+
+        let live: Vec<_> =
+            a.live().map(|e| (e.ptr(), e.layout(), e.seq())).collect();
+        assert_eq!(
+            live,
+            [
+                (ptrs[0], layouts[0], 0),
+                (ptrs[2], layouts[2], 2),
+                (ptrs[3], layouts[3], 3)
+            ]
+        );
+
+        for i in [0, 2, 3] {
+            unsafe { a.dealloc(ptrs[i], layouts[i]) };
+        }
+        assert_eq!(a.live().count(), 0);
+        assert_eq!(a.live_bytes(), 0);
+    }
+
+    #[test]
+    fn checkpoint_separates_new_allocations() {
+        let mut a = LeakTracker::new(BumpAllocator::new(OsHeap));
+        let l = Layout::new::<u64>();
+
+        let _old = unsafe { a.alloc(l).unwrap() };
+        let cp = a.checkpoint();
+        let new = unsafe { a.alloc(l).unwrap() };
+
+        let leaked: Vec<_> = a
+            .live()
+            .filter(|e| e.seq() >= cp)
+            .map(|e| e.ptr())
+            .collect();
+        assert_eq!(leaked, [new.cast::<u8>()]);
+    }
+
+    #[test]
+    fn records_backtraces() {
+        let mut a =
+            LeakTracker::new(FreeList::new(OsHeap)).with_backtrace(true);
+        let l = Layout::from_size_align(24, 8).unwrap();
+
+        let p = unsafe { a.alloc(l).unwrap() };
+        let e = a.live().next().unwrap();
+        assert_eq!(e.ptr(), p.cast::<u8>());
+        if cfg!(target_env = "gnu") {
+            assert!(!e.backtrace().is_empty());
+            assert!(e.backtrace().len() <= BACKTRACE_FRAMES);
+        }
+
+        unsafe { a.dealloc(p.cast(), l) };
+        assert_eq!(a.live_count(), 0);
+    }
+
+    #[test]
+    fn reports_leaks_behind_locked() {
+        let a = Locked::new(LeakTracker::new(FreeList::new(OsHeap)));
+
+        let mut v: Vec<u32, _> = Vec::new_in(&a);
+        v.extend(0..100);
+        let kept = Vec::<u8, _>::with_capacity_in(7, &a);
+        drop(v);
+
+        a.with_lock(|t| {
+            let live: Vec<_> = t.live().map(|e| e.layout().size()).collect();
+            assert_eq!(live, [7]);
+
+            // パイプに書き出して中身を確かめる
+            let mut fds = [0; 2];
+            assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+            t.write_report(fds[1]);
+            unsafe { libc::close(fds[1]) };
+
+            let mut buf = [0u8; 256];
+            let n = unsafe {
+                libc::read(fds[0], buf.as_mut_ptr().cast(), buf.len())
+            };
+            unsafe { libc::close(fds[0]) };
+            let report = core::str::from_utf8(&buf[..n as usize]).unwrap();
+            assert!(report.starts_with("1 live allocations, 7 bytes\n"));
+            assert!(report.contains("size 7 align 1"));
+        });
+        drop(kept);
+    }
+}
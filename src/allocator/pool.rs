@@ -0,0 +1,363 @@
+use core::{
+    alloc::Layout,
+    marker::PhantomData,
+    mem,
+    ptr::{self, NonNull},
+};
+
+use crate::{
+    align::align_up,
+    allocator::{MutAllocator, stats::SourceUsage},
+    source::MemorySource,
+};
+
+/// 固定サイズのスロットを切り出すプール。
+///
+/// source から取ったチャンクを同じ大きさのスロットに区切り、空いたスロットは
+/// スロット自身に埋め込んだ free list でつなぐ。確保も解放も O(1)。
+/// スロットに収まらない `Layout`（サイズかアラインが大きすぎる）の確保は `None` を返す。
+pub struct Pool<S: MemorySource> {
+    source: S,
+
+    /// スロット 1 つの大きさ（`slot_align` の倍数）
+    slot_size: usize,
+    slot_align: usize,
+
+    /// 解放されたスロットの free list
+    free: Option<NonNull<FreeSlot>>,
+
+    /// 現在のチャンクのうち、まだ一度も切り出していない部分
+    fresh: NonNull<u8>,
+    fresh_end: NonNull<u8>,
+
+    chunks: Option<NonNull<ChunkNode>>,
+}
+
+/// 空きスロットの先頭に置く、次の空きスロットへのリンク
+struct FreeSlot {
+    next: Option<NonNull<FreeSlot>>,
+}
+
+/// チャンクの先頭に置くヘッダ
+struct ChunkNode {
+    next: Option<NonNull<ChunkNode>>,
+    ptr: NonNull<u8>,
+    layout: Layout,
+}
+
+/// 1 つのチャンクに入れるスロットの数の目安
+const SLOTS_PER_CHUNK: usize = 64;
+
+unsafe impl<S: MemorySource + Send> Send for Pool<S> {}
+
+impl<S: MemorySource> Pool<S> {
+    /// `slot` が収まるスロットを持つプールを作る。
+    /// スロットは空きリストのリンクを置けるよう、ポインタ 1 つ分以上に切り上げられる。
+    pub const fn new(source: S, slot: Layout) -> Self {
+        let align = if slot.align() < mem::align_of::<FreeSlot>() {
+            mem::align_of::<FreeSlot>()
+        } else {
+            slot.align()
+        };
+        let size = if slot.size() < mem::size_of::<FreeSlot>() {
+            mem::size_of::<FreeSlot>()
+        } else {
+            slot.size()
+        };
+
+        Self {
+            source,
+            slot_size: align_up(size, align),
+            slot_align: align,
+            free: None,
+            fresh: NonNull::dangling(),
+            fresh_end: NonNull::dangling(),
+            chunks: None,
+        }
+    }
+
+    /// スロット 1 つの `Layout`
+    pub fn slot_layout(&self) -> Layout {
+        unsafe {
+            Layout::from_size_align_unchecked(self.slot_size, self.slot_align)
+        }
+    }
+
+    /// `layout` がスロットに収まるかどうか
+    #[inline]
+    pub fn fits(&self, layout: Layout) -> bool {
+        layout.size() <= self.slot_size && layout.align() <= self.slot_align
+    }
+
+    /// 空きスロットを 1 つ取り出す
+    fn take_slot(&mut self) -> Option<NonNull<u8>> {
+        if let Some(slot) = self.free {
+            self.free = unsafe { slot.as_ref().next };
+            return Some(slot.cast::<u8>());
+        }
+
+        let remaining =
+            self.fresh_end.as_ptr().addr() - self.fresh.as_ptr().addr();
+        if remaining < self.slot_size {
+            unsafe { self.new_chunk()? };
+        }
+
+        let slot = self.fresh;
+        self.fresh = unsafe { slot.add(self.slot_size) };
+        Some(slot)
+    }
+
+    /// source から新しいチャンクを取り、未使用部分として使い始める
+    unsafe fn new_chunk(&mut self) -> Option<()> {
+        let slots = Layout::from_size_align(
+            self.slot_size.checked_mul(SLOTS_PER_CHUNK)?,
+            self.slot_align,
+        )
+        .ok()?;
+        let (request, offset) =
+            Layout::new::<ChunkNode>().extend(slots).ok()?;
+
+        let request =
+            Layout::from_size_align(request.size().max(4096), request.align())
+                .ok()?;
+        let chunk = unsafe { self.source.request_chunk(request) }?;
+
+        let actual_layout =
+            Layout::from_size_align(chunk.len(), request.align()).ok()?;
+
+        let chunk_ptr = chunk.cast::<u8>();
+        let node_ptr = chunk_ptr.cast::<ChunkNode>();
+        unsafe {
+            node_ptr.write(ChunkNode {
+                next: self.chunks,
+                ptr: chunk_ptr,
+                layout: actual_layout,
+            })
+        };
+        self.chunks = Some(node_ptr);
+
+        self.fresh = unsafe { chunk_ptr.add(offset) };
+        self.fresh_end = unsafe { chunk_ptr.add(chunk.len()) };
+        Some(())
+    }
+}
+
+impl<S: MemorySource> MutAllocator for Pool<S> {
+    unsafe fn alloc(&mut self, layout: Layout) -> Option<NonNull<[u8]>> {
+        // ZST は適当な non-null を返す
+        if layout.size() == 0 {
+            let p = ptr::without_provenance_mut::<u8>(layout.align());
+            let nn = unsafe { NonNull::new_unchecked(p) };
+            return Some(NonNull::slice_from_raw_parts(nn, 0));
+        }
+
+        if !self.fits(layout) {
+            return None;
+        }
+
+        let slot = self.take_slot()?;
+        Some(NonNull::slice_from_raw_parts(slot, layout.size()))
+    }
+
+    unsafe fn dealloc(&mut self, ptr: NonNull<u8>, layout: Layout) {
+        if layout.size() == 0 {
+            return;
+        }
+
+        debug_assert!(self.fits(layout));
+
+        let slot = ptr.cast::<FreeSlot>();
+        unsafe { slot.write(FreeSlot { next: self.free }) };
+        self.free = Some(slot);
+    }
+
+    unsafe fn grow(
+        &mut self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Option<NonNull<[u8]>> {
+        // スロットに収まる限りはそのまま。収まらなければ移す先もない
+        if old_layout.size() == 0 {
+            return unsafe { self.alloc(new_layout) };
+        }
+        if !self.fits(new_layout) {
+            return None;
+        }
+
+        Some(NonNull::slice_from_raw_parts(ptr, new_layout.size()))
+    }
+
+    unsafe fn shrink(
+        &mut self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Option<NonNull<[u8]>> {
+        if new_layout.size() == 0 {
+            unsafe { self.dealloc(ptr, old_layout) };
+            return unsafe { self.alloc(new_layout) };
+        }
+        if !self.fits(new_layout) {
+            return None;
+        }
+
+        Some(NonNull::slice_from_raw_parts(ptr, new_layout.size()))
+    }
+}
+
+impl<S: MemorySource> SourceUsage for Pool<S> {
+    fn source_bytes(&self) -> usize {
+        let mut total = 0;
+        let mut current = self.chunks;
+
+        while let Some(node_ptr) = current {
+            let node = unsafe { node_ptr.as_ref() };
+            total += node.layout.size();
+            current = node.next;
+        }
+
+        total
+    }
+}
+
+impl<S: MemorySource> Drop for Pool<S> {
+    fn drop(&mut self) {
+        let mut current = self.chunks;
+
+        while let Some(node_ptr) = current {
+            unsafe {
+                let node = node_ptr.read();
+                current = node.next;
+
+                self.source.release_chunk(node.ptr, node.layout);
+            }
+        }
+    }
+}
+
+/// `T` 専用の `Pool`。
+pub struct TypedPool<T, S: MemorySource> {
+    pool: Pool<S>,
+    _marker: PhantomData<T>,
+}
+
+impl<T, S: MemorySource> TypedPool<T, S> {
+    pub const fn new(source: S) -> Self {
+        Self {
+            pool: Pool::new(source, Layout::new::<T>()),
+            _marker: PhantomData,
+        }
+    }
+
+    /// スロットを 1 つ確保し、`value` を書き込んで返す。
+    pub fn alloc(&mut self, value: T) -> Option<NonNull<T>> {
+        let ptr = unsafe { self.pool.alloc(Layout::new::<T>())? }.cast::<T>();
+        unsafe { ptr.write(value) };
+        Some(ptr)
+    }
+
+    /// `ptr` が指す値を drop し、スロットをプールに返す。
+    ///
+    /// # Safety
+    /// - `ptr` はこのプールの `alloc` が返したポインタでなければなりません。
+    /// - 同じ `ptr` を二度 `dealloc` してはいけません。
+    pub unsafe fn dealloc(&mut self, ptr: NonNull<T>) {
+        unsafe {
+            ptr.drop_in_place();
+            self.pool.dealloc(ptr.cast::<u8>(), Layout::new::<T>());
+        }
+    }
+
+    /// 内部の `Pool`
+    pub fn pool(&mut self) -> &mut Pool<S> {
+        &mut self.pool
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::source::os_heap::OsHeap;
+
+    fn addr(p: NonNull<[u8]>) -> usize {
+        p.cast::<u8>().as_ptr().addr()
+    }
+
+    #[test]
+    fn slots_are_reused_lifo() {
+        let mut pool = Pool::new(OsHeap, Layout::new::<[u64; 3]>());
+        let l = Layout::new::<[u64; 3]>();
+
+        let p1 = unsafe { pool.alloc(l).unwrap() };
+        let p2 = unsafe { pool.alloc(l).unwrap() };
+        assert_eq!(addr(p2), addr(p1) + 24);
+
+        unsafe {
+            pool.dealloc(p1.cast::<u8>(), l);
+            pool.dealloc(p2.cast::<u8>(), l);
+        }
+        assert_eq!(addr(unsafe { pool.alloc(l).unwrap() }), addr(p2));
+        assert_eq!(addr(unsafe { pool.alloc(l).unwrap() }), addr(p1));
+    }
+
+    #[test]
+    fn rejects_layouts_that_do_not_fit() {
+        let mut pool =
+            Pool::new(OsHeap, Layout::from_size_align(32, 8).unwrap());
+
+        let too_big = Layout::from_size_align(33, 8).unwrap();
+        let over_aligned = Layout::from_size_align(16, 16).unwrap();
+        assert!(unsafe { pool.alloc(too_big) }.is_none());
+        assert!(unsafe { pool.alloc(over_aligned) }.is_none());
+
+        let smaller = Layout::from_size_align(8, 4).unwrap();
+        assert!(unsafe { pool.alloc(smaller) }.is_some());
+    }
+
+    #[test]
+    fn tiny_slots_are_rounded_up_to_a_pointer() {
+        let pool = Pool::new(OsHeap, Layout::new::<u8>());
+        assert_eq!(pool.slot_layout(), Layout::new::<usize>());
+    }
+
+    #[test]
+    fn spans_multiple_chunks() {
+        let mut pool = Pool::new(OsHeap, Layout::new::<[u8; 256]>());
+        let l = Layout::new::<[u8; 256]>();
+
+        let ptrs: std::vec::Vec<_> = (0..SLOTS_PER_CHUNK * 3)
+            .map(|_| unsafe { pool.alloc(l).unwrap() })
+            .collect();
+
+        let mut n = 0;
+        let mut cur = pool.chunks;
+        while let Some(c) = cur {
+            n += 1;
+            cur = unsafe { c.as_ref().next };
+        }
+        assert!(n >= 3);
+
+        for p in ptrs {
+            assert_eq!(addr(p) % l.align(), 0);
+        }
+    }
+
+    #[test]
+    fn typed_pool_drops_values() {
+        use std::rc::Rc;
+
+        let counter = Rc::new(());
+        let mut pool = TypedPool::<Rc<()>, _>::new(OsHeap);
+
+        let a = pool.alloc(counter.clone()).unwrap();
+        let b = pool.alloc(counter.clone()).unwrap();
+        assert_eq!(Rc::strong_count(&counter), 3);
+
+        unsafe {
+            pool.dealloc(a);
+            pool.dealloc(b);
+        }
+        assert_eq!(Rc::strong_count(&counter), 1);
+    }
+}
@@ -0,0 +1,416 @@
+use core::{
+    alloc::Layout,
+    mem,
+    ptr::{self, NonNull},
+};
+
+use crate::{
+    allocator::{MutAllocator, stats::SourceUsage},
+    source::MemorySource,
+};
+
+/// 一番小さいサイズクラス（2^4 = 16 バイト）
+const MIN_CLASS_SHIFT: u32 = 4;
+/// サイズクラスの数。16, 32, ..., 4096 バイトの 9 クラス。
+const NUM_CLASSES: usize = 9;
+/// 一番大きいサイズクラス。これを超える確保は source から直接取る。
+pub const MAX_CLASS_SIZE: usize =
+    1 << (MIN_CLASS_SHIFT as usize + NUM_CLASSES - 1);
+/// サイズクラスのブロックを切り出すために source から取るチャンクの大きさ
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// 2 の冪のサイズクラスごとに free list を持つアロケータ。
+///
+/// `MAX_CLASS_SIZE` 以下の確保は、`max(size, align)` を 2 の冪に切り上げたクラスの
+/// free list から O(1) で取り出す。クラスの free list が空なら、チャンクを 1 つ取って
+/// そのクラスのブロックに切り分ける。
+/// `MAX_CLASS_SIZE` を超える確保は、そのまま source に要求する。
+pub struct SegregatedFreeList<S: MemorySource> {
+    source: S,
+    classes: [Option<NonNull<FreeBlock>>; NUM_CLASSES],
+
+    /// サイズクラス用に取得したチャンクのリスト
+    chunks: Option<NonNull<ChunkNode>>,
+    /// source から直接取った大きな確保のリスト
+    large: Option<NonNull<LargeHeader>>,
+}
+
+/// 空きブロックの先頭に置く、次の空きブロックへのリンク
+struct FreeBlock {
+    next: Option<NonNull<FreeBlock>>,
+}
+
+/// チャンクの末尾に置くヘッダ
+struct ChunkNode {
+    next: Option<NonNull<ChunkNode>>,
+    ptr: NonNull<u8>,
+    layout: Layout,
+}
+
+/// 大きな確保の直前に置くヘッダ
+struct LargeHeader {
+    prev: Option<NonNull<LargeHeader>>,
+    next: Option<NonNull<LargeHeader>>,
+    ptr: NonNull<u8>,
+    layout: Layout,
+}
+
+unsafe impl<S: MemorySource + Send> Send for SegregatedFreeList<S> {}
+
+impl<S: MemorySource> SegregatedFreeList<S> {
+    pub const fn new(source: S) -> Self {
+        Self {
+            source,
+            classes: [None; NUM_CLASSES],
+            chunks: None,
+            large: None,
+        }
+    }
+
+    /// `layout` が入るサイズクラスの番号。大きな確保なら `None`。
+    #[inline]
+    fn class_of(layout: Layout) -> Option<usize> {
+        let size = layout.size().max(layout.align());
+        if size > MAX_CLASS_SIZE {
+            return None;
+        }
+
+        let shift = size
+            .next_power_of_two()
+            .trailing_zeros()
+            .max(MIN_CLASS_SHIFT);
+        Some((shift - MIN_CLASS_SHIFT) as usize)
+    }
+
+    #[inline]
+    const fn class_size(class: usize) -> usize {
+        1 << (MIN_CLASS_SHIFT as usize + class)
+    }
+
+    /// チャンクを 1 つ取り、`class` のブロックに切り分けて free list に積む
+    unsafe fn refill(&mut self, class: usize) -> Option<()> {
+        let block_size = Self::class_size(class);
+
+        // ブロックの align = ブロックのサイズ になるように、チャンクをブロックサイズでアラインする
+        let request = Layout::from_size_align(CHUNK_SIZE, block_size).ok()?;
+        let chunk = unsafe { self.source.request_chunk(request) }?;
+        let chunk_ptr = chunk.cast::<u8>();
+
+        let actual_layout =
+            Layout::from_size_align(chunk.len(), request.align()).ok()?;
+
+        // ヘッダは末尾に置く
+        let header_addr = (chunk_ptr.as_ptr().addr() + chunk.len()
+            - mem::size_of::<ChunkNode>())
+            & !(mem::align_of::<ChunkNode>() - 1);
+        let header_offset = header_addr - chunk_ptr.as_ptr().addr();
+        let node_ptr =
+            unsafe { chunk_ptr.add(header_offset) }.cast::<ChunkNode>();
+        unsafe {
+            node_ptr.write(ChunkNode {
+                next: self.chunks,
+                ptr: chunk_ptr,
+                layout: actual_layout,
+            })
+        };
+        self.chunks = Some(node_ptr);
+
+        // 後ろから積むと、先頭のブロックから順に使われる
+        let count = header_offset / block_size;
+        for i in (0..count).rev() {
+            let block = unsafe { chunk_ptr.add(i * block_size) };
+            unsafe { self.push_block(class, block) };
+        }
+
+        (count > 0).then_some(())
+    }
+
+    unsafe fn push_block(&mut self, class: usize, block: NonNull<u8>) {
+        let block = block.cast::<FreeBlock>();
+        unsafe {
+            block.write(FreeBlock {
+                next: self.classes[class],
+            })
+        };
+        self.classes[class] = Some(block);
+    }
+
+    fn pop_block(&mut self, class: usize) -> Option<NonNull<u8>> {
+        let block = self.classes[class]?;
+        self.classes[class] = unsafe { block.as_ref().next };
+        Some(block.cast::<u8>())
+    }
+
+    /// source から直接確保し、直前に `LargeHeader` を置く
+    unsafe fn alloc_large(&mut self, layout: Layout) -> Option<NonNull<u8>> {
+        let (request, offset) =
+            Layout::new::<LargeHeader>().extend(layout).ok()?;
+        let chunk = unsafe { self.source.request_chunk(request) }?;
+        let chunk_ptr = chunk.cast::<u8>();
+
+        let actual_layout =
+            Layout::from_size_align(chunk.len(), request.align()).ok()?;
+
+        let user_ptr = unsafe { chunk_ptr.add(offset) };
+        let header = unsafe { Self::large_header(user_ptr) };
+        unsafe {
+            header.write(LargeHeader {
+                prev: None,
+                next: self.large,
+                ptr: chunk_ptr,
+                layout: actual_layout,
+            });
+            if let Some(mut next) = self.large {
+                next.as_mut().prev = Some(header);
+            }
+        }
+        self.large = Some(header);
+
+        Some(user_ptr)
+    }
+
+    unsafe fn dealloc_large(&mut self, ptr: NonNull<u8>) {
+        let header = unsafe { Self::large_header(ptr).read() };
+
+        match header.prev {
+            None => self.large = header.next,
+            Some(mut prev) => unsafe { prev.as_mut().next = header.next },
+        }
+        if let Some(mut next) = header.next {
+            unsafe { next.as_mut().prev = header.prev };
+        }
+
+        unsafe { self.source.release_chunk(header.ptr, header.layout) };
+    }
+
+    /// 大きな確保のユーザ領域の先頭から、そのヘッダを得る
+    #[inline]
+    unsafe fn large_header(ptr: NonNull<u8>) -> NonNull<LargeHeader> {
+        unsafe { ptr.cast::<LargeHeader>().sub(1) }
+    }
+}
+
+impl<S: MemorySource> MutAllocator for SegregatedFreeList<S> {
+    unsafe fn alloc(&mut self, layout: Layout) -> Option<NonNull<[u8]>> {
+        // ZST は適当な non-null を返す
+        if layout.size() == 0 {
+            let p = ptr::without_provenance_mut::<u8>(layout.align());
+            let nn = unsafe { NonNull::new_unchecked(p) };
+            return Some(NonNull::slice_from_raw_parts(nn, 0));
+        }
+
+        let ptr = match Self::class_of(layout) {
+            Some(class) => match self.pop_block(class) {
+                Some(block) => block,
+                None => {
+                    unsafe { self.refill(class)? };
+                    self.pop_block(class)?
+                }
+            },
+            None => unsafe { self.alloc_large(layout)? },
+        };
+
+        Some(NonNull::slice_from_raw_parts(ptr, layout.size()))
+    }
+
+    unsafe fn dealloc(&mut self, ptr: NonNull<u8>, layout: Layout) {
+        if layout.size() == 0 {
+            return;
+        }
+
+        match Self::class_of(layout) {
+            Some(class) => unsafe { self.push_block(class, ptr) },
+            None => unsafe { self.dealloc_large(ptr) },
+        }
+    }
+
+    unsafe fn grow(
+        &mut self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Option<NonNull<[u8]>> {
+        // 同じサイズクラスに収まるならそのまま
+        if old_layout.size() != 0
+            && let Some(class) = Self::class_of(old_layout)
+            && Self::class_of(new_layout) == Some(class)
+        {
+            return Some(NonNull::slice_from_raw_parts(ptr, new_layout.size()));
+        }
+
+        let new_ptr = unsafe { self.alloc(new_layout)? };
+        unsafe {
+            ptr::copy_nonoverlapping(
+                ptr.as_ptr(),
+                new_ptr.cast::<u8>().as_ptr(),
+                old_layout.size(),
+            );
+            self.dealloc(ptr, old_layout);
+        }
+
+        Some(new_ptr)
+    }
+
+    unsafe fn shrink(
+        &mut self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Option<NonNull<[u8]>> {
+        // 同じサイズクラスに収まるならそのまま
+        if new_layout.size() != 0
+            && let Some(class) = Self::class_of(old_layout)
+            && Self::class_of(new_layout) == Some(class)
+        {
+            return Some(NonNull::slice_from_raw_parts(ptr, new_layout.size()));
+        }
+
+        let new_ptr = unsafe { self.alloc(new_layout)? };
+        unsafe {
+            ptr::copy_nonoverlapping(
+                ptr.as_ptr(),
+                new_ptr.cast::<u8>().as_ptr(),
+                new_layout.size(),
+            );
+            self.dealloc(ptr, old_layout);
+        }
+
+        Some(new_ptr)
+    }
+}
+
+impl<S: MemorySource> SourceUsage for SegregatedFreeList<S> {
+    fn source_bytes(&self) -> usize {
+        let mut total = 0;
+
+        let mut current = self.chunks;
+        while let Some(node_ptr) = current {
+            let node = unsafe { node_ptr.as_ref() };
+            total += node.layout.size();
+            current = node.next;
+        }
+
+        let mut current = self.large;
+        while let Some(header_ptr) = current {
+            let header = unsafe { header_ptr.as_ref() };
+            total += header.layout.size();
+            current = header.next;
+        }
+
+        total
+    }
+}
+
+impl<S: MemorySource> Drop for SegregatedFreeList<S> {
+    fn drop(&mut self) {
+        let mut current = self.chunks;
+        while let Some(node_ptr) = current {
+            unsafe {
+                let node = node_ptr.read();
+                current = node.next;
+
+                self.source.release_chunk(node.ptr, node.layout);
+            }
+        }
+
+        let mut current = self.large;
+        while let Some(header_ptr) = current {
+            unsafe {
+                let header = header_ptr.read();
+                current = header.next;
+
+                self.source.release_chunk(header.ptr, header.layout);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::source::os_heap::OsHeap;
+
+    fn addr(p: NonNull<[u8]>) -> usize {
+        p.cast::<u8>().as_ptr().addr()
+    }
+
+    fn large_count<S: MemorySource>(a: &SegregatedFreeList<S>) -> usize {
+        let mut n = 0;
+        let mut cur = a.large;
+        while let Some(h) = cur {
+            n += 1;
+            cur = unsafe { h.as_ref().next };
+        }
+        n
+    }
+
+    #[test]
+    fn class_of_rounds_up_to_power_of_two() {
+        let class = |size, align| {
+            SegregatedFreeList::<OsHeap>::class_of(
+                Layout::from_size_align(size, align).unwrap(),
+            )
+        };
+
+        assert_eq!(class(1, 1), Some(0));
+        assert_eq!(class(16, 8), Some(0));
+        assert_eq!(class(17, 8), Some(1));
+        assert_eq!(class(8, 64), Some(2));
+        assert_eq!(class(MAX_CLASS_SIZE, 8), Some(NUM_CLASSES - 1));
+        assert_eq!(class(MAX_CLASS_SIZE + 1, 8), None);
+    }
+
+    #[test]
+    fn freed_block_is_reused_by_same_class() {
+        let mut a = SegregatedFreeList::new(OsHeap);
+
+        let l = Layout::from_size_align(24, 8).unwrap();
+        let p = unsafe { a.alloc(l).unwrap() };
+        unsafe { a.dealloc(p.cast::<u8>(), l) };
+
+        let l2 = Layout::from_size_align(32, 8).unwrap();
+        let q = unsafe { a.alloc(l2).unwrap() };
+        assert_eq!(addr(p), addr(q));
+    }
+
+    #[test]
+    fn blocks_are_aligned_to_class_size() {
+        let mut a = SegregatedFreeList::new(OsHeap);
+
+        for align in [1, 8, 64, 256, 4096] {
+            let l = Layout::from_size_align(8, align).unwrap();
+            let p = unsafe { a.alloc(l).unwrap() };
+            assert_eq!(addr(p) % align, 0);
+        }
+    }
+
+    #[test]
+    fn large_allocation_goes_to_source() {
+        let mut a = SegregatedFreeList::new(OsHeap);
+
+        let l = Layout::from_size_align(MAX_CLASS_SIZE * 4, 16).unwrap();
+        let p1 = unsafe { a.alloc(l).unwrap() };
+        let p2 = unsafe { a.alloc(l).unwrap() };
+        assert_eq!(large_count(&a), 2);
+        assert!(a.chunks.is_none());
+
+        unsafe { p1.cast::<u8>().write_bytes(0xEE, l.size()) };
+        unsafe { a.dealloc(p1.cast::<u8>(), l) };
+        assert_eq!(large_count(&a), 1);
+        unsafe { a.dealloc(p2.cast::<u8>(), l) };
+        assert_eq!(large_count(&a), 0);
+    }
+
+    #[test]
+    fn grow_within_class_is_in_place() {
+        let mut a = SegregatedFreeList::new(OsHeap);
+
+        let old = Layout::from_size_align(40, 8).unwrap();
+        let new = Layout::from_size_align(64, 8).unwrap();
+        let p = unsafe { a.alloc(old).unwrap() }.cast::<u8>();
+
+        let q = unsafe { a.grow(p, old, new).unwrap() };
+        assert_eq!(q.cast::<u8>(), p);
+        assert_eq!(q.len(), 64);
+    }
+}
@@ -0,0 +1,319 @@
+//! アロケータの使用量を数える。
+
+use core::{alloc::Layout, ptr::NonNull};
+
+use crate::allocator::MutAllocator;
+
+/// ヒストグラムのバケットの数
+pub const HISTOGRAM_BUCKETS: usize = usize::BITS as usize + 1;
+
+/// `size` バイトの確保が入るヒストグラムのバケット
+pub fn bucket_of(size: usize) -> usize {
+    if size <= 1 {
+        0
+    } else {
+        (usize::BITS - (size - 1).leading_zeros()) as usize
+    }
+}
+
+/// source から取っているメモリの量を返せるアロケータ。
+pub trait SourceUsage {
+    /// source から取って、まだ返していないチャンクの合計バイト数
+    fn source_bytes(&self) -> usize;
+}
+
+impl<A: SourceUsage + ?Sized> SourceUsage for &mut A {
+    fn source_bytes(&self) -> usize {
+        (**self).source_bytes()
+    }
+}
+
+/// 内側のアロケータへの確保と解放を数える `MutAllocator`。
+///
+/// 生きている確保の合計バイト数とその最大値、確保・解放・再確保の回数、
+/// 確保したサイズの 2 の冪ごとのヒストグラムを記録する。
+/// 数えるのは呼び出し側が要求した `Layout` の大きさで、整数の加算だけなので負荷は小さい。
+///
+/// 内側のアロケータが `SourceUsage` を実装していれば、`snapshot` で
+/// source から取っているバイト数もまとめて取れる。
+pub struct Counting<A: MutAllocator> {
+    inner: A,
+
+    live_bytes: usize,
+    peak_bytes: usize,
+
+    allocs: usize,
+    deallocs: usize,
+    reallocs: usize,
+    failures: usize,
+
+    histogram: [usize; HISTOGRAM_BUCKETS],
+}
+
+/// `Counting` がある時点で記録していた値
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Snapshot {
+    /// 生きている確保の合計バイト数
+    pub live_bytes: usize,
+    /// `live_bytes` の最大値
+    pub peak_bytes: usize,
+    /// source から取っているバイト数
+    pub source_bytes: usize,
+
+    /// 成功した確保の回数
+    pub allocs: usize,
+    /// 解放の回数
+    pub deallocs: usize,
+    /// 成功した `grow` / `shrink` の回数
+    pub reallocs: usize,
+    /// 失敗した確保・再確保の回数
+    pub failures: usize,
+
+    /// 確保したサイズのヒストグラム。
+    /// `histogram[k]` は大きさが `(2^(k-1), 2^k]` の確保の回数（`histogram[0]` は大きさ 0 と 1）。
+    pub histogram: [usize; HISTOGRAM_BUCKETS],
+}
+
+impl<A: MutAllocator> Counting<A> {
+    pub const fn new(inner: A) -> Self {
+        Self {
+            inner,
+            live_bytes: 0,
+            peak_bytes: 0,
+            allocs: 0,
+            deallocs: 0,
+            reallocs: 0,
+            failures: 0,
+            histogram: [0; HISTOGRAM_BUCKETS],
+        }
+    }
+
+    /// 内側のアロケータ
+    pub fn inner(&self) -> &A {
+        &self.inner
+    }
+
+    /// 内側のアロケータ
+    pub fn inner_mut(&mut self) -> &mut A {
+        &mut self.inner
+    }
+
+    /// 生きている確保の合計バイト数
+    pub fn live_bytes(&self) -> usize {
+        self.live_bytes
+    }
+
+    /// `live_bytes` の最大値
+    pub fn peak_bytes(&self) -> usize {
+        self.peak_bytes
+    }
+
+    /// `peak_bytes` を今の `live_bytes` に戻す。
+    pub fn reset_peak(&mut self) {
+        self.peak_bytes = self.live_bytes;
+    }
+
+    /// いまの値をまとめて返す。
+    pub fn snapshot(&self) -> Snapshot
+    where
+        A: SourceUsage,
+    {
+        Snapshot {
+            live_bytes: self.live_bytes,
+            peak_bytes: self.peak_bytes,
+            source_bytes: self.inner.source_bytes(),
+            allocs: self.allocs,
+            deallocs: self.deallocs,
+            reallocs: self.reallocs,
+            failures: self.failures,
+            histogram: self.histogram,
+        }
+    }
+
+    fn add_live(&mut self, size: usize) {
+        self.live_bytes += size;
+        if self.live_bytes > self.peak_bytes {
+            self.peak_bytes = self.live_bytes;
+        }
+    }
+
+    fn record(
+        &mut self,
+        result: Option<NonNull<[u8]>>,
+    ) -> Option<NonNull<[u8]>> {
+        if result.is_none() {
+            self.failures += 1;
+        }
+        result
+    }
+}
+
+impl<A: MutAllocator> MutAllocator for Counting<A> {
+    unsafe fn alloc(&mut self, layout: Layout) -> Option<NonNull<[u8]>> {
+        let result = unsafe { self.inner.alloc(layout) };
+        if result.is_some() {
+            self.allocs += 1;
+            self.histogram[bucket_of(layout.size())] += 1;
+            self.add_live(layout.size());
+        }
+        self.record(result)
+    }
+
+    unsafe fn dealloc(&mut self, ptr: NonNull<u8>, layout: Layout) {
+        unsafe { self.inner.dealloc(ptr, layout) };
+        self.deallocs += 1;
+        self.live_bytes -= layout.size();
+    }
+
+    unsafe fn grow(
+        &mut self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Option<NonNull<[u8]>> {
+        let result = unsafe { self.inner.grow(ptr, old_layout, new_layout) };
+        if result.is_some() {
+            self.reallocs += 1;
+            self.add_live(new_layout.size() - old_layout.size());
+        }
+        self.record(result)
+    }
+
+    unsafe fn grow_zeroed(
+        &mut self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Option<NonNull<[u8]>> {
+        let result =
+            unsafe { self.inner.grow_zeroed(ptr, old_layout, new_layout) };
+        if result.is_some() {
+            self.reallocs += 1;
+            self.add_live(new_layout.size() - old_layout.size());
+        }
+        self.record(result)
+    }
+
+    unsafe fn shrink(
+        &mut self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Option<NonNull<[u8]>> {
+        let result = unsafe { self.inner.shrink(ptr, old_layout, new_layout) };
+        if result.is_some() {
+            self.reallocs += 1;
+            self.live_bytes -= old_layout.size() - new_layout.size();
+        }
+        self.record(result)
+    }
+}
+
+impl<A: MutAllocator + SourceUsage> SourceUsage for Counting<A> {
+    fn source_bytes(&self) -> usize {
+        self.inner.source_bytes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        allocator::{
+            buddy::BuddyAllocator, bump::BumpAllocator, free_list::FreeList,
+            pool::Pool, segregated::SegregatedFreeList, tlsf::Tlsf,
+        },
+        mutex::Locked,
+        source::os_heap::OsHeap,
+    };
+    use std::vec::Vec;
+
+    #[test]
+    fn histogram_buckets() {
+        assert_eq!(bucket_of(0), 0);
+        assert_eq!(bucket_of(1), 0);
+        assert_eq!(bucket_of(2), 1);
+        assert_eq!(bucket_of(3), 2);
+        assert_eq!(bucket_of(4), 2);
+        assert_eq!(bucket_of(5), 3);
+        assert_eq!(bucket_of(4096), 12);
+        assert_eq!(bucket_of(usize::MAX), HISTOGRAM_BUCKETS - 1);
+    }
+
+    #[test]
+    fn tracks_live_and_peak_bytes() {
+        let mut a = Counting::new(FreeList::new(OsHeap));
+        let l = Layout::from_size_align(100, 8).unwrap();
+
+        let p = unsafe { a.alloc(l).unwrap() };
+        let q = unsafe { a.alloc(l).unwrap() };
+        assert_eq!(a.live_bytes(), 200);
+
+        let big = Layout::from_size_align(300, 8).unwrap();
+        let q = unsafe { a.grow(q.cast(), l, big).unwrap() };
+        assert_eq!(a.live_bytes(), 400);
+
+        unsafe {
+            a.dealloc(p.cast(), l);
+            a.dealloc(q.cast(), big);
+        }
+
+        let s = a.snapshot();
+        assert_eq!(s.live_bytes, 0);
+        assert_eq!(s.peak_bytes, 400);
+        assert_eq!((s.allocs, s.deallocs, s.reallocs), (2, 2, 1));
+        assert_eq!(s.histogram[7], 2);
+        assert_eq!(s.histogram.iter().sum::<usize>(), 2);
+        assert!(s.source_bytes >= 4096);
+
+        a.reset_peak();
+        assert_eq!(a.peak_bytes(), 0);
+    }
+
+    #[test]
+    fn counts_failures() {
+        let mut a = Counting::new(Pool::new(OsHeap, Layout::new::<u64>()));
+        assert!(unsafe { a.alloc(Layout::new::<[u64; 2]>()) }.is_none());
+
+        let s = a.snapshot();
+        assert_eq!((s.allocs, s.failures, s.live_bytes), (0, 1, 0));
+    }
+
+    #[test]
+    fn source_bytes_follow_the_chunks() {
+        fn check<A: MutAllocator + SourceUsage>(mut a: A) {
+            assert_eq!(a.source_bytes(), 0);
+
+            let l = Layout::from_size_align(256, 8).unwrap();
+            let ptrs: Vec<_> =
+                (0..64).map(|_| unsafe { a.alloc(l).unwrap() }).collect();
+            assert!(a.source_bytes() >= 64 * 256);
+
+            for p in ptrs {
+                unsafe { a.dealloc(p.cast(), l) };
+            }
+        }
+
+        check(BumpAllocator::new(OsHeap));
+        check(FreeList::new(OsHeap));
+        check(SegregatedFreeList::new(OsHeap));
+        check(Pool::new(OsHeap, Layout::new::<[u8; 256]>()));
+        check(BuddyAllocator::new(OsHeap, 4, 16));
+        check(Tlsf::new(OsHeap));
+    }
+
+    #[test]
+    fn works_behind_locked() {
+        let a = Locked::new(Counting::new(BumpAllocator::new(OsHeap)));
+
+        let mut v: Vec<u32, _> = Vec::new_in(&a);
+        v.extend(0..1000);
+        drop(v);
+
+        let s = a.with_lock(|c| c.snapshot());
+        assert_eq!(s.live_bytes, 0);
+        assert!(s.peak_bytes >= 4000);
+        assert!(s.allocs >= 1);
+        assert!(s.source_bytes >= 4000);
+    }
+}
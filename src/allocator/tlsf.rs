@@ -0,0 +1,819 @@
+use core::{
+    alloc::Layout,
+    mem,
+    ptr::{self, NonNull},
+};
+
+use crate::{
+    align::align_up,
+    allocator::{MutAllocator, stats::SourceUsage},
+    source::MemorySource,
+};
+
+/// ブロックサイズの単位（2^3 = 8 バイト）
+const ALIGN_SIZE_LOG2: u32 = 3;
+const ALIGN_SIZE: usize = 1 << ALIGN_SIZE_LOG2;
+
+/// 第 2 レベルの分割数（2^5 = 32）
+const SL_INDEX_COUNT_LOG2: u32 = 5;
+const SL_INDEX_COUNT: usize = 1 << SL_INDEX_COUNT_LOG2;
+
+/// 第 1 レベルの最大の添字。これを超えるブロックは扱わない。
+const FL_INDEX_MAX: u32 = if usize::BITS == 64 { 40 } else { 30 };
+const FL_INDEX_SHIFT: u32 = SL_INDEX_COUNT_LOG2 + ALIGN_SIZE_LOG2;
+const FL_INDEX_COUNT: usize = (FL_INDEX_MAX - FL_INDEX_SHIFT + 1) as usize;
+
+/// これ未満のブロックは第 1 レベル 0 にまとめ、線形に分割する
+const SMALL_BLOCK_SIZE: usize = 1 << FL_INDEX_SHIFT;
+
+/// 使用中ブロックのオーバーヘッド（サイズのフィールド）
+const BLOCK_OVERHEAD: usize = mem::size_of::<usize>();
+/// ブロックの先頭からユーザ領域までのオフセット
+const BLOCK_START_OFFSET: usize = mem::offset_of!(BlockHeader, next_free);
+/// 空きブロックとして free list のリンクと次のブロックの `prev_phys` を置ける最小サイズ
+const BLOCK_SIZE_MIN: usize = mem::size_of::<BlockHeader>()
+    - mem::size_of::<Option<NonNull<BlockHeader>>>();
+const BLOCK_SIZE_MAX: usize = 1 << FL_INDEX_MAX;
+
+/// `size` の下位ビットに持つフラグ
+const BLOCK_FREE_BIT: usize = 1 << 0;
+const BLOCK_PREV_FREE_BIT: usize = 1 << 1;
+
+/// source に要求するプールの最小サイズ
+const DEFAULT_POOL_SIZE: usize = 64 * 1024;
+
+/// TLSF (Two-Level Segregated Fit) アロケータ。
+///
+/// 空きブロックを「2 の冪の区間（第 1 レベル）× その区間の等分（第 2 レベル）」の
+/// free list に分け、どのリストが空でないかを 2 段のビットマップで持つ。
+/// 割当も解放もビット演算とリストの付け替えだけなので、最悪でも O(1)。
+///
+/// 各ブロックの先頭にはサイズとフラグ（自分が空きか、直前のブロックが空きか）を置き、
+/// 空きブロックは次のブロックの先頭（`prev_phys`）に自分へのポインタを書く（境界タグ）。
+/// 解放時は前後の空きブロックとすぐに結合する。
+///
+/// プールは `add_pool` で実行時に足せるほか、空きが足りなければ source から自動で追加する。
+pub struct Tlsf<S: MemorySource> {
+    source: S,
+
+    fl_bitmap: usize,
+    sl_bitmap: [u32; FL_INDEX_COUNT],
+    blocks: [[Option<NonNull<BlockHeader>>; SL_INDEX_COUNT]; FL_INDEX_COUNT],
+
+    pools: Option<NonNull<PoolHeader>>,
+}
+
+/// ブロックのヘッダ。
+///
+/// - `prev_phys` は直前のブロックのユーザ領域の末尾と重なっていて、直前のブロックが空きのときだけ有効。
+/// - `size` はユーザ領域の大きさ。下位 2 ビットはフラグ。
+/// - `next_free`/`prev_free` は自分が空きのときだけ有効（ユーザ領域と重なる）。
+#[repr(C)]
+struct BlockHeader {
+    prev_phys: Option<NonNull<BlockHeader>>,
+    size: usize,
+    next_free: Option<NonNull<BlockHeader>>,
+    prev_free: Option<NonNull<BlockHeader>>,
+}
+
+/// プールの先頭に置くヘッダ
+struct PoolHeader {
+    next: Option<NonNull<PoolHeader>>,
+    ptr: NonNull<u8>,
+    layout: Layout,
+}
+
+unsafe impl<S: MemorySource + Send> Send for Tlsf<S> {}
+
+/// 最上位ビットの位置
+#[inline]
+fn fls(x: usize) -> u32 {
+    debug_assert!(x != 0);
+    usize::BITS - 1 - x.leading_zeros()
+}
+
+/// `size` のブロックが入る (第 1 レベル, 第 2 レベル)
+#[inline]
+fn mapping_insert(size: usize) -> (usize, usize) {
+    if size < SMALL_BLOCK_SIZE {
+        (0, size / (SMALL_BLOCK_SIZE / SL_INDEX_COUNT))
+    } else {
+        let fl = fls(size);
+        let sl = (size >> (fl - SL_INDEX_COUNT_LOG2)) ^ SL_INDEX_COUNT;
+        ((fl - (FL_INDEX_SHIFT - 1)) as usize, sl)
+    }
+}
+
+/// `size` 以上が必ず入るリストの (第 1 レベル, 第 2 レベル)。
+/// 区間の途中に入らないよう、次の区間の先頭まで切り上げてから求める。
+#[inline]
+fn mapping_search(size: usize) -> (usize, usize) {
+    let size = if size >= SMALL_BLOCK_SIZE {
+        size + (1 << (fls(size) - SL_INDEX_COUNT_LOG2)) - 1
+    } else {
+        size
+    };
+    mapping_insert(size)
+}
+
+/// ユーザの要求サイズを、ブロックのサイズに揃える
+#[inline]
+fn adjust_size(size: usize) -> Option<usize> {
+    let size = align_up(size.max(BLOCK_SIZE_MIN), ALIGN_SIZE);
+    (size < BLOCK_SIZE_MAX).then_some(size)
+}
+
+// --- ブロックの操作 ---
+
+#[inline]
+unsafe fn block_size(block: NonNull<BlockHeader>) -> usize {
+    unsafe { block.as_ref().size & !(BLOCK_FREE_BIT | BLOCK_PREV_FREE_BIT) }
+}
+
+#[inline]
+unsafe fn set_block_size(mut block: NonNull<BlockHeader>, size: usize) {
+    unsafe {
+        let b = block.as_mut();
+        b.size = size | (b.size & (BLOCK_FREE_BIT | BLOCK_PREV_FREE_BIT));
+    }
+}
+
+#[inline]
+unsafe fn set_flag(mut block: NonNull<BlockHeader>, bit: usize, on: bool) {
+    unsafe {
+        let b = block.as_mut();
+        if on {
+            b.size |= bit;
+        } else {
+            b.size &= !bit;
+        }
+    }
+}
+
+#[inline]
+unsafe fn is_free(block: NonNull<BlockHeader>) -> bool {
+    unsafe { block.as_ref().size & BLOCK_FREE_BIT != 0 }
+}
+
+#[inline]
+unsafe fn is_prev_free(block: NonNull<BlockHeader>) -> bool {
+    unsafe { block.as_ref().size & BLOCK_PREV_FREE_BIT != 0 }
+}
+
+#[inline]
+unsafe fn block_to_ptr(block: NonNull<BlockHeader>) -> NonNull<u8> {
+    unsafe { block.cast::<u8>().add(BLOCK_START_OFFSET) }
+}
+
+#[inline]
+unsafe fn block_from_ptr(ptr: NonNull<u8>) -> NonNull<BlockHeader> {
+    unsafe { ptr.sub(BLOCK_START_OFFSET) }.cast::<BlockHeader>()
+}
+
+/// 物理的に次のブロック
+#[inline]
+unsafe fn next_phys(block: NonNull<BlockHeader>) -> NonNull<BlockHeader> {
+    unsafe {
+        block_to_ptr(block)
+            .add(block_size(block) - BLOCK_OVERHEAD)
+            .cast::<BlockHeader>()
+    }
+}
+
+/// 次のブロックの `prev_phys` に自分を書き、次のブロックを返す
+#[inline]
+unsafe fn link_next(block: NonNull<BlockHeader>) -> NonNull<BlockHeader> {
+    unsafe {
+        let mut next = next_phys(block);
+        next.as_mut().prev_phys = Some(block);
+        next
+    }
+}
+
+unsafe fn mark_as_free(block: NonNull<BlockHeader>) {
+    unsafe {
+        let next = link_next(block);
+        set_flag(next, BLOCK_PREV_FREE_BIT, true);
+        set_flag(block, BLOCK_FREE_BIT, true);
+    }
+}
+
+unsafe fn mark_as_used(block: NonNull<BlockHeader>) {
+    unsafe {
+        let next = next_phys(block);
+        set_flag(next, BLOCK_PREV_FREE_BIT, false);
+        set_flag(block, BLOCK_FREE_BIT, false);
+    }
+}
+
+/// `block` を `size` で切り、後ろの残りを空きブロックとして返す
+unsafe fn split(
+    block: NonNull<BlockHeader>,
+    size: usize,
+) -> NonNull<BlockHeader> {
+    unsafe {
+        let remaining = block_to_ptr(block)
+            .add(size - BLOCK_OVERHEAD)
+            .cast::<BlockHeader>();
+        let remain_size = block_size(block) - (size + BLOCK_OVERHEAD);
+        debug_assert!(remain_size >= BLOCK_SIZE_MIN);
+
+        remaining
+            .cast::<u8>()
+            .add(mem::offset_of!(BlockHeader, size))
+            .cast::<usize>()
+            .write(remain_size);
+        set_block_size(block, size);
+        mark_as_free(remaining);
+        remaining
+    }
+}
+
+/// 物理的に隣接する `block` を `prev` に吸収する
+unsafe fn absorb(
+    mut prev: NonNull<BlockHeader>,
+    block: NonNull<BlockHeader>,
+) -> NonNull<BlockHeader> {
+    unsafe {
+        prev.as_mut().size += block_size(block) + BLOCK_OVERHEAD;
+        link_next(prev);
+    }
+    prev
+}
+
+#[inline]
+unsafe fn can_split(block: NonNull<BlockHeader>, size: usize) -> bool {
+    unsafe { block_size(block) >= mem::size_of::<BlockHeader>() + size }
+}
+
+impl<S: MemorySource> Tlsf<S> {
+    pub const fn new(source: S) -> Self {
+        Self {
+            source,
+            fl_bitmap: 0,
+            sl_bitmap: [0; FL_INDEX_COUNT],
+            blocks: [[None; SL_INDEX_COUNT]; FL_INDEX_COUNT],
+            pools: None,
+        }
+    }
+
+    /// source から少なくとも `min_size` バイトを割り当てられるプールを取って追加する。
+    pub fn add_pool(&mut self, min_size: usize) -> Option<()> {
+        let (header, offset) = Layout::new::<PoolHeader>()
+            .extend(Layout::from_size_align(ALIGN_SIZE, ALIGN_SIZE).ok()?)
+            .ok()?;
+        // 先頭ブロックの prev_phys とサイズ、末尾の番兵の分
+        let overhead = offset + BLOCK_START_OFFSET + BLOCK_START_OFFSET;
+        let size = min_size
+            .checked_add(overhead)?
+            .max(DEFAULT_POOL_SIZE)
+            .max(header.size());
+        let request = Layout::from_size_align(size, header.align()).ok()?;
+
+        let chunk = unsafe { self.source.request_chunk(request) }?;
+        let actual_layout =
+            Layout::from_size_align(chunk.len(), request.align()).ok()?;
+
+        let chunk_ptr = chunk.cast::<u8>();
+        let node_ptr = chunk_ptr.cast::<PoolHeader>();
+        unsafe {
+            node_ptr.write(PoolHeader {
+                next: self.pools,
+                ptr: chunk_ptr,
+                layout: actual_layout,
+            })
+        };
+        self.pools = Some(node_ptr);
+
+        let region = unsafe { chunk_ptr.add(offset) };
+        let region_len = (chunk.len() - offset) & !(ALIGN_SIZE - 1);
+        unsafe { self.add_region(region, region_len) };
+        Some(())
+    }
+
+    /// `[start, start + len)` をプールとして登録する。
+    ///
+    /// 先頭に 1 つの大きな空きブロック、末尾にサイズ 0 の使用中の番兵ブロックを置く。
+    unsafe fn add_region(&mut self, start: NonNull<u8>, len: usize) {
+        debug_assert!(start.as_ptr().align_offset(ALIGN_SIZE) == 0);
+
+        let size =
+            (len - BLOCK_START_OFFSET - BLOCK_START_OFFSET) & !(ALIGN_SIZE - 1);
+        let size = size.min(BLOCK_SIZE_MAX - ALIGN_SIZE);
+        debug_assert!(size >= BLOCK_SIZE_MIN);
+
+        let mut block = start.cast::<BlockHeader>();
+        unsafe {
+            block.as_mut().size = size | BLOCK_FREE_BIT;
+            // 先頭ブロックの前には何もないので、直前は使用中扱い
+            set_flag(block, BLOCK_PREV_FREE_BIT, false);
+
+            // 番兵
+            let sentinel = link_next(block);
+            sentinel
+                .cast::<u8>()
+                .add(mem::offset_of!(BlockHeader, size))
+                .cast::<usize>()
+                .write(BLOCK_PREV_FREE_BIT);
+
+            self.insert_free_block(block);
+        }
+    }
+
+    unsafe fn insert_free_block(&mut self, mut block: NonNull<BlockHeader>) {
+        let (fl, sl) = mapping_insert(unsafe { block_size(block) });
+        let current = self.blocks[fl][sl];
+
+        unsafe {
+            let b = block.as_mut();
+            b.next_free = current;
+            b.prev_free = None;
+            if let Some(mut c) = current {
+                c.as_mut().prev_free = Some(block);
+            }
+        }
+
+        self.blocks[fl][sl] = Some(block);
+        self.fl_bitmap |= 1 << fl;
+        self.sl_bitmap[fl] |= 1 << sl;
+    }
+
+    unsafe fn remove_free_block(
+        &mut self,
+        block: NonNull<BlockHeader>,
+        fl: usize,
+        sl: usize,
+    ) {
+        let (prev, next) = unsafe {
+            let b = block.as_ref();
+            (b.prev_free, b.next_free)
+        };
+
+        unsafe {
+            if let Some(mut p) = prev {
+                p.as_mut().next_free = next;
+            }
+            if let Some(mut n) = next {
+                n.as_mut().prev_free = prev;
+            }
+        }
+
+        if self.blocks[fl][sl] == Some(block) {
+            self.blocks[fl][sl] = next;
+            if next.is_none() {
+                self.sl_bitmap[fl] &= !(1 << sl);
+                if self.sl_bitmap[fl] == 0 {
+                    self.fl_bitmap &= !(1 << fl);
+                }
+            }
+        }
+    }
+
+    unsafe fn block_remove(&mut self, block: NonNull<BlockHeader>) {
+        let (fl, sl) = mapping_insert(unsafe { block_size(block) });
+        unsafe { self.remove_free_block(block, fl, sl) };
+    }
+
+    /// 直前のブロックが空いていれば結合する
+    unsafe fn merge_prev(
+        &mut self,
+        block: NonNull<BlockHeader>,
+    ) -> NonNull<BlockHeader> {
+        unsafe {
+            if !is_prev_free(block) {
+                return block;
+            }
+
+            let prev = block.as_ref().prev_phys.unwrap_unchecked();
+            debug_assert!(is_free(prev));
+            self.block_remove(prev);
+            absorb(prev, block)
+        }
+    }
+
+    /// 直後のブロックが空いていれば結合する
+    unsafe fn merge_next(
+        &mut self,
+        block: NonNull<BlockHeader>,
+    ) -> NonNull<BlockHeader> {
+        unsafe {
+            let next = next_phys(block);
+            if !is_free(next) {
+                return block;
+            }
+
+            self.block_remove(next);
+            absorb(block, next)
+        }
+    }
+
+    /// 空きブロックの後ろの余りを切り離して free list に戻す
+    unsafe fn trim_free(&mut self, block: NonNull<BlockHeader>, size: usize) {
+        unsafe {
+            debug_assert!(is_free(block));
+            if can_split(block, size) {
+                let remaining = split(block, size);
+                link_next(block);
+                set_flag(remaining, BLOCK_PREV_FREE_BIT, true);
+                self.insert_free_block(remaining);
+            }
+        }
+    }
+
+    /// 使用中ブロックの後ろの余りを切り離し、後ろの空きと結合して free list に戻す
+    unsafe fn trim_used(&mut self, block: NonNull<BlockHeader>, size: usize) {
+        unsafe {
+            debug_assert!(!is_free(block));
+            if can_split(block, size) {
+                let remaining = split(block, size);
+                set_flag(remaining, BLOCK_PREV_FREE_BIT, false);
+                let remaining = self.merge_next(remaining);
+                self.insert_free_block(remaining);
+            }
+        }
+    }
+
+    /// 空きブロックの前の `size` バイトを切り離して free list に戻し、後ろを返す
+    unsafe fn trim_free_leading(
+        &mut self,
+        block: NonNull<BlockHeader>,
+        size: usize,
+    ) -> NonNull<BlockHeader> {
+        unsafe {
+            if !can_split(block, size) {
+                return block;
+            }
+
+            let remaining = split(block, size - BLOCK_OVERHEAD);
+            set_flag(remaining, BLOCK_PREV_FREE_BIT, true);
+            link_next(block);
+            self.insert_free_block(block);
+            remaining
+        }
+    }
+
+    /// `size` 以上の空きブロックを 2 段のビットマップから探して free list から外す
+    fn locate_free(&mut self, size: usize) -> Option<NonNull<BlockHeader>> {
+        let (mut fl, sl) = mapping_search(size);
+        if fl >= FL_INDEX_COUNT {
+            return None;
+        }
+
+        let mut sl_map = self.sl_bitmap[fl] & (!0u32 << sl);
+        if sl_map == 0 {
+            let fl_map =
+                self.fl_bitmap & (!0usize).checked_shl(fl as u32 + 1)?;
+            if fl_map == 0 {
+                return None;
+            }
+            fl = fl_map.trailing_zeros() as usize;
+            sl_map = self.sl_bitmap[fl];
+        }
+        let sl = sl_map.trailing_zeros() as usize;
+
+        let block = self.blocks[fl][sl]?;
+        debug_assert!(unsafe { block_size(block) } >= size);
+        unsafe { self.remove_free_block(block, fl, sl) };
+        Some(block)
+    }
+
+    /// 空きが見つからなければプールを足して、もう一度探す
+    fn locate_or_grow(&mut self, size: usize) -> Option<NonNull<BlockHeader>> {
+        if let Some(block) = self.locate_free(size) {
+            return Some(block);
+        }
+
+        // mapping_search の切り上げ分を見込んで、倍の大きさのプールを取る
+        self.add_pool(size.checked_mul(2)?)?;
+        self.locate_free(size)
+    }
+}
+
+impl<S: MemorySource> MutAllocator for Tlsf<S> {
+    unsafe fn alloc(&mut self, layout: Layout) -> Option<NonNull<[u8]>> {
+        // ZST は適当な non-null を返す
+        if layout.size() == 0 {
+            let p = ptr::without_provenance_mut::<u8>(layout.align());
+            let nn = unsafe { NonNull::new_unchecked(p) };
+            return Some(NonNull::slice_from_raw_parts(nn, 0));
+        }
+
+        let size = adjust_size(layout.size())?;
+        let align = layout.align();
+
+        if align <= ALIGN_SIZE {
+            let block = self.locate_or_grow(size)?;
+            unsafe {
+                self.trim_free(block, size);
+                mark_as_used(block);
+            }
+            let ptr = unsafe { block_to_ptr(block) };
+            return Some(NonNull::slice_from_raw_parts(ptr, layout.size()));
+        }
+
+        // アラインのずれを前に空きブロックとして切り離せるよう、余分に探す
+        let gap_minimum = mem::size_of::<BlockHeader>();
+        let size_with_gap =
+            adjust_size(size.checked_add(align)?.checked_add(gap_minimum)?)?;
+        let mut block = self.locate_or_grow(size_with_gap)?;
+
+        let ptr = unsafe { block_to_ptr(block) };
+        let mut aligned = align_up(ptr.as_ptr().addr(), align);
+        let mut gap = aligned - ptr.as_ptr().addr();
+
+        // ずれが小さすぎて空きブロックにできなければ、次のアライン位置までずらす
+        if gap != 0 && gap < gap_minimum {
+            let offset = (gap_minimum - gap).max(align);
+            aligned = align_up(aligned + offset, align);
+            gap = aligned - ptr.as_ptr().addr();
+        }
+
+        if gap != 0 {
+            block = unsafe { self.trim_free_leading(block, gap) };
+        }
+
+        unsafe {
+            self.trim_free(block, size);
+            mark_as_used(block);
+        }
+        let ptr = unsafe { block_to_ptr(block) };
+        debug_assert!(ptr.as_ptr().align_offset(align) == 0);
+
+        Some(NonNull::slice_from_raw_parts(ptr, layout.size()))
+    }
+
+    unsafe fn dealloc(&mut self, ptr: NonNull<u8>, layout: Layout) {
+        if layout.size() == 0 {
+            return;
+        }
+
+        unsafe {
+            let block = block_from_ptr(ptr);
+            debug_assert!(!is_free(block));
+
+            mark_as_free(block);
+            let block = self.merge_prev(block);
+            let block = self.merge_next(block);
+            self.insert_free_block(block);
+        }
+    }
+
+    unsafe fn grow(
+        &mut self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Option<NonNull<[u8]>> {
+        if old_layout.size() != 0
+            && ptr.as_ptr().align_offset(new_layout.align()) == 0
+            && let Some(size) = adjust_size(new_layout.size())
+        {
+            unsafe {
+                let block = block_from_ptr(ptr);
+                let current = block_size(block);
+                let next = next_phys(block);
+                let combined = current + block_size(next) + BLOCK_OVERHEAD;
+
+                if size <= current {
+                    return Some(NonNull::slice_from_raw_parts(
+                        ptr,
+                        new_layout.size(),
+                    ));
+                }
+
+                // 後ろの空きブロックを吸収して伸ばす
+                if is_free(next) && size <= combined {
+                    self.merge_next(block);
+                    mark_as_used(block);
+                    self.trim_used(block, size);
+                    return Some(NonNull::slice_from_raw_parts(
+                        ptr,
+                        new_layout.size(),
+                    ));
+                }
+            }
+        }
+
+        let new_ptr = unsafe { self.alloc(new_layout)? };
+        unsafe {
+            ptr::copy_nonoverlapping(
+                ptr.as_ptr(),
+                new_ptr.cast::<u8>().as_ptr(),
+                old_layout.size(),
+            );
+            self.dealloc(ptr, old_layout);
+        }
+
+        Some(new_ptr)
+    }
+
+    unsafe fn shrink(
+        &mut self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Option<NonNull<[u8]>> {
+        if new_layout.size() != 0
+            && ptr.as_ptr().align_offset(new_layout.align()) == 0
+            && let Some(size) = adjust_size(new_layout.size())
+        {
+            unsafe { self.trim_used(block_from_ptr(ptr), size) };
+            return Some(NonNull::slice_from_raw_parts(ptr, new_layout.size()));
+        }
+
+        let new_ptr = unsafe { self.alloc(new_layout)? };
+        unsafe {
+            ptr::copy_nonoverlapping(
+                ptr.as_ptr(),
+                new_ptr.cast::<u8>().as_ptr(),
+                new_layout.size(),
+            );
+            self.dealloc(ptr, old_layout);
+        }
+
+        Some(new_ptr)
+    }
+}
+
+impl<S: MemorySource> SourceUsage for Tlsf<S> {
+    fn source_bytes(&self) -> usize {
+        let mut total = 0;
+        let mut current = self.pools;
+
+        while let Some(node_ptr) = current {
+            let node = unsafe { node_ptr.as_ref() };
+            total += node.layout.size();
+            current = node.next;
+        }
+
+        total
+    }
+}
+
+impl<S: MemorySource> Drop for Tlsf<S> {
+    fn drop(&mut self) {
+        let mut current = self.pools;
+
+        while let Some(node_ptr) = current {
+            unsafe {
+                let node = node_ptr.read();
+                current = node.next;
+
+                self.source.release_chunk(node.ptr, node.layout);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::source::{os_heap::OsHeap, static_buff::StaticBuffer};
+    use std::vec::Vec;
+
+    fn addr(p: NonNull<[u8]>) -> usize {
+        p.cast::<u8>().as_ptr().addr()
+    }
+
+    /// 空きブロックの (数, 合計サイズ)
+    fn free_blocks<S: MemorySource>(a: &Tlsf<S>) -> (usize, usize) {
+        let mut count = 0;
+        let mut total = 0;
+        for fl in 0..FL_INDEX_COUNT {
+            for sl in 0..SL_INDEX_COUNT {
+                let mut cur = a.blocks[fl][sl];
+                while let Some(b) = cur {
+                    count += 1;
+                    total += unsafe { block_size(b) };
+                    cur = unsafe { b.as_ref().next_free };
+                }
+            }
+        }
+        (count, total)
+    }
+
+    #[test]
+    fn mapping_matches_size_ranges() {
+        assert_eq!(mapping_insert(8), (0, 1));
+        assert_eq!(
+            mapping_insert(SMALL_BLOCK_SIZE - 8),
+            (0, SL_INDEX_COUNT - 1)
+        );
+        assert_eq!(mapping_insert(SMALL_BLOCK_SIZE), (1, 0));
+        assert_eq!(
+            mapping_insert(SMALL_BLOCK_SIZE * 2 - 1),
+            (1, SL_INDEX_COUNT - 1)
+        );
+
+        // search は区間の途中にあるサイズを次の区間に切り上げる
+        assert_eq!(mapping_search(SMALL_BLOCK_SIZE), (1, 0));
+        assert_eq!(mapping_search(SMALL_BLOCK_SIZE + 1), (1, 1));
+    }
+
+    #[test]
+    fn free_coalesces_back_into_one_block() {
+        let mut a = Tlsf::new(OsHeap);
+
+        let layouts: Vec<_> = (1..50)
+            .map(|i| Layout::from_size_align(i * 24, 8).unwrap())
+            .collect();
+        let ptrs: Vec<_> = layouts
+            .iter()
+            .map(|&l| unsafe { a.alloc(l).unwrap() })
+            .collect();
+
+        let (_, initial_total) = {
+            let mut b = Tlsf::new(OsHeap);
+            let l = Layout::from_size_align(8, 8).unwrap();
+            let p = unsafe { b.alloc(l).unwrap() };
+            unsafe { b.dealloc(p.cast::<u8>(), l) };
+            free_blocks(&b)
+        };
+
+        // 奇数番目、偶数番目の順に解放する
+        for (i, (p, l)) in ptrs.iter().zip(&layouts).enumerate() {
+            if i % 2 == 1 {
+                unsafe { a.dealloc(p.cast::<u8>(), *l) };
+            }
+        }
+        for (i, (p, l)) in ptrs.iter().zip(&layouts).enumerate() {
+            if i % 2 == 0 {
+                unsafe { a.dealloc(p.cast::<u8>(), *l) };
+            }
+        }
+
+        assert_eq!(free_blocks(&a), (1, initial_total));
+    }
+
+    #[test]
+    fn respects_large_alignment() {
+        let mut a = Tlsf::new(OsHeap);
+
+        let mut ptrs = Vec::new();
+        for align in [16, 64, 256, 4096] {
+            let l = Layout::from_size_align(40, align).unwrap();
+            let p = unsafe { a.alloc(l).unwrap() };
+            assert_eq!(addr(p) % align, 0);
+            unsafe { p.cast::<u8>().write_bytes(0xAA, 40) };
+            ptrs.push((p, l));
+        }
+
+        for (p, l) in ptrs {
+            unsafe { a.dealloc(p.cast::<u8>(), l) };
+        }
+        assert_eq!(free_blocks(&a).0, 1);
+    }
+
+    #[test]
+    fn adds_pools_on_demand() {
+        let mut a = Tlsf::new(OsHeap);
+
+        let l = Layout::from_size_align(DEFAULT_POOL_SIZE / 2, 8).unwrap();
+        let ptrs: Vec<_> =
+            (0..4).map(|_| unsafe { a.alloc(l).unwrap() }).collect();
+
+        let mut pools = 0;
+        let mut cur = a.pools;
+        while let Some(p) = cur {
+            pools += 1;
+            cur = unsafe { p.as_ref().next };
+        }
+        assert!(pools >= 2);
+
+        for p in ptrs {
+            unsafe { a.dealloc(p.cast::<u8>(), l) };
+        }
+        assert_eq!(free_blocks(&a).0, pools);
+    }
+
+    #[test]
+    fn grow_in_place_absorbs_next_free_block() {
+        let mut a = Tlsf::new(OsHeap);
+
+        let old = Layout::from_size_align(64, 8).unwrap();
+        let new = Layout::from_size_align(1024, 8).unwrap();
+        let p = unsafe { a.alloc(old).unwrap() }.cast::<u8>();
+        unsafe { p.write_bytes(0x11, 64) };
+
+        let q = unsafe { a.grow(p, old, new).unwrap() };
+        assert_eq!(q.cast::<u8>(), p);
+        assert_eq!(unsafe { p.add(63).read() }, 0x11);
+
+        let r = unsafe { a.shrink(p, new, old).unwrap() };
+        assert_eq!(r.cast::<u8>(), p);
+        unsafe { a.dealloc(p, old) };
+        assert_eq!(free_blocks(&a).0, 1);
+    }
+
+    #[test]
+    fn works_on_static_buffer() {
+        static BUFFER: StaticBuffer<{ 128 * 1024 }> = StaticBuffer::new();
+        let mut a = Tlsf::new(&BUFFER);
+
+        let l = Layout::from_size_align(100, 8).unwrap();
+        let p = unsafe { a.alloc(l).unwrap() };
+        unsafe { p.cast::<u8>().write_bytes(0x42, 100) };
+        unsafe { a.dealloc(p.cast::<u8>(), l) };
+        assert_eq!(free_blocks(&a).0, 1);
+    }
+}
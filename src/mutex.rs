@@ -1,5 +1,5 @@
 use core::{
-    alloc::{GlobalAlloc, Layout, AllocError, Allocator},
+    alloc::{AllocError, Allocator, GlobalAlloc, Layout},
     ptr::{self, NonNull},
 };
 
@@ -43,6 +43,33 @@ unsafe impl<T: MutAllocator> GlobalAlloc for Locked<T> {
             })
         }
     }
+
+    unsafe fn realloc(
+        &self,
+        ptr: *mut u8,
+        layout: Layout,
+        new_size: usize,
+    ) -> *mut u8 {
+        let Some(ptr) = NonNull::new(ptr) else {
+            return ptr::null_mut();
+        };
+        let new_layout = unsafe {
+            Layout::from_size_align_unchecked(new_size, layout.align())
+        };
+
+        self.with_lock(|value| {
+            let result = if new_size >= layout.size() {
+                unsafe { value.grow(ptr, layout, new_layout) }
+            } else {
+                unsafe { value.shrink(ptr, layout, new_layout) }
+            };
+
+            match result {
+                Some(ptr) => ptr.as_ptr().cast::<u8>(),
+                None => ptr::null_mut(),
+            }
+        })
+    }
 }
 
 impl<T: MemorySource> MemorySource for &Locked<T> {
@@ -67,4 +94,40 @@ unsafe impl<T: MutAllocator> Allocator for &Locked<T> {
     unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
         self.with_lock(|value| unsafe { value.dealloc(ptr, layout) })
     }
+
+    unsafe fn grow(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        self.with_lock(|value| unsafe {
+            value.grow(ptr, old_layout, new_layout)
+        })
+        .ok_or(AllocError)
+    }
+
+    unsafe fn grow_zeroed(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        self.with_lock(|value| unsafe {
+            value.grow_zeroed(ptr, old_layout, new_layout)
+        })
+        .ok_or(AllocError)
+    }
+
+    unsafe fn shrink(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        self.with_lock(|value| unsafe {
+            value.shrink(ptr, old_layout, new_layout)
+        })
+        .ok_or(AllocError)
+    }
 }
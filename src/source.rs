@@ -1,10 +1,28 @@
 use core::{alloc::Layout, ptr::NonNull};
 
+pub mod cache;
+pub mod limit;
 pub mod static_buff;
+pub mod stats;
 
 #[cfg(feature = "std")]
 pub mod os_heap;
 
+#[cfg(all(feature = "std", target_os = "linux"))]
+pub mod huge_page;
+
+#[cfg(feature = "std")]
+pub mod reserved;
+
+#[cfg(feature = "std")]
+pub mod guard;
+
+#[cfg(all(feature = "std", target_os = "linux"))]
+pub mod file;
+
+#[cfg(all(feature = "std", target_os = "linux"))]
+pub mod shm;
+
 /// まとまったメモリ領域（チャンク）を供給/回収する。
 /// アロケータ（例: bump allocator / free-list allocator）が内部で使うために、
 /// 大きめのメモリ領域（チャンク）を確保して提供します。
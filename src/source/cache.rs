@@ -0,0 +1,322 @@
+use core::{alloc::Layout, mem, ptr::NonNull};
+
+use crate::source::MemorySource;
+
+/// サイズクラスの数（大きさの 2 進の桁数ごとに 1 つ）
+const NUM_BUCKETS: usize = usize::BITS as usize;
+
+/// 解放されたチャンクを捨てずに取っておき、次の `request_chunk` に使い回す `MemorySource`。
+///
+/// bump allocator を作っては捨てるような使い方で、毎回 mmap/munmap が走るのを防ぐ。
+/// キャッシュしたチャンクは大きさ（2 の冪ごと）で分けたリストにつなぎ、
+/// 大きさが足りてアラインが同じチャンクがあればそれを返す。
+///
+/// キャッシュするのは最大 `max_chunks` 個、合計 `max_bytes` バイトまで。
+/// 溢れた分と `trim` したチャンクは内側の source に返す。
+pub struct CachingSource<S: MemorySource> {
+    source: S,
+
+    buckets: [Option<NonNull<CachedChunk>>; NUM_BUCKETS],
+
+    max_chunks: usize,
+    max_bytes: usize,
+
+    cached_chunks: usize,
+    cached_bytes: usize,
+}
+
+/// キャッシュしているチャンクの先頭に置くヘッダ
+struct CachedChunk {
+    next: Option<NonNull<CachedChunk>>,
+    /// 内側の source に返すときの `Layout`
+    layout: Layout,
+}
+
+unsafe impl<S: MemorySource + Send> Send for CachingSource<S> {}
+
+impl<S: MemorySource> CachingSource<S> {
+    pub const fn new(source: S, max_chunks: usize, max_bytes: usize) -> Self {
+        Self {
+            source,
+            buckets: [None; NUM_BUCKETS],
+            max_chunks,
+            max_bytes,
+            cached_chunks: 0,
+            cached_bytes: 0,
+        }
+    }
+
+    /// キャッシュしているチャンクの数
+    pub fn cached_chunks(&self) -> usize {
+        self.cached_chunks
+    }
+
+    /// キャッシュしているチャンクの合計バイト数
+    pub fn cached_bytes(&self) -> usize {
+        self.cached_bytes
+    }
+
+    /// 内側の source
+    pub fn source(&mut self) -> &mut S {
+        &mut self.source
+    }
+
+    /// キャッシュしているチャンクをすべて内側の source に返す。
+    pub fn trim(&mut self) {
+        for bucket in 0..NUM_BUCKETS {
+            while let Some(node_ptr) = self.buckets[bucket] {
+                unsafe {
+                    let node = node_ptr.read();
+                    self.buckets[bucket] = node.next;
+                    self.source
+                        .release_chunk(node_ptr.cast::<u8>(), node.layout);
+                }
+            }
+        }
+
+        self.cached_chunks = 0;
+        self.cached_bytes = 0;
+    }
+
+    /// `size` バイトのチャンクを入れるリスト
+    fn bucket_of(size: usize) -> usize {
+        (usize::BITS - 1 - size.leading_zeros()) as usize
+    }
+
+    /// `layout` を満たすキャッシュ済みのチャンクを取り出す。
+    ///
+    /// 大きすぎるチャンクで小さな要求を満たさないよう、`layout` と同じサイズクラスと
+    /// その 1 つ上だけを探す。
+    fn take_cached(&mut self, layout: Layout) -> Option<NonNull<[u8]>> {
+        let first = Self::bucket_of(layout.size().max(1));
+
+        for bucket in first..(first + 2).min(NUM_BUCKETS) {
+            let mut prev: Option<NonNull<CachedChunk>> = None;
+            let mut current = self.buckets[bucket];
+
+            while let Some(node_ptr) = current {
+                let node = unsafe { node_ptr.as_ref() };
+                let next = node.next;
+
+                if node.layout.size() >= layout.size()
+                    && node.layout.align() == layout.align()
+                {
+                    match prev {
+                        Some(mut p) => unsafe { p.as_mut().next = next },
+                        None => self.buckets[bucket] = next,
+                    }
+
+                    let size = node.layout.size();
+                    self.cached_chunks -= 1;
+                    self.cached_bytes -= size;
+
+                    return Some(NonNull::slice_from_raw_parts(
+                        node_ptr.cast::<u8>(),
+                        size,
+                    ));
+                }
+
+                prev = current;
+                current = next;
+            }
+        }
+
+        None
+    }
+
+    /// チャンクをキャッシュに入れる。入らなければ `false`。
+    fn try_cache(&mut self, ptr: NonNull<u8>, layout: Layout) -> bool {
+        let fits = self.cached_chunks < self.max_chunks
+            && self
+                .cached_bytes
+                .checked_add(layout.size())
+                .is_some_and(|total| total <= self.max_bytes);
+        let can_hold_header = layout.size() >= mem::size_of::<CachedChunk>()
+            && ptr
+                .as_ptr()
+                .addr()
+                .is_multiple_of(mem::align_of::<CachedChunk>());
+        if !(fits && can_hold_header) {
+            return false;
+        }
+
+        let bucket = Self::bucket_of(layout.size());
+        let node_ptr = ptr.cast::<CachedChunk>();
+        unsafe {
+            node_ptr.write(CachedChunk {
+                next: self.buckets[bucket],
+                layout,
+            })
+        };
+        self.buckets[bucket] = Some(node_ptr);
+
+        self.cached_chunks += 1;
+        self.cached_bytes += layout.size();
+        true
+    }
+}
+
+impl<S: MemorySource> MemorySource for CachingSource<S> {
+    unsafe fn request_chunk(
+        &mut self,
+        layout: Layout,
+    ) -> Option<NonNull<[u8]>> {
+        if let Some(chunk) = self.take_cached(layout) {
+            return Some(chunk);
+        }
+
+        unsafe { self.source.request_chunk(layout) }
+    }
+
+    unsafe fn release_chunk(&mut self, ptr: NonNull<u8>, layout: Layout) {
+        if !self.try_cache(ptr, layout) {
+            unsafe { self.source.release_chunk(ptr, layout) };
+        }
+    }
+}
+
+impl<S: MemorySource> Drop for CachingSource<S> {
+    fn drop(&mut self) {
+        self.trim();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        allocator::{MutAllocator, bump::BumpAllocator},
+        source::os_heap::OsHeap,
+    };
+
+    /// 内側の source への要求と解放を数える
+    #[derive(Default)]
+    struct Counted {
+        requested: usize,
+        released: usize,
+    }
+
+    impl MemorySource for Counted {
+        unsafe fn request_chunk(
+            &mut self,
+            layout: Layout,
+        ) -> Option<NonNull<[u8]>> {
+            self.requested += 1;
+            unsafe { OsHeap.request_chunk(layout) }
+        }
+
+        unsafe fn release_chunk(&mut self, ptr: NonNull<u8>, layout: Layout) {
+            self.released += 1;
+            unsafe { OsHeap.release_chunk(ptr, layout) }
+        }
+    }
+
+    fn request(
+        cache: &mut CachingSource<Counted>,
+        size: usize,
+        align: usize,
+    ) -> (NonNull<u8>, Layout) {
+        let layout = Layout::from_size_align(size, align).unwrap();
+        let chunk = unsafe { cache.request_chunk(layout).unwrap() };
+        let actual = Layout::from_size_align(chunk.len(), align).unwrap();
+        (chunk.cast::<u8>(), actual)
+    }
+
+    #[test]
+    fn released_chunks_are_reused() {
+        let mut cache = CachingSource::new(Counted::default(), 8, 1 << 20);
+
+        let (a, la) = request(&mut cache, 4096, 8);
+        unsafe { cache.release_chunk(a, la) };
+        assert_eq!(cache.cached_chunks(), 1);
+        assert_eq!(cache.source().released, 0);
+
+        let (b, lb) = request(&mut cache, 3000, 8);
+        assert_eq!(b, a);
+        assert_eq!(lb, la);
+        assert_eq!(cache.source().requested, 1);
+        assert_eq!(cache.cached_chunks(), 0);
+
+        unsafe { cache.release_chunk(b, lb) };
+    }
+
+    #[test]
+    fn only_matching_chunks_are_handed_back() {
+        let mut cache = CachingSource::new(Counted::default(), 8, 1 << 20);
+
+        let (a, la) = request(&mut cache, 4096, 8);
+        unsafe { cache.release_chunk(a, la) };
+
+        // アラインが違う
+        let (b, lb) = request(&mut cache, 4096, 16);
+        assert_ne!(b, a);
+        // 大きさが足りない
+        let (c, lc) = request(&mut cache, 8192, 8);
+        assert_ne!(c, a);
+        // 大きすぎる（サイズクラスが 2 つ以上離れている）
+        let (d, ld) = request(&mut cache, 100, 8);
+        assert_ne!(d, a);
+        assert_eq!(cache.source().requested, 4);
+
+        unsafe {
+            cache.release_chunk(b, lb);
+            cache.release_chunk(c, lc);
+            cache.release_chunk(d, ld);
+        }
+    }
+
+    #[test]
+    fn respects_chunk_and_byte_limits() {
+        let mut cache = CachingSource::new(Counted::default(), 2, 1 << 20);
+        let chunks: [_; 3] =
+            core::array::from_fn(|_| request(&mut cache, 4096, 8));
+        for (p, l) in chunks {
+            unsafe { cache.release_chunk(p, l) };
+        }
+        assert_eq!(cache.cached_chunks(), 2);
+        assert_eq!(cache.source().released, 1);
+
+        let mut cache = CachingSource::new(Counted::default(), 8, 8192);
+        let (a, la) = request(&mut cache, 4096, 8);
+        let (b, lb) = request(&mut cache, 8192, 8);
+        unsafe {
+            cache.release_chunk(a, la);
+            cache.release_chunk(b, lb);
+        }
+        assert_eq!(cache.cached_bytes(), 4096);
+        assert_eq!(cache.source().released, 1);
+    }
+
+    #[test]
+    fn trim_returns_everything() {
+        let mut cache = CachingSource::new(Counted::default(), 8, 1 << 20);
+        let chunks: [_; 4] =
+            core::array::from_fn(|i| request(&mut cache, 4096 << i, 8));
+        for (p, l) in chunks {
+            unsafe { cache.release_chunk(p, l) };
+        }
+        assert_eq!(cache.cached_chunks(), 4);
+
+        cache.trim();
+        assert_eq!(cache.cached_chunks(), 0);
+        assert_eq!(cache.cached_bytes(), 0);
+        assert_eq!(cache.source().released, 4);
+    }
+
+    #[test]
+    fn recreated_bump_allocators_reuse_chunks() {
+        let mut cache = CachingSource::new(Counted::default(), 16, 1 << 20);
+        let l = Layout::from_size_align(1000, 8).unwrap();
+
+        for _ in 0..10 {
+            let mut bump = BumpAllocator::new(&mut cache);
+            for _ in 0..10 {
+                unsafe { bump.alloc(l).unwrap() };
+            }
+        }
+
+        let requested = cache.source().requested;
+        assert!(requested <= 4, "requested {requested} chunks");
+        assert_eq!(cache.source().released, 0);
+    }
+}
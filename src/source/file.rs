@@ -0,0 +1,999 @@
+use core::{
+    alloc::Layout,
+    mem::{self, MaybeUninit},
+    ptr::{self, NonNull},
+};
+use std::{ffi::CString, io, os::unix::ffi::OsStrExt, path::Path};
+
+use crate::{
+    align::align_up,
+    source::{MemorySource, os_heap::page_size},
+};
+
+/// ヘッダに記録できるチャンクの数
+pub const MAX_CHUNKS: usize = 128;
+
+const MAGIC: u64 = u64::from_le_bytes(*b"RIKUHEAP");
+const VERSION: u32 = 3;
+
+/// ファイルを `MAP_SHARED` で map し、そこからチャンクを切り出す `MemorySource`。
+///
+/// ファイルの先頭にはヘッダを置き、切り出したチャンクの一覧を記録する。
+/// ファイルを閉じても中身は残るので、`open` で開き直せばチャンクの一覧と中身がそのまま戻る。
+///
+/// `create` で固定のベースアドレスを指定すると、開き直すときも `MAP_FIXED_NOREPLACE` で
+/// 同じアドレスに map する。この場合はファイルの中に保存したポインタも開き直した後で有効なので、
+/// ポインタでつながったデータ構造を `store` でファイルの中に置いておけば、
+/// `root` で取り出してそのまま辿れる。
+///
+/// `FileMapped` は map とファイルディスクリプタを持つハンドルで、drop すると map を外してファイルを閉じる。
+/// アロケータには `FileMapped` か `&mut FileMapped` を渡す（例えば `BumpAllocator<&mut FileMapped>`）。
+///
+/// `BumpAllocator` と `FreeList` は、`detach` で管理情報（カーソルや free list）をヘッダに書いて、
+/// チャンクを返さずにファイルを手放せる。開き直したファイルを `reopen` に渡すと、同じヒープで割当を続けられる。
+/// 管理情報はポインタでつながっているので、`reopen` できるのは固定のベースアドレスのファイルだけ。
+#[derive(Debug)]
+pub struct FileMapped {
+    header: NonNull<Header>,
+    fd: libc::c_int,
+}
+
+/// ファイルの先頭に置くヘッダ
+#[repr(C)]
+struct Header {
+    magic: u64,
+    version: u32,
+    /// 固定のベースアドレスに map するかどうか
+    fixed: u32,
+    /// 固定のベースアドレス
+    base: usize,
+    /// map するアドレス範囲の大きさ（ファイルの最大の大きさ）
+    capacity: usize,
+    /// ファイルの使っている部分の大きさ
+    len: usize,
+    /// ルートのオフセット（0 ならなし）
+    root: usize,
+    /// `detach` したアロケータの管理情報
+    allocator: SavedAllocator,
+    chunks: [ChunkEntry; MAX_CHUNKS],
+}
+
+/// `detach` したアロケータがヘッダに残す管理情報
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct SavedAllocator {
+    /// `AllocatorKind` の値（0 ならなし）
+    kind: u32,
+    /// アロケータごとの値
+    words: [usize; 4],
+}
+
+/// ヘッダに管理情報を残せるアロケータ
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u32)]
+pub(crate) enum AllocatorKind {
+    Bump = 1,
+    FreeList = 2,
+}
+
+/// ヘッダに記録するチャンク。`size` が 0 のエントリは空き。
+///
+/// ファイルから読んだ値がそのまま入るので、どんなビット列でも有効な整数だけで作る。
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct ChunkEntry {
+    offset: usize,
+    size: usize,
+    /// 0 なら返されたチャンク
+    live: u32,
+}
+
+impl ChunkEntry {
+    fn is_live(&self) -> bool {
+        self.live != 0
+    }
+}
+
+unsafe impl Send for FileMapped {}
+
+impl FileMapped {
+    /// `path` に新しいヒープファイルを作って map する。既存のファイルは切り詰められる。
+    ///
+    /// `capacity` はファイルの最大の大きさで、この大きさのアドレス範囲を最初に予約する。
+    /// `base` を指定すると、そのアドレスに map し、開き直すときも同じアドレスを使う。
+    pub fn create(
+        path: impl AsRef<Path>,
+        capacity: usize,
+        base: Option<NonNull<u8>>,
+    ) -> io::Result<Self> {
+        let header_size = Self::header_size();
+        let capacity = align_up(capacity, page_size());
+        if capacity <= header_size {
+            return Err(io::Error::from(io::ErrorKind::InvalidInput));
+        }
+
+        let flags =
+            libc::O_RDWR | libc::O_CREAT | libc::O_TRUNC | libc::O_CLOEXEC;
+        let fd = open(path.as_ref(), flags)?;
+
+        let mapped = truncate(fd, header_size)
+            .and_then(|()| map(fd, capacity, base.map(|p| p.as_ptr())));
+        let ptr = match mapped {
+            Ok(ptr) => ptr,
+            Err(e) => {
+                unsafe { libc::close(fd) };
+                return Err(e);
+            }
+        };
+
+        let header = ptr.cast::<Header>();
+        unsafe {
+            header.write(Header {
+                magic: MAGIC,
+                version: VERSION,
+                fixed: base.is_some() as u32,
+                base: ptr.as_ptr().addr(),
+                capacity,
+                len: header_size,
+                root: 0,
+                allocator: SavedAllocator {
+                    kind: 0,
+                    words: [0; 4],
+                },
+                chunks: [ChunkEntry {
+                    offset: 0,
+                    size: 0,
+                    live: 0,
+                }; MAX_CHUNKS],
+            })
+        };
+
+        Ok(Self { header, fd })
+    }
+
+    /// `create` で作ったヒープファイルを開き直す。
+    ///
+    /// 固定のベースアドレスで作ったファイルは同じアドレスに map する。
+    /// そのアドレスがすでに使われていればエラーになる。
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let fd = open(path.as_ref(), libc::O_RDWR | libc::O_CLOEXEC)?;
+
+        match unsafe { Self::map_existing(fd) } {
+            Ok(this) => Ok(this),
+            Err(e) => {
+                unsafe { libc::close(fd) };
+                Err(e)
+            }
+        }
+    }
+
+    unsafe fn map_existing(fd: libc::c_int) -> io::Result<Self> {
+        let invalid = || io::Error::from(io::ErrorKind::InvalidData);
+
+        let mut header = MaybeUninit::<Header>::uninit();
+        let n = unsafe {
+            libc::pread(
+                fd,
+                header.as_mut_ptr().cast::<libc::c_void>(),
+                mem::size_of::<Header>(),
+                0,
+            )
+        };
+        if n != mem::size_of::<Header>() as isize {
+            return Err(invalid());
+        }
+
+        // Header は整数だけでできているので、どんなバイト列を読んでも有効
+        let header = unsafe { header.assume_init() };
+        let file_len = file_len(fd)?;
+        if !header.is_valid(file_len) {
+            return Err(invalid());
+        }
+
+        let base = (header.fixed != 0)
+            .then_some(ptr::without_provenance_mut::<u8>(header.base));
+        let ptr = map(fd, header.capacity, base)?;
+
+        Ok(Self {
+            header: ptr.cast::<Header>(),
+            fd,
+        })
+    }
+
+    /// 書き込んだ内容をファイルに書き出す。
+    pub fn flush(&self) -> io::Result<()> {
+        let ret = unsafe {
+            libc::msync(
+                self.header.as_ptr().cast::<libc::c_void>(),
+                self.header().len,
+                libc::MS_SYNC,
+            )
+        };
+        if ret != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// map した領域の先頭
+    pub fn base(&self) -> NonNull<u8> {
+        self.header.cast::<u8>()
+    }
+
+    /// 固定のベースアドレスに map しているかどうか
+    pub fn is_fixed(&self) -> bool {
+        self.header().fixed != 0
+    }
+
+    /// ファイルの使っている部分の大きさ
+    pub fn len(&self) -> usize {
+        self.header().len
+    }
+
+    /// まだチャンクを 1 つも切り出していなければ `true`
+    pub fn is_empty(&self) -> bool {
+        self.header().len == Self::header_size()
+    }
+
+    /// 渡しているチャンクの一覧（切り出した順）
+    pub fn chunks(&self) -> impl Iterator<Item = NonNull<[u8]>> {
+        let base = self.base();
+        let mut entries: [ChunkEntry; MAX_CHUNKS] = self.header().chunks;
+        entries.sort_unstable_by_key(|e| e.offset);
+
+        entries.into_iter().filter(|e| e.is_live()).map(move |e| {
+            let ptr = unsafe { base.add(e.offset) };
+            NonNull::slice_from_raw_parts(ptr, e.size)
+        })
+    }
+
+    /// ルートとして記録したポインタ
+    pub fn root<T>(&self) -> Option<NonNull<T>> {
+        let root = self.header().root;
+        (root != 0).then(|| unsafe { self.base().add(root).cast::<T>() })
+    }
+
+    /// `ptr`（このファイルの中を指すポインタ）をルートとして記録する。
+    /// 固定のベースアドレスでなくても、記録するのはオフセットなので開き直した後も使える。
+    pub fn set_root<T>(&mut self, ptr: NonNull<T>) {
+        let offset = ptr.as_ptr().addr() - self.base().as_ptr().addr();
+        debug_assert!(offset < self.header().capacity);
+        self.header_mut().root = offset;
+    }
+
+    /// `value` を入れるチャンクを取って書き込み、ルートとして記録する。
+    ///
+    /// `value` がポインタを持つなら、開き直した後もそれが有効であるよう、固定のベースアドレスで使ってください。
+    pub fn store<T>(&mut self, value: T) -> Option<NonNull<T>> {
+        let chunk = unsafe { self.request_chunk(Layout::new::<T>())? };
+        let ptr = chunk.cast::<T>();
+        unsafe { ptr.write(value) };
+        self.set_root(ptr);
+        Some(ptr)
+    }
+
+    /// アロケータの管理情報 `words` をヘッダに残す。
+    pub(crate) fn save_allocator(
+        &mut self,
+        kind: AllocatorKind,
+        words: [usize; 4],
+    ) {
+        self.header_mut().allocator = SavedAllocator {
+            kind: kind as u32,
+            words,
+        };
+    }
+
+    /// ヘッダに残した `kind` の管理情報を取り出して消す。
+    ///
+    /// 管理情報のポインタは固定のベースアドレスでしか開き直した後に有効でないので、
+    /// そうでなければ `InvalidInput` を返す。`kind` の管理情報がなければ `InvalidData` を返す。
+    pub(crate) fn take_allocator(
+        &mut self,
+        kind: AllocatorKind,
+    ) -> io::Result<[usize; 4]> {
+        if !self.is_fixed() {
+            return Err(io::Error::from(io::ErrorKind::InvalidInput));
+        }
+
+        let saved = self.header().allocator;
+        if saved.kind != kind as u32 {
+            return Err(io::Error::from(io::ErrorKind::InvalidData));
+        }
+        self.header_mut().allocator.kind = 0;
+        Ok(saved.words)
+    }
+
+    /// `ptr` のファイルの先頭からのオフセット。`None` なら 0
+    pub(crate) fn offset_of<T>(&self, ptr: Option<NonNull<T>>) -> usize {
+        // ファイルから読んだ壊れたポインタも渡されるので、引き算はあふれてもよい
+        ptr.map_or(0, |p| {
+            p.as_ptr().addr().wrapping_sub(self.base().as_ptr().addr())
+        })
+    }
+
+    /// オフセット `offset` から `len` バイトが、生きているどれか 1 つのチャンクに収まっていれば、そのポインタ
+    pub(crate) fn live_range(
+        &self,
+        offset: usize,
+        len: usize,
+    ) -> Option<NonNull<u8>> {
+        let end = offset.checked_add(len)?;
+        self.header()
+            .chunks
+            .iter()
+            .any(|e| {
+                e.is_live() && e.offset <= offset && end <= e.offset + e.size
+            })
+            .then(|| unsafe { self.base().add(offset) })
+    }
+
+    /// オフセット `offset` から始まる生きているチャンク
+    pub(crate) fn live_chunk_at(&self, offset: usize) -> Option<NonNull<[u8]>> {
+        let e = self
+            .header()
+            .chunks
+            .iter()
+            .find(|e| e.is_live() && e.offset == offset)?;
+        let ptr = unsafe { self.base().add(e.offset) };
+        Some(NonNull::slice_from_raw_parts(ptr, e.size))
+    }
+
+    /// オフセット `head` のノードから辿れるチャンクのリストが、生きているチャンクだけでできているかどうか。
+    ///
+    /// `node` はノードが記録しているチャンクの先頭と次のノードを返す。
+    /// ノードはそれぞれのチャンクの先頭にあり、同じチャンクを 2 度通ってはいけない。
+    pub(crate) fn is_chunk_list(
+        &self,
+        head: usize,
+        node: impl Fn(NonNull<u8>) -> (NonNull<u8>, Option<NonNull<u8>>),
+    ) -> bool {
+        let mut cur = head;
+        let mut count = 0;
+        while cur != 0 {
+            let Some(chunk) = self.live_chunk_at(cur) else {
+                return false;
+            };
+            // 生きているチャンクは MAX_CHUNKS 個までなので、それより多く辿れたら循環している
+            count += 1;
+            if count > MAX_CHUNKS {
+                return false;
+            }
+
+            let (ptr, next) = node(chunk.cast::<u8>());
+            if ptr != chunk.cast::<u8>() {
+                return false;
+            }
+            cur = self.offset_of(next);
+        }
+        true
+    }
+
+    fn header(&self) -> &Header {
+        unsafe { self.header.as_ref() }
+    }
+
+    fn header_mut(&mut self) -> &mut Header {
+        unsafe { self.header.as_mut() }
+    }
+
+    fn header_size() -> usize {
+        align_up(mem::size_of::<Header>(), page_size())
+    }
+}
+
+impl Header {
+    /// ディスクから読んだヘッダが、大きさ `file_len` のファイルのものとして辻褄が合っているかどうか
+    fn is_valid(&self, file_len: usize) -> bool {
+        let header_size = FileMapped::header_size();
+
+        if self.magic != MAGIC || self.version != VERSION {
+            return false;
+        }
+        // 使っている部分は map する範囲とファイルの中に収まっていなければならない。
+        // ファイルが切り詰められていると、末尾に触れたときに SIGBUS になる
+        if !self.capacity.is_multiple_of(page_size())
+            || self.len < header_size
+            || self.len > self.capacity
+            || self.len > file_len
+            || (self.root != 0
+                && (self.root < header_size || self.root >= self.len))
+        {
+            return false;
+        }
+
+        // チャンクはヘッダの後ろで、使っている部分に収まっていなければならない
+        self.chunks.iter().filter(|e| e.size != 0).all(|e| {
+            e.offset >= header_size
+                && e.offset
+                    .checked_add(e.size)
+                    .is_some_and(|end| end <= self.len)
+        })
+    }
+}
+
+impl Drop for FileMapped {
+    fn drop(&mut self) {
+        let capacity = self.header().capacity;
+        unsafe {
+            libc::munmap(self.header.as_ptr().cast::<libc::c_void>(), capacity);
+            libc::close(self.fd);
+        }
+    }
+}
+
+impl MemorySource for FileMapped {
+    unsafe fn request_chunk(
+        &mut self,
+        layout: Layout,
+    ) -> Option<NonNull<[u8]>> {
+        let page_size = page_size();
+        let align = layout.align().max(page_size);
+        let size = align_up(layout.size().max(1), page_size);
+        let base = self.base();
+        let base_addr = base.as_ptr().addr();
+
+        let header = self.header_mut();
+
+        // 返されたチャンクの場所が使えるならそこを使う
+        let reusable = header.chunks.iter_mut().find(|e| {
+            !e.is_live()
+                && e.size >= size
+                && (base_addr + e.offset).is_multiple_of(align)
+        });
+        if let Some(entry) = reusable {
+            entry.live = 1;
+            let ptr = unsafe { base.add(entry.offset) };
+            return Some(NonNull::slice_from_raw_parts(ptr, entry.size));
+        }
+
+        let start =
+            align_up(base_addr.checked_add(header.len)?, align) - base_addr;
+        let end = start.checked_add(size)?;
+        if end > header.capacity {
+            return None;
+        }
+
+        let fd = self.fd;
+        let header = self.header_mut();
+        let entry = header.chunks.iter_mut().find(|e| e.size == 0)?;
+        truncate(fd, end).ok()?;
+
+        *entry = ChunkEntry {
+            offset: start,
+            size,
+            live: 1,
+        };
+        header.len = end;
+
+        let ptr = unsafe { base.add(start) };
+        Some(NonNull::slice_from_raw_parts(ptr, size))
+    }
+
+    unsafe fn release_chunk(&mut self, ptr: NonNull<u8>, _layout: Layout) {
+        let offset = ptr.as_ptr().addr() - self.base().as_ptr().addr();
+        let fd = self.fd;
+        let header = self.header_mut();
+
+        let Some(entry) = header
+            .chunks
+            .iter_mut()
+            .find(|e| e.is_live() && e.offset == offset)
+        else {
+            debug_assert!(false, "unknown chunk at offset {offset}");
+            return;
+        };
+        entry.live = 0;
+
+        // 最後の生きているチャンクより後ろは、ファイルを切り詰めて消す
+        let len = header
+            .chunks
+            .iter()
+            .filter(|e| e.is_live())
+            .map(|e| e.offset + e.size)
+            .max()
+            .unwrap_or(Self::header_size());
+        for e in header.chunks.iter_mut().filter(|e| e.offset >= len) {
+            e.size = 0;
+        }
+
+        if len != header.len {
+            header.len = len;
+            let _ = truncate(fd, len);
+        }
+    }
+}
+
+fn open(path: &Path, flags: libc::c_int) -> io::Result<libc::c_int> {
+    let path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?;
+
+    let fd = unsafe { libc::open(path.as_ptr(), flags, 0o600) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(fd)
+}
+
+pub(crate) fn file_len(fd: libc::c_int) -> io::Result<usize> {
+    let mut stat = MaybeUninit::<libc::stat>::uninit();
+    if unsafe { libc::fstat(fd, stat.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { stat.assume_init() }.st_size as usize)
+}
+
+pub(crate) fn truncate(fd: libc::c_int, len: usize) -> io::Result<()> {
+    if unsafe { libc::ftruncate(fd, len as libc::off_t) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// ファイルを `capacity` バイト分 map する。ファイルの大きさを超える部分は、
+/// ファイルを伸ばしたときに使えるようになる。
+pub(crate) fn map(
+    fd: libc::c_int,
+    capacity: usize,
+    base: Option<*mut u8>,
+) -> io::Result<NonNull<u8>> {
+    let mut flags = libc::MAP_SHARED;
+    if base.is_some() {
+        flags |= libc::MAP_FIXED_NOREPLACE;
+    }
+    let hint = base.unwrap_or(ptr::null_mut());
+
+    let ptr = unsafe {
+        libc::mmap(
+            hint.cast::<libc::c_void>(),
+            capacity,
+            libc::PROT_READ | libc::PROT_WRITE,
+            flags,
+            fd,
+            0,
+        )
+    };
+    if ptr == libc::MAP_FAILED {
+        return Err(io::Error::last_os_error());
+    }
+
+    // MAP_FIXED_NOREPLACE を知らない古いカーネルは、別の場所に map することがある
+    if let Some(base) = base
+        && ptr.cast::<u8>() != base
+    {
+        unsafe { libc::munmap(ptr, capacity) };
+        return Err(io::Error::from(io::ErrorKind::AddrInUse));
+    }
+
+    Ok(unsafe { NonNull::new_unchecked(ptr.cast::<u8>()) })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::allocator::{
+        MutAllocator, bump::BumpAllocator, free_list::FreeList,
+    };
+    use std::{fs, panic, path::PathBuf, vec::Vec};
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir()
+            .join(std::format!("rikualloc-{}-{name}", std::process::id()))
+    }
+
+    /// 固定のベースアドレスでヒープファイルを作る。
+    ///
+    /// 空いているアドレスを探してから map するまでの間に、並行して走る他のテストが
+    /// そのアドレスを使ってしまうことがあるので、`MAP_FIXED_NOREPLACE` が失敗したら探し直す。
+    fn create_fixed(path: &Path, capacity: usize) -> FileMapped {
+        for _ in 0..100 {
+            let ptr = unsafe {
+                libc::mmap(
+                    ptr::null_mut(),
+                    capacity,
+                    libc::PROT_NONE,
+                    libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                    -1,
+                    0,
+                )
+            };
+            assert_ne!(ptr, libc::MAP_FAILED);
+            unsafe { libc::munmap(ptr, capacity) };
+            let base = NonNull::new(ptr.cast::<u8>()).unwrap();
+
+            match FileMapped::create(path, capacity, Some(base)) {
+                Ok(heap) => return heap,
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::AlreadyExists | io::ErrorKind::AddrInUse
+                    ) => {}
+                Err(e) => panic!("{e}"),
+            }
+        }
+        panic!("no free address range");
+    }
+
+    /// 子プロセスで `f` を実行し、`true` を返して終了したかどうかを返す。
+    ///
+    /// 子プロセスには他のスレッドがないので、アドレス空間を横取りされずに map し直せる。
+    fn in_child(f: impl FnOnce() -> bool) -> bool {
+        let pid = unsafe { libc::fork() };
+        assert!(pid >= 0);
+
+        if pid == 0 {
+            let ok = panic::catch_unwind(panic::AssertUnwindSafe(f));
+            unsafe { libc::_exit(if matches!(ok, Ok(true)) { 0 } else { 1 }) };
+        }
+
+        let mut status = 0;
+        let ret = unsafe { libc::waitpid(pid, &mut status, 0) };
+        assert_eq!(ret, pid);
+
+        libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0
+    }
+
+    #[test]
+    fn chunks_survive_reopen() {
+        let path = temp_path("chunks");
+        let mut heap = FileMapped::create(&path, 1 << 20, None).unwrap();
+        assert!(heap.is_empty());
+
+        let l = Layout::from_size_align(5000, 8).unwrap();
+        let a = unsafe { heap.request_chunk(l).unwrap() };
+        let b = unsafe { heap.request_chunk(l).unwrap() };
+        unsafe {
+            a.cast::<u8>().write_bytes(0xAA, a.len());
+            b.cast::<u8>().write_bytes(0xBB, b.len());
+        }
+        let offsets = |heap: &FileMapped| -> Vec<(usize, usize)> {
+            let base = heap.base().as_ptr().addr();
+            heap.chunks()
+                .map(|c| (c.cast::<u8>().as_ptr().addr() - base, c.len()))
+                .collect()
+        };
+        let before = offsets(&heap);
+        assert_eq!(before.len(), 2);
+        drop(heap);
+
+        let heap = FileMapped::open(&path).unwrap();
+        assert!(!heap.is_fixed());
+        assert_eq!(offsets(&heap), before);
+
+        let chunks: Vec<_> = heap.chunks().collect();
+        assert_eq!(unsafe { chunks[0].cast::<u8>().read() }, 0xAA);
+        assert_eq!(unsafe { chunks[1].cast::<u8>().read() }, 0xBB);
+
+        drop(heap);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn released_chunks_are_reused_and_the_file_shrinks() {
+        let path = temp_path("release");
+        let mut heap = FileMapped::create(&path, 1 << 20, None).unwrap();
+        let l = Layout::from_size_align(4096, 8).unwrap();
+
+        let a = unsafe { heap.request_chunk(l).unwrap() };
+        let b = unsafe { heap.request_chunk(l).unwrap() };
+        let len = heap.len();
+        assert_eq!(fs::metadata(&path).unwrap().len() as usize, len);
+
+        unsafe { heap.release_chunk(a.cast(), l) };
+        let c = unsafe { heap.request_chunk(l).unwrap() };
+        assert_eq!(c, a);
+        assert_eq!(heap.len(), len);
+
+        unsafe {
+            heap.release_chunk(b.cast(), l);
+            heap.release_chunk(c.cast(), l);
+        }
+        assert!(heap.is_empty());
+        assert_eq!(heap.chunks().count(), 0);
+        assert_eq!(
+            fs::metadata(&path).unwrap().len() as usize,
+            FileMapped::header_size()
+        );
+
+        drop(heap);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn allocators_borrow_the_file() {
+        let path = temp_path("bump");
+        let mut heap = FileMapped::create(&path, 1 << 20, None).unwrap();
+
+        {
+            let mut bump = BumpAllocator::new(&mut heap);
+            let l = Layout::from_size_align(3000, 8).unwrap();
+            for _ in 0..4 {
+                unsafe { bump.alloc(l).unwrap() };
+            }
+        }
+        // drop したアロケータはチャンクをすべて返している
+        assert!(heap.is_empty());
+
+        drop(heap);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn bump_allocator_continues_after_detach() {
+        let path = temp_path("bump-detach");
+        let heap = create_fixed(&path, 1 << 20);
+
+        let l = Layout::from_size_align(128, 8).unwrap();
+        let mut bump = BumpAllocator::new(heap);
+        let p = unsafe { bump.alloc(l).unwrap() };
+        unsafe { p.cast::<u8>().write_bytes(0xAB, l.size()) };
+
+        let heap = bump.detach();
+        assert_eq!(heap.chunks().count(), 1);
+
+        // カーソルの続きから割り当てる
+        let mut bump = BumpAllocator::reopen(heap).unwrap();
+        let q = unsafe { bump.alloc(l).unwrap() };
+        assert_eq!(
+            q.cast::<u8>().as_ptr().addr(),
+            p.cast::<u8>().as_ptr().addr() + l.size()
+        );
+        assert_eq!(unsafe { p.cast::<u8>().read() }, 0xAB);
+
+        drop(bump);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn free_list_survives_reopen() {
+        let path = temp_path("free-list");
+        let heap = create_fixed(&path, 4 << 20);
+
+        let l = Layout::new::<[u64; 64]>();
+        let mut list = FreeList::new(heap);
+        let blocks: Vec<NonNull<u64>> = (0..8)
+            .map(|i| {
+                let p = unsafe { list.alloc(l).unwrap() }.cast::<u64>();
+                unsafe { p.write(i) };
+                p
+            })
+            .collect();
+        let heap = list.detach();
+        heap.flush().unwrap();
+
+        // 子プロセスで map を外して開き直し、同じヒープで解放と割当を続ける
+        let reopened = in_child(|| {
+            drop(unsafe { ptr::read(&heap) });
+
+            let heap = FileMapped::open(&path).unwrap();
+            let mut list = FreeList::reopen(heap).unwrap();
+            let kept = blocks
+                .iter()
+                .zip(0..)
+                .all(|(p, i)| unsafe { p.read() } == i);
+
+            for p in blocks.iter().step_by(2) {
+                unsafe { list.dealloc(p.cast(), l) };
+            }
+            // first-fit なので、解放した一番前の場所が使われる
+            let q = unsafe { list.alloc(l).unwrap() }.cast::<u64>();
+            unsafe { q.write(100) };
+
+            let ok = kept && q == blocks[0] && list.verify().is_ok();
+            list.detach().flush().unwrap();
+            ok
+        });
+        assert!(reopened);
+
+        // MAP_SHARED なので、子プロセスが残した管理情報と中身が見える
+        let mut list = FreeList::reopen(heap).unwrap();
+        assert_eq!(unsafe { blocks[0].read() }, 100);
+        assert_eq!(unsafe { blocks[1].read() }, 1);
+        assert_eq!(list.verify().unwrap().holes, 4);
+
+        let q = unsafe { list.alloc(l).unwrap() }.cast::<u64>();
+        assert_eq!(q, blocks[2]);
+
+        // 最後に drop すれば、チャンクはすべて返される
+        let heap = list.detach();
+        let mut list = FreeList::reopen(heap).unwrap().with_retain(0);
+        for p in blocks.iter().skip(1).step_by(2).chain([&blocks[0], &q]) {
+            unsafe { list.dealloc(p.cast(), l) };
+        }
+        let heap = list.detach();
+        assert!(heap.is_empty());
+
+        drop(heap);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn reopen_needs_a_detached_allocator() {
+        let path = temp_path("not-detached");
+        let heap = create_fixed(&path, 1 << 20);
+
+        // 何も残していない
+        let err = FreeList::reopen(heap).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        fs::remove_file(&path).unwrap();
+
+        // 別のアロケータの管理情報
+        let path = temp_path("other-kind");
+        let heap = create_fixed(&path, 1 << 20);
+        let heap = FreeList::new(heap).detach();
+        let err = BumpAllocator::reopen(heap).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        fs::remove_file(&path).unwrap();
+
+        // 固定のベースアドレスでないファイル
+        let path = temp_path("not-fixed");
+        let heap = FileMapped::create(&path, 1 << 20, None).unwrap();
+        let heap = BumpAllocator::new(heap).detach();
+        let err = BumpAllocator::reopen(heap).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        fs::remove_file(&path).unwrap();
+    }
+
+    /// ファイルの中に置くデータ。ポインタでつながったリスト
+    struct Node {
+        value: u64,
+        next: Option<NonNull<Node>>,
+    }
+
+    struct Root {
+        list: Option<NonNull<Node>>,
+    }
+
+    /// `heap` からチャンクを取って `values` をリストの先頭に足していく
+    fn push(heap: &mut FileMapped, root: &mut Root, values: &[u64]) {
+        let l = Layout::array::<Node>(values.len()).unwrap();
+        let nodes = unsafe { heap.request_chunk(l).unwrap() }.cast::<Node>();
+
+        for (i, &value) in values.iter().enumerate() {
+            let node = unsafe { nodes.add(i) };
+            unsafe {
+                node.write(Node {
+                    value,
+                    next: root.list,
+                })
+            };
+            root.list = Some(node);
+        }
+    }
+
+    fn values(root: &Root) -> Vec<u64> {
+        let mut out = Vec::new();
+        let mut cur = root.list;
+        while let Some(node) = cur {
+            let node = unsafe { node.as_ref() };
+            out.push(node.value);
+            cur = node.next;
+        }
+        out
+    }
+
+    #[test]
+    fn fixed_base_keeps_pointers_valid() {
+        let path = temp_path("fixed");
+        let mut heap = create_fixed(&path, 4 << 20);
+        let base = heap.base();
+        assert!(heap.is_fixed());
+
+        let root = heap.store(Root { list: None }).unwrap();
+        let all: Vec<u64> = (0..1000).collect();
+        push(&mut heap, unsafe { &mut *root.as_ptr() }, &all);
+        heap.flush().unwrap();
+
+        // 子プロセスで map を外して開き直すと、同じアドレスにリストが戻っている
+        let reopened = in_child(|| {
+            drop(unsafe { ptr::read(&heap) });
+
+            let mut heap = FileMapped::open(&path).unwrap();
+            let root = heap.root::<Root>().unwrap();
+            let root = unsafe { &mut *root.as_ptr() };
+            let expected: Vec<u64> = (0..1000).rev().collect();
+
+            // そのまま書き足せる
+            push(&mut heap, root, &[1000]);
+            heap.base() == base && values(root)[1..] == expected[..]
+        });
+        assert!(reopened);
+
+        // MAP_SHARED なので、子プロセスが書き足した分が見える
+        let root = heap.root::<Root>().unwrap();
+        assert_eq!(values(unsafe { root.as_ref() })[0], 1000);
+
+        drop(heap);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn fixed_base_must_be_free() {
+        let path = temp_path("busy");
+        let heap = create_fixed(&path, 1 << 20);
+
+        // 同じアドレスはもう使われている
+        assert!(FileMapped::open(&path).is_err());
+
+        drop(heap);
+        fs::remove_file(&path).unwrap();
+    }
+
+    /// `create` したヒープファイルの `offset` バイト目に `bytes` を書き込む
+    fn patch(path: &Path, offset: usize, bytes: &[u8]) {
+        use std::os::unix::fs::FileExt;
+        let file = fs::OpenOptions::new().write(true).open(path).unwrap();
+        file.write_all_at(bytes, offset as u64).unwrap();
+    }
+
+    #[test]
+    fn rejects_truncated_files() {
+        let path = temp_path("truncated");
+        let mut heap = FileMapped::create(&path, 1 << 20, None).unwrap();
+        let l = Layout::from_size_align(8192, 8).unwrap();
+        unsafe { heap.request_chunk(l).unwrap() };
+        let len = heap.len();
+        drop(heap);
+
+        // ヘッダは残っているが、チャンクの途中でファイルが切れている
+        let file = fs::OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_len(len as u64 - 4096).unwrap();
+        drop(file);
+
+        assert_eq!(
+            FileMapped::open(&path).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn rejects_chunk_entries_outside_the_file() {
+        let path = temp_path("entries");
+        let mut heap = FileMapped::create(&path, 1 << 20, None).unwrap();
+        let l = Layout::from_size_align(4096, 8).unwrap();
+        unsafe { heap.request_chunk(l).unwrap() };
+        drop(heap);
+
+        let entry = mem::offset_of!(Header, chunks);
+        let size = entry + mem::offset_of!(ChunkEntry, size);
+        patch(&path, size, &(1usize << 30).to_ne_bytes());
+
+        assert_eq!(
+            FileMapped::open(&path).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn any_live_byte_is_accepted() {
+        let path = temp_path("live");
+        let mut heap = FileMapped::create(&path, 1 << 20, None).unwrap();
+        let l = Layout::from_size_align(4096, 8).unwrap();
+        unsafe { heap.request_chunk(l).unwrap() };
+        drop(heap);
+
+        // bool だったら UB になる値
+        let live =
+            mem::offset_of!(Header, chunks) + mem::offset_of!(ChunkEntry, live);
+        patch(&path, live, &[7]);
+
+        let heap = FileMapped::open(&path).unwrap();
+        assert_eq!(heap.chunks().count(), 1);
+
+        drop(heap);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn rejects_files_that_are_not_heaps() {
+        let path = temp_path("garbage");
+        fs::write(&path, [0u8; 8192]).unwrap();
+        assert_eq!(
+            FileMapped::open(&path).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,205 @@
+use core::{alloc::Layout, ptr::NonNull};
+
+use crate::{
+    align::align_up,
+    source::{MemorySource, os_heap::page_size},
+};
+
+/// 内側の source から取ったチャンクの前後に、`PROT_NONE` のガードページを置く `MemorySource`。
+///
+/// チャンクを越えて読み書きするとすぐに SIGSEGV になるので、
+/// bump や free list のオーバーランが隣のチャンクを壊すのを防げる。
+///
+/// 前のガードはチャンクのアライン（ページサイズ以上）の大きさ、後ろのガードは 1 ページ。
+/// チャンクの大きさはページサイズの倍数に切り上げられ、末尾はちょうど後ろのガードに接する。
+/// `release_chunk` ではガードも含めた領域全体を内側の source に返す。
+///
+/// 内側の source はページサイズの倍数の大きさのチャンクを返さなければなりません
+/// （`OsHeap` や `ReservedRegion` など）。
+pub struct Guarded<S: MemorySource> {
+    source: S,
+}
+
+impl<S: MemorySource> Guarded<S> {
+    pub const fn new(source: S) -> Self {
+        Self { source }
+    }
+
+    /// 内側の source
+    pub fn source(&mut self) -> &mut S {
+        &mut self.source
+    }
+
+    /// 前のガードの大きさ
+    fn front_guard(layout: Layout) -> usize {
+        layout.align().max(page_size())
+    }
+}
+
+impl<S: MemorySource> MemorySource for Guarded<S> {
+    unsafe fn request_chunk(
+        &mut self,
+        layout: Layout,
+    ) -> Option<NonNull<[u8]>> {
+        let page_size = page_size();
+        let front = Self::front_guard(layout);
+
+        let size = align_up(layout.size().max(1), page_size);
+        let outer = Layout::from_size_align(
+            front.checked_add(size)?.checked_add(page_size)?,
+            front,
+        )
+        .ok()?;
+
+        let chunk = unsafe { self.source.request_chunk(outer)? };
+        debug_assert!(chunk.len().is_multiple_of(page_size));
+
+        let base = chunk.cast::<u8>();
+        let inner_size = chunk.len() - front - page_size;
+        let inner = unsafe { base.add(front) };
+        let back = unsafe { inner.add(inner_size) };
+
+        unsafe {
+            let front_ok = protect(base, front, libc::PROT_NONE);
+            let back_ok = protect(back, page_size, libc::PROT_NONE);
+            if !(front_ok && back_ok) {
+                let rw = libc::PROT_READ | libc::PROT_WRITE;
+                protect(base, front, rw);
+                protect(back, page_size, rw);
+
+                let actual =
+                    Layout::from_size_align_unchecked(chunk.len(), front);
+                self.source.release_chunk(base, actual);
+                return None;
+            }
+        }
+
+        Some(NonNull::slice_from_raw_parts(inner, inner_size))
+    }
+
+    unsafe fn release_chunk(&mut self, ptr: NonNull<u8>, layout: Layout) {
+        let page_size = page_size();
+        let front = Self::front_guard(layout);
+
+        unsafe {
+            let base = ptr.sub(front);
+            let back = ptr.add(layout.size());
+
+            // 内側の source が領域を再利用できるよう、ガードを元に戻してから返す
+            let rw = libc::PROT_READ | libc::PROT_WRITE;
+            protect(base, front, rw);
+            protect(back, page_size, rw);
+
+            let outer = Layout::from_size_align_unchecked(
+                front + layout.size() + page_size,
+                front,
+            );
+            self.source.release_chunk(base, outer);
+        }
+    }
+}
+
+unsafe fn protect(ptr: NonNull<u8>, len: usize, prot: libc::c_int) -> bool {
+    unsafe {
+        libc::mprotect(ptr.as_ptr().cast::<libc::c_void>(), len, prot) == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        allocator::{MutAllocator, bump::BumpAllocator},
+        source::os_heap::OsHeap,
+    };
+
+    /// 子プロセスで `f` を実行し、SIGSEGV で終了したかどうかを返す
+    fn dies_with_sigsegv(f: impl FnOnce()) -> bool {
+        let pid = unsafe { libc::fork() };
+        assert!(pid >= 0);
+
+        if pid == 0 {
+            // コアダンプを残さない
+            let no_core = libc::rlimit {
+                rlim_cur: 0,
+                rlim_max: 0,
+            };
+            unsafe { libc::setrlimit(libc::RLIMIT_CORE, &no_core) };
+
+            f();
+            unsafe { libc::_exit(0) };
+        }
+
+        let mut status = 0;
+        let ret = unsafe { libc::waitpid(pid, &mut status, 0) };
+        assert_eq!(ret, pid);
+
+        libc::WIFSIGNALED(status) && libc::WTERMSIG(status) == libc::SIGSEGV
+    }
+
+    #[test]
+    fn chunk_is_usable_up_to_its_end() {
+        let mut source = Guarded::new(OsHeap);
+        let layout = Layout::from_size_align(5000, 8).unwrap();
+
+        let chunk = unsafe { source.request_chunk(layout).unwrap() };
+        assert_eq!(chunk.len(), align_up(5000, page_size()));
+
+        unsafe { chunk.cast::<u8>().write_bytes(0xCC, chunk.len()) };
+
+        let actual = Layout::from_size_align(chunk.len(), 8).unwrap();
+        unsafe { source.release_chunk(chunk.cast(), actual) };
+    }
+
+    #[test]
+    fn overrun_past_the_end_faults() {
+        let mut source = Guarded::new(OsHeap);
+        let layout = Layout::from_size_align(100, 8).unwrap();
+        let chunk = unsafe { source.request_chunk(layout).unwrap() };
+        let ptr = chunk.cast::<u8>();
+
+        assert!(dies_with_sigsegv(|| unsafe {
+            ptr.add(chunk.len()).write_volatile(1);
+        }));
+        assert!(dies_with_sigsegv(|| unsafe {
+            ptr.sub(1).write_volatile(1);
+        }));
+
+        // ガードの内側は親プロセスでも普通に使える
+        unsafe { ptr.add(chunk.len() - 1).write_volatile(1) };
+
+        let actual = Layout::from_size_align(chunk.len(), 8).unwrap();
+        unsafe { source.release_chunk(ptr, actual) };
+    }
+
+    #[test]
+    fn guards_respect_alignment() {
+        let mut source = Guarded::new(OsHeap);
+        let align = 64 * 1024;
+        let layout = Layout::from_size_align(4096, align).unwrap();
+
+        let chunk = unsafe { source.request_chunk(layout).unwrap() };
+        let ptr = chunk.cast::<u8>();
+        assert_eq!(ptr.as_ptr().addr() % align, 0);
+        assert!(dies_with_sigsegv(|| unsafe {
+            ptr.sub(align).write_volatile(1);
+        }));
+
+        let actual = Layout::from_size_align(chunk.len(), align).unwrap();
+        unsafe { source.release_chunk(ptr, actual) };
+    }
+
+    #[test]
+    fn bump_overflow_hits_the_guard() {
+        let mut bump = BumpAllocator::new(Guarded::new(OsHeap));
+        let l = Layout::from_size_align(64, 8).unwrap();
+        let p = unsafe { bump.alloc(l).unwrap() }.cast::<u8>();
+
+        assert!(dies_with_sigsegv(|| unsafe {
+            // チャンクの末尾を越えるまで書き続ける
+            for i in 0.. {
+                p.add(i).write_volatile(0);
+            }
+        }));
+    }
+}
@@ -0,0 +1,219 @@
+use core::{alloc::Layout, ptr, ptr::NonNull};
+
+use crate::{
+    align::align_up,
+    source::{
+        MemorySource,
+        os_heap::{OsHeap, map_aligned, page_size},
+    },
+};
+
+/// transparent huge page の大きさ
+pub const TRANSPARENT_HUGE_PAGE_SIZE: usize = 2 * 1024 * 1024;
+
+/// `HugePageHeap` に要求するページの種類
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HugePages {
+    /// `MAP_HUGETLB` で、大きさ `page_size`（2 の冪）の hugetlb ページを使う
+    HugeTlb { page_size: usize },
+    /// 2 MiB にアラインした領域を取り、`madvise(MADV_HUGEPAGE)` をかける
+    Transparent,
+}
+
+/// チャンクを取るときに実際に使われたページの種類
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PageMode {
+    /// hugetlb ページ
+    HugeTlb,
+    /// `MADV_HUGEPAGE` が受け付けられた通常の map
+    Transparent,
+    /// 通常のページ（huge page が使えなかった）
+    Normal,
+}
+
+/// huge page でチャンクを取る `MemorySource`。
+///
+/// TLB ミスを減らしたい大きなアリーナ向け。huge page が使えないときは
+/// 通常のページにフォールバックし、どちらを使ったかを `last_mode` で返す。
+///
+/// チャンクの大きさは huge page の大きさに切り上げられる。
+/// `HugeTlb` でページサイズより大きいアラインを要求されたときは通常のページを使う。
+pub struct HugePageHeap {
+    pages: HugePages,
+    last_mode: Option<PageMode>,
+}
+
+impl HugePageHeap {
+    pub const fn new(pages: HugePages) -> Self {
+        if let HugePages::HugeTlb { page_size } = pages {
+            assert!(page_size.is_power_of_two());
+        }
+
+        Self {
+            pages,
+            last_mode: None,
+        }
+    }
+
+    /// 要求するページの種類
+    pub fn pages(&self) -> HugePages {
+        self.pages
+    }
+
+    /// 最後に取ったチャンクで実際に使われたページの種類。
+    /// まだ 1 つもチャンクを取っていなければ `None`。
+    pub fn last_mode(&self) -> Option<PageMode> {
+        self.last_mode
+    }
+
+    fn map_hugetlb(page_size: usize, layout: Layout) -> Option<NonNull<[u8]>> {
+        if layout.align() > page_size {
+            return None;
+        }
+
+        let size = align_up(layout.size().max(1), page_size);
+        let flags = libc::MAP_PRIVATE
+            | libc::MAP_ANONYMOUS
+            | libc::MAP_HUGETLB
+            | ((page_size.trailing_zeros() as libc::c_int)
+                << libc::MAP_HUGE_SHIFT);
+
+        let ptr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                size,
+                libc::PROT_READ | libc::PROT_WRITE,
+                flags,
+                -1,
+                0,
+            )
+        };
+
+        if ptr == libc::MAP_FAILED {
+            return None;
+        }
+
+        NonNull::new(ptr::slice_from_raw_parts_mut(ptr.cast::<u8>(), size))
+    }
+
+    fn map_transparent(layout: Layout) -> Option<(NonNull<[u8]>, PageMode)> {
+        let size = align_up(layout.size().max(1), TRANSPARENT_HUGE_PAGE_SIZE);
+        let align = layout.align().max(TRANSPARENT_HUGE_PAGE_SIZE);
+
+        let ptr = unsafe { map_aligned(size, align)? };
+
+        // THP が無効なカーネルでは失敗するが、領域自体は通常のページとして使える
+        let advised = unsafe {
+            libc::madvise(ptr.cast::<libc::c_void>(), size, libc::MADV_HUGEPAGE)
+        } == 0;
+        let mode = if advised {
+            PageMode::Transparent
+        } else {
+            PageMode::Normal
+        };
+
+        let chunk = NonNull::new(ptr::slice_from_raw_parts_mut(ptr, size))?;
+        Some((chunk, mode))
+    }
+}
+
+impl MemorySource for HugePageHeap {
+    unsafe fn request_chunk(
+        &mut self,
+        layout: Layout,
+    ) -> Option<NonNull<[u8]>> {
+        let mapped = match self.pages {
+            HugePages::HugeTlb { page_size } => {
+                Self::map_hugetlb(page_size, layout)
+                    .map(|chunk| (chunk, PageMode::HugeTlb))
+            }
+            HugePages::Transparent => Self::map_transparent(layout),
+        };
+
+        let (chunk, mode) = match mapped {
+            Some(mapped) => mapped,
+            None => {
+                let chunk = unsafe { OsHeap.request_chunk(layout)? };
+                (chunk, PageMode::Normal)
+            }
+        };
+
+        self.last_mode = Some(mode);
+        Some(chunk)
+    }
+
+    unsafe fn release_chunk(&mut self, ptr: NonNull<u8>, layout: Layout) {
+        // どのモードで取ったチャンクでも、渡される大きさはページサイズの倍数
+        let size = align_up(layout.size(), page_size());
+
+        unsafe {
+            libc::munmap(ptr.as_ptr().cast::<libc::c_void>(), size);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn release(heap: &mut HugePageHeap, chunk: NonNull<[u8]>, align: usize) {
+        let actual = Layout::from_size_align(chunk.len(), align).unwrap();
+        unsafe { heap.release_chunk(chunk.cast(), actual) };
+    }
+
+    #[test]
+    fn transparent_chunks_are_aligned_to_huge_pages() {
+        let mut heap = HugePageHeap::new(HugePages::Transparent);
+        assert_eq!(heap.last_mode(), None);
+
+        let layout = Layout::from_size_align(3 * 1024 * 1024, 8).unwrap();
+        let chunk = unsafe { heap.request_chunk(layout).unwrap() };
+        let ptr = chunk.cast::<u8>();
+
+        assert_eq!(ptr.as_ptr().addr() % TRANSPARENT_HUGE_PAGE_SIZE, 0);
+        assert_eq!(chunk.len(), 2 * TRANSPARENT_HUGE_PAGE_SIZE);
+        assert!(matches!(
+            heap.last_mode(),
+            Some(PageMode::Transparent | PageMode::Normal)
+        ));
+
+        unsafe { ptr.write_bytes(0x5A, chunk.len()) };
+        release(&mut heap, chunk, 8);
+    }
+
+    #[test]
+    fn hugetlb_falls_back_to_normal_pages() {
+        // どのカーネルも対応していないページサイズなので、必ずフォールバックする
+        let mut heap =
+            HugePageHeap::new(HugePages::HugeTlb { page_size: 1 << 40 });
+
+        let layout = Layout::from_size_align(10_000, 8).unwrap();
+        let chunk = unsafe { heap.request_chunk(layout).unwrap() };
+        assert_eq!(heap.last_mode(), Some(PageMode::Normal));
+        assert!(chunk.len() >= layout.size());
+
+        unsafe { chunk.cast::<u8>().write_bytes(0, chunk.len()) };
+        release(&mut heap, chunk, 8);
+    }
+
+    #[test]
+    fn hugetlb_uses_huge_pages_when_available() {
+        let page_size = 2 * 1024 * 1024;
+        let mut heap = HugePageHeap::new(HugePages::HugeTlb { page_size });
+
+        let layout = Layout::from_size_align(4096, 8).unwrap();
+        let chunk = unsafe { heap.request_chunk(layout).unwrap() };
+
+        // hugetlb ページが予約されていない環境ではフォールバックする
+        match heap.last_mode() {
+            Some(PageMode::HugeTlb) => {
+                assert_eq!(chunk.len(), page_size);
+                assert_eq!(chunk.cast::<u8>().as_ptr().addr() % page_size, 0);
+            }
+            mode => assert_eq!(mode, Some(PageMode::Normal)),
+        }
+
+        unsafe { chunk.cast::<u8>().write_bytes(0, chunk.len()) };
+        release(&mut heap, chunk, 8);
+    }
+}
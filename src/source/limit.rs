@@ -0,0 +1,239 @@
+use core::{alloc::Layout, ptr::NonNull};
+
+use crate::source::MemorySource;
+
+/// `Limited` が上限に達したときの状況
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LimitHit {
+    /// その時点の上限
+    pub limit: usize,
+    /// すでに取っているバイト数
+    pub used: usize,
+    /// 新しく要求されたバイト数
+    pub requested: usize,
+}
+
+/// 内側の source から取れるメモリの合計に上限を設ける `MemorySource`。
+///
+/// 取っているチャンクの合計（実際の大きさ）が `limit` を超える `request_chunk` は `None` を返し、
+/// `release_chunk` した分は再び使えるようになる。
+///
+/// `with_callback` で、上限に達したときに呼ばれるコールバックを設定できる。
+/// コールバックが `Some(new_limit)` を返すと上限を `new_limit` に変えてもう一度試し、
+/// `None` を返すとそのまま失敗する（ログを取るだけならこちら）。
+pub struct Limited<S: MemorySource, F = fn(LimitHit) -> Option<usize>>
+where
+    F: FnMut(LimitHit) -> Option<usize>,
+{
+    source: S,
+    limit: usize,
+    used: usize,
+    on_limit: Option<F>,
+}
+
+impl<S: MemorySource> Limited<S> {
+    pub const fn new(source: S, limit: usize) -> Self {
+        Self {
+            source,
+            limit,
+            used: 0,
+            on_limit: None,
+        }
+    }
+}
+
+impl<S: MemorySource, F: FnMut(LimitHit) -> Option<usize>> Limited<S, F> {
+    /// 上限に達したときに呼ばれるコールバックを設定する。
+    pub fn with_callback<G>(self, on_limit: G) -> Limited<S, G>
+    where
+        G: FnMut(LimitHit) -> Option<usize>,
+    {
+        Limited {
+            source: self.source,
+            limit: self.limit,
+            used: self.used,
+            on_limit: Some(on_limit),
+        }
+    }
+
+    /// 上限のバイト数
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    /// 上限を変える。すでに取っている分が新しい上限を超えていても、返されるまではそのまま。
+    pub fn set_limit(&mut self, limit: usize) {
+        self.limit = limit;
+    }
+
+    /// 内側の source から取っているバイト数
+    pub fn used(&self) -> usize {
+        self.used
+    }
+
+    /// 上限まであと何バイト取れるか
+    pub fn remaining(&self) -> usize {
+        self.limit.saturating_sub(self.used)
+    }
+
+    /// 内側の source
+    pub fn source(&mut self) -> &mut S {
+        &mut self.source
+    }
+
+    /// `size` バイト増やしても上限を超えないか。超えるならコールバックに上限を上げてもらう。
+    fn admit(&mut self, size: usize) -> bool {
+        loop {
+            if self.used.checked_add(size).is_some_and(|n| n <= self.limit) {
+                return true;
+            }
+
+            let hit = LimitHit {
+                limit: self.limit,
+                used: self.used,
+                requested: size,
+            };
+            match self.on_limit.as_mut().and_then(|f| f(hit)) {
+                // 上限が上がらなければ、何度聞いても同じなのでやめる
+                Some(new_limit) if new_limit > self.limit => {
+                    self.limit = new_limit
+                }
+                _ => return false,
+            }
+        }
+    }
+}
+
+impl<S: MemorySource, F: FnMut(LimitHit) -> Option<usize>> MemorySource
+    for Limited<S, F>
+{
+    unsafe fn request_chunk(
+        &mut self,
+        layout: Layout,
+    ) -> Option<NonNull<[u8]>> {
+        if !self.admit(layout.size()) {
+            return None;
+        }
+
+        let chunk = unsafe { self.source.request_chunk(layout)? };
+
+        // 実際のチャンクは要求より大きいことがあるので、その大きさで数え直す
+        if chunk.len() > layout.size() && !self.admit(chunk.len()) {
+            unsafe {
+                let actual = Layout::from_size_align_unchecked(
+                    chunk.len(),
+                    layout.align(),
+                );
+                self.source.release_chunk(chunk.cast::<u8>(), actual);
+            }
+            return None;
+        }
+
+        self.used += chunk.len();
+        Some(chunk)
+    }
+
+    unsafe fn release_chunk(&mut self, ptr: NonNull<u8>, layout: Layout) {
+        self.used -= layout.size();
+        unsafe { self.source.release_chunk(ptr, layout) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        allocator::{MutAllocator, bump::BumpAllocator, free_list::FreeList},
+        mutex::Locked,
+        source::os_heap::OsHeap,
+    };
+    use std::vec::Vec;
+
+    #[test]
+    fn bump_allocator_stops_at_the_limit() {
+        let mut bump = BumpAllocator::new(Limited::new(OsHeap, 16 * 1024));
+        let l = Layout::from_size_align(1000, 8).unwrap();
+
+        let mut n = 0;
+        while unsafe { bump.alloc(l) }.is_some() {
+            n += 1;
+            assert!(n < 100);
+        }
+        assert!(n >= 12);
+    }
+
+    #[test]
+    fn released_chunks_give_budget_back() {
+        let mut list =
+            FreeList::new(Limited::new(OsHeap, 8 * 1024)).with_retain(0);
+        let l = Layout::from_size_align(6000, 8).unwrap();
+
+        let p = unsafe { list.alloc(l).unwrap() };
+        assert!(unsafe { list.alloc(l) }.is_none());
+
+        unsafe { list.dealloc(p.cast(), l) };
+        assert!(unsafe { list.alloc(l) }.is_some());
+    }
+
+    #[test]
+    fn callback_can_raise_the_limit() {
+        let mut hits = Vec::new();
+        let mut source =
+            Limited::new(OsHeap, 4096).with_callback(|hit: LimitHit| {
+                hits.push(hit);
+                (hits.len() == 1).then_some(hit.limit * 2)
+            });
+
+        let l = Layout::from_size_align(4096, 8).unwrap();
+        let a = unsafe { source.request_chunk(l).unwrap() };
+        let b = unsafe { source.request_chunk(l).unwrap() };
+        assert_eq!(source.limit(), 8192);
+        assert!(unsafe { source.request_chunk(l) }.is_none());
+        assert_eq!(source.used(), 8192);
+
+        unsafe {
+            source.release_chunk(a.cast(), l);
+            source.release_chunk(b.cast(), l);
+        }
+        assert_eq!(source.used(), 0);
+
+        assert_eq!(
+            hits,
+            [
+                LimitHit {
+                    limit: 4096,
+                    used: 4096,
+                    requested: 4096
+                },
+                LimitHit {
+                    limit: 8192,
+                    used: 8192,
+                    requested: 4096
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn works_behind_locked() {
+        let bump =
+            Locked::new(BumpAllocator::new(Limited::new(OsHeap, 64 * 1024)));
+
+        let mut v: Vec<u8, _> = Vec::new_in(&bump);
+        assert!(v.try_reserve(32 * 1024).is_ok());
+        assert!(v.try_reserve(128 * 1024).is_err());
+
+        // source を共有して、別々のアロケータで同じ予算を使う
+        let shared = Locked::new(Limited::new(OsHeap, 16 * 1024));
+        let mut a = BumpAllocator::new(&shared);
+        let mut b = FreeList::new(&shared);
+        let l = Layout::from_size_align(6000, 8).unwrap();
+        assert!(unsafe { a.alloc(l) }.is_some());
+        assert!(unsafe { b.alloc(l) }.is_some());
+        assert!(
+            unsafe { b.alloc(Layout::from_size_align(8000, 8).unwrap()) }
+                .is_none()
+        );
+        assert!(shared.with_lock(|s| s.used()) <= 16 * 1024);
+    }
+}
@@ -17,23 +17,13 @@ impl MemorySource for OsHeap {
 
         let alloc_size = align_up(layout.size(), page_size);
 
-        let ptr = unsafe {
-            libc::mmap(
-                ptr::null_mut(),
-                alloc_size,
-                libc::PROT_READ | libc::PROT_WRITE,
-                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
-                -1,
-                0,
-            )
+        let ptr = if layout.align() <= page_size {
+            map(alloc_size, libc::PROT_READ | libc::PROT_WRITE)?
+        } else {
+            unsafe { map_aligned(alloc_size, layout.align())? }
         };
 
-        if ptr == libc::MAP_FAILED {
-            return None;
-        }
-
-        let slice_ptr =
-            ptr::slice_from_raw_parts_mut(ptr.cast::<u8>(), alloc_size);
+        let slice_ptr = ptr::slice_from_raw_parts_mut(ptr, alloc_size);
 
         NonNull::new(slice_ptr)
     }
@@ -43,6 +33,8 @@ impl MemorySource for OsHeap {
         ptr: core::ptr::NonNull<u8>,
         layout: core::alloc::Layout,
     ) {
+        // アラインのために余分に取った部分は確保時に unmap 済みなので、
+        // どのアラインでも解放するのはサイズ分だけ
         let alloc_size = align_up(layout.size(), page_size());
 
         unsafe {
@@ -51,9 +43,62 @@ impl MemorySource for OsHeap {
     }
 }
 
+/// 匿名メモリを `size` バイト map する
+pub(crate) fn map(size: usize, prot: libc::c_int) -> Option<*mut u8> {
+    let ptr = unsafe {
+        libc::mmap(
+            ptr::null_mut(),
+            size,
+            prot,
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+            -1,
+            0,
+        )
+    };
+
+    if ptr == libc::MAP_FAILED {
+        return None;
+    }
+
+    Some(ptr.cast::<u8>())
+}
+
+/// ページサイズより大きいアラインで `size` バイト map する。
+///
+/// `size + align - page_size` バイトを `PROT_NONE` で予約し、アラインされた部分の前後を
+/// `munmap` で切り落としてから、残りを読み書き可能にする。
+/// 予約の段階ではメモリをコミットしないので、大きなアラインでも余分な分は数えられない。
+pub(crate) unsafe fn map_aligned(size: usize, align: usize) -> Option<*mut u8> {
+    let page_size = page_size();
+    let reserve_size = size.checked_add(align - page_size)?;
+
+    let base = map(reserve_size, libc::PROT_NONE)?;
+    let start = align_up(base.addr(), align);
+    let head = start - base.addr();
+    let tail = reserve_size - head - size;
+
+    unsafe {
+        if head > 0 {
+            libc::munmap(base.cast::<libc::c_void>(), head);
+        }
+        let ptr = base.add(head);
+        if tail > 0 {
+            libc::munmap(ptr.add(size).cast::<libc::c_void>(), tail);
+        }
+
+        let prot = libc::PROT_READ | libc::PROT_WRITE;
+        if libc::mprotect(ptr.cast::<libc::c_void>(), size, prot) != 0 {
+            libc::munmap(ptr.cast::<libc::c_void>(), size);
+            return None;
+        }
+
+        Some(ptr)
+    }
+}
+
 static PAGE_SIZE: AtomicUsize = AtomicUsize::new(0);
 
-fn page_size() -> usize {
+pub(crate) fn page_size() -> usize {
     let page_size = PAGE_SIZE.load(Ordering::Relaxed);
     if page_size != 0 {
         return page_size;
@@ -63,3 +108,45 @@ fn page_size() -> usize {
     PAGE_SIZE.store(page_size, Ordering::Relaxed);
     page_size
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chunks_are_page_aligned_by_default() {
+        let mut heap = OsHeap;
+        let layout = Layout::from_size_align(100, 8).unwrap();
+
+        let chunk = unsafe { heap.request_chunk(layout).unwrap() };
+        assert_eq!(chunk.len(), page_size());
+        assert_eq!(chunk.cast::<u8>().as_ptr().addr() % page_size(), 0);
+
+        let actual = Layout::from_size_align(chunk.len(), 8).unwrap();
+        unsafe { heap.release_chunk(chunk.cast(), actual) };
+    }
+
+    #[test]
+    fn chunks_respect_large_alignments() {
+        let mut heap = OsHeap;
+
+        let mut align = 4096;
+        while align <= 1 << 30 {
+            let layout = Layout::from_size_align(3 * 4096, align).unwrap();
+            let chunk = unsafe { heap.request_chunk(layout).unwrap() };
+            let ptr = chunk.cast::<u8>();
+
+            assert_eq!(ptr.as_ptr().addr() % align, 0, "align {align}");
+            assert!(chunk.len() >= layout.size());
+
+            // 全体に書き込める
+            unsafe { ptr.write_bytes(0xAB, chunk.len()) };
+            assert_eq!(unsafe { ptr.add(chunk.len() - 1).read() }, 0xAB);
+
+            let actual = Layout::from_size_align(chunk.len(), align).unwrap();
+            unsafe { heap.release_chunk(ptr, actual) };
+
+            align *= 2;
+        }
+    }
+}
@@ -0,0 +1,251 @@
+use core::{alloc::Layout, ptr, ptr::NonNull};
+
+use crate::{
+    align::align_up,
+    source::{MemorySource, os_heap::page_size},
+};
+
+/// 大きな仮想アドレス範囲を先に予約しておき、必要になった分だけコミットする `MemorySource`。
+///
+/// `new` で `PROT_NONE` と `MAP_NORESERVE` を付けて範囲全体を予約し、
+/// `request_chunk` は予約した範囲を先頭から順に切り出しながら、
+/// まだコミットしていない部分を `mprotect` で読み書き可能にする。
+/// チャンクは常に前のチャンクの直後から取るので、アリーナは 1 つの連続した領域として伸びていく。
+///
+/// `release_chunk` されたチャンクは `madvise(MADV_DONTNEED)` で物理メモリを返す。
+/// 一番最後に取ったチャンクであれば、その分だけ切り出し位置を戻して再利用する。
+pub struct ReservedRegion {
+    base: NonNull<u8>,
+    reserved: usize,
+
+    /// 先頭から何バイトを読み書き可能にしたか
+    committed: usize,
+
+    /// 次のチャンクを切り出す位置（先頭からのオフセット）
+    cursor: usize,
+
+    /// 渡しているチャンクの合計バイト数
+    used: usize,
+}
+
+unsafe impl Send for ReservedRegion {}
+
+impl ReservedRegion {
+    /// `size` バイト（ページサイズに切り上げ）のアドレス範囲を予約する。
+    pub fn new(size: usize) -> Option<Self> {
+        let reserved = align_up(size.max(1), page_size());
+
+        let ptr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                reserved,
+                libc::PROT_NONE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_NORESERVE,
+                -1,
+                0,
+            )
+        };
+
+        if ptr == libc::MAP_FAILED {
+            return None;
+        }
+
+        Some(Self {
+            base: NonNull::new(ptr.cast::<u8>())?,
+            reserved,
+            committed: 0,
+            cursor: 0,
+            used: 0,
+        })
+    }
+
+    /// 予約したアドレス範囲の先頭
+    pub fn base(&self) -> NonNull<u8> {
+        self.base
+    }
+
+    /// 予約したバイト数
+    pub fn reserved_bytes(&self) -> usize {
+        self.reserved
+    }
+
+    /// 読み書き可能にしたバイト数
+    pub fn committed_bytes(&self) -> usize {
+        self.committed
+    }
+
+    /// チャンクとして渡しているバイト数
+    pub fn used_bytes(&self) -> usize {
+        self.used
+    }
+
+    /// 先頭から `end` バイトまでを読み書き可能にする
+    fn commit(&mut self, end: usize) -> Option<()> {
+        if end <= self.committed {
+            return Some(());
+        }
+
+        let ret = unsafe {
+            libc::mprotect(
+                self.base
+                    .as_ptr()
+                    .add(self.committed)
+                    .cast::<libc::c_void>(),
+                end - self.committed,
+                libc::PROT_READ | libc::PROT_WRITE,
+            )
+        };
+        if ret != 0 {
+            return None;
+        }
+
+        self.committed = end;
+        Some(())
+    }
+}
+
+impl MemorySource for ReservedRegion {
+    unsafe fn request_chunk(
+        &mut self,
+        layout: Layout,
+    ) -> Option<NonNull<[u8]>> {
+        let page_size = page_size();
+        let align = layout.align().max(page_size);
+
+        let base_addr = self.base.as_ptr().addr();
+        let start = align_up(base_addr.checked_add(self.cursor)?, align)
+            .checked_sub(base_addr)?;
+        let size = align_up(layout.size().max(1), page_size);
+        let end = start.checked_add(size)?;
+        if end > self.reserved {
+            return None;
+        }
+
+        self.commit(end)?;
+        self.cursor = end;
+        self.used += size;
+
+        let ptr = unsafe { self.base.add(start) };
+        Some(NonNull::slice_from_raw_parts(ptr, size))
+    }
+
+    unsafe fn release_chunk(&mut self, ptr: NonNull<u8>, layout: Layout) {
+        let start = ptr.as_ptr().addr() - self.base.as_ptr().addr();
+        let size = align_up(layout.size(), page_size());
+        debug_assert!(start + size <= self.cursor);
+
+        unsafe {
+            libc::madvise(
+                ptr.as_ptr().cast::<libc::c_void>(),
+                size,
+                libc::MADV_DONTNEED,
+            );
+        }
+
+        self.used -= size;
+        if start + size == self.cursor {
+            self.cursor = start;
+        }
+    }
+}
+
+impl Drop for ReservedRegion {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(
+                self.base.as_ptr().cast::<libc::c_void>(),
+                self.reserved,
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::allocator::{MutAllocator, bump::BumpAllocator};
+
+    fn addr(p: NonNull<[u8]>) -> usize {
+        p.cast::<u8>().as_ptr().addr()
+    }
+
+    #[test]
+    fn commits_on_demand() {
+        let mut region = ReservedRegion::new(1 << 30).unwrap();
+        assert_eq!(region.reserved_bytes(), 1 << 30);
+        assert_eq!(region.committed_bytes(), 0);
+        assert_eq!(region.used_bytes(), 0);
+
+        let page = page_size();
+        let l = Layout::from_size_align(page + 1, 8).unwrap();
+        let a = unsafe { region.request_chunk(l).unwrap() };
+        assert_eq!(addr(a), region.base().as_ptr().addr());
+        assert_eq!(a.len(), 2 * page);
+        assert_eq!(region.committed_bytes(), 2 * page);
+        assert_eq!(region.used_bytes(), 2 * page);
+
+        // 次のチャンクは直後から取られる
+        let b = unsafe { region.request_chunk(l).unwrap() };
+        assert_eq!(addr(b), addr(a) + a.len());
+        assert_eq!(region.committed_bytes(), 4 * page);
+
+        unsafe {
+            a.cast::<u8>().write_bytes(1, a.len());
+            b.cast::<u8>().write_bytes(2, b.len());
+        }
+    }
+
+    #[test]
+    fn releasing_the_last_chunk_rewinds() {
+        let mut region = ReservedRegion::new(1 << 20).unwrap();
+        let page = page_size();
+        let l = Layout::from_size_align(page, 8).unwrap();
+
+        let a = unsafe { region.request_chunk(l).unwrap() };
+        let b = unsafe { region.request_chunk(l).unwrap() };
+        unsafe { b.cast::<u8>().write_bytes(0xFF, b.len()) };
+
+        unsafe { region.release_chunk(b.cast(), l) };
+        assert_eq!(region.used_bytes(), page);
+        assert_eq!(region.committed_bytes(), 2 * page);
+
+        // 同じ場所が再び使われ、中身は捨てられている
+        let c = unsafe { region.request_chunk(l).unwrap() };
+        assert_eq!(addr(c), addr(b));
+        assert_eq!(unsafe { c.cast::<u8>().read() }, 0);
+
+        // 途中のチャンクを返しても切り出し位置は戻らない
+        unsafe { region.release_chunk(a.cast(), l) };
+        let d = unsafe { region.request_chunk(l).unwrap() };
+        assert_eq!(addr(d), addr(c) + page);
+    }
+
+    #[test]
+    fn respects_alignment_and_reservation() {
+        let mut region = ReservedRegion::new(4 << 20).unwrap();
+
+        let small = Layout::from_size_align(1, 1).unwrap();
+        unsafe { region.request_chunk(small).unwrap() };
+
+        let aligned = Layout::from_size_align(4096, 1 << 20).unwrap();
+        let p = unsafe { region.request_chunk(aligned).unwrap() };
+        assert_eq!(addr(p) % (1 << 20), 0);
+
+        let too_big = Layout::from_size_align(8 << 20, 8).unwrap();
+        assert!(unsafe { region.request_chunk(too_big) }.is_none());
+    }
+
+    #[test]
+    fn bump_allocator_grows_within_the_region() {
+        let region = ReservedRegion::new(64 << 20).unwrap();
+        let base = region.base().as_ptr().addr();
+        let mut bump = BumpAllocator::new(region);
+
+        let l = Layout::from_size_align(3000, 8).unwrap();
+        for _ in 0..1000 {
+            let p = unsafe { bump.alloc(l).unwrap() };
+            assert!((base..base + (64 << 20)).contains(&addr(p)));
+            unsafe { p.cast::<u8>().write_bytes(0, l.size()) };
+        }
+    }
+}
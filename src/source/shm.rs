@@ -0,0 +1,496 @@
+use core::{
+    alloc::Layout,
+    ffi::CStr,
+    mem,
+    ptr::NonNull,
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+    time::Duration,
+};
+use std::{io, thread};
+
+use crate::{
+    align::align_up,
+    source::{
+        MemorySource,
+        file::{file_len, map, truncate},
+        os_heap::page_size,
+    },
+};
+
+const MAGIC: u64 = u64::from_le_bytes(*b"RIKUSHM\0");
+
+/// 作った側がヘッダを書き終えるのを待つ回数（1 回 1ms）
+const OPEN_RETRIES: usize = 1000;
+
+/// 共有メモリ（`memfd_create` か POSIX 共有メモリ）からチャンクを切り出す `MemorySource`。
+///
+/// 共有メモリ全体を `capacity` バイト分まとめて `MAP_SHARED` で map し、先頭のヘッダに
+/// 切り出し位置を置いて、チャンクは先頭から順に切り出す。切り出し位置は共有メモリの中にあるので、
+/// 同じ fd を map した複数のプロセスが同時にチャンクを取っても重ならない。
+///
+/// 他のプロセスでは map されるアドレスが違うので、チャンクの場所は `offset_of` で
+/// 共有メモリの先頭からのオフセットにして渡し、`ptr_at` でポインタに戻す。
+/// ページサイズより大きいアラインは、チャンクを取ったプロセスでのアドレスに対してだけ満たされる。
+///
+/// `release_chunk` されたチャンクは `fallocate` で穴を開けてメモリを返す。
+/// それが一番最後に切り出したチャンクであれば、切り出し位置も戻す。
+pub struct SharedMemory {
+    fd: libc::c_int,
+    base: NonNull<Header>,
+}
+
+/// 共有メモリの先頭に置くヘッダ
+#[repr(C)]
+struct Header {
+    /// ヘッダを書き終えたら最後に Release で書く
+    magic: AtomicU64,
+    /// map する大きさ
+    capacity: usize,
+    /// 次のチャンクを切り出す位置
+    len: AtomicUsize,
+}
+
+unsafe impl Send for SharedMemory {}
+
+impl SharedMemory {
+    /// `memfd_create` で名前のない共有メモリを作る。
+    ///
+    /// fd は close-on-exec にしないので、fork した子プロセスや exec したプロセスにそのまま渡せる。
+    pub fn memfd(name: &CStr, capacity: usize) -> io::Result<Self> {
+        let fd = unsafe { libc::memfd_create(name.as_ptr(), 0) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        unsafe { Self::init(fd, capacity) }
+    }
+
+    /// 名前付きの POSIX 共有メモリを開く。なければ作る。
+    ///
+    /// 同じ名前を開いたプロセスどうしで同じチャンクを共有できる。
+    /// 他のプロセスが作っている途中なら、ヘッダを書き終えるまで待つ。
+    /// 使い終わったら `unlink` で名前を消す。
+    pub fn open(name: &CStr, capacity: usize) -> io::Result<Self> {
+        let flags = libc::O_RDWR | libc::O_CREAT | libc::O_EXCL;
+        let fd = unsafe { libc::shm_open(name.as_ptr(), flags, 0o600) };
+        if fd >= 0 {
+            return unsafe { Self::init(fd, capacity) };
+        }
+
+        let e = io::Error::last_os_error();
+        if e.kind() != io::ErrorKind::AlreadyExists {
+            return Err(e);
+        }
+
+        let fd = unsafe { libc::shm_open(name.as_ptr(), libc::O_RDWR, 0) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        unsafe { Self::from_fd(fd) }
+    }
+
+    /// 名前付きの POSIX 共有メモリの名前を消す。
+    pub fn unlink(name: &CStr) -> io::Result<()> {
+        if unsafe { libc::shm_unlink(name.as_ptr()) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// 他のプロセスから受け取った fd の共有メモリを map する。
+    ///
+    /// 作った側がまだヘッダを書いていなければ、書き終えるまで少し待ち、
+    /// それでも書かれなければ `TimedOut` を返す。
+    ///
+    /// # Safety
+    /// - `fd` は `SharedMemory` が作った共有メモリの fd でなければなりません。
+    ///   fd の所有権は `SharedMemory` に移り、drop するときに閉じられます。
+    pub unsafe fn from_fd(fd: libc::c_int) -> io::Result<Self> {
+        let mapped = Self::wait_for_header(fd)
+            .and_then(|capacity| map(fd, capacity, None));
+
+        match mapped {
+            Ok(ptr) => Ok(Self {
+                fd,
+                base: ptr.cast::<Header>(),
+            }),
+            Err(e) => {
+                unsafe { libc::close(fd) };
+                Err(e)
+            }
+        }
+    }
+
+    /// 作った側が `magic` を書くまで待ち、共有メモリの大きさを返す。
+    fn wait_for_header(fd: libc::c_int) -> io::Result<usize> {
+        let header_size = Self::header_size();
+
+        for _ in 0..OPEN_RETRIES {
+            // ftruncate の前に map したページに触ると SIGBUS になる
+            if file_len(fd)? >= header_size {
+                let header = map(fd, header_size, None)?.cast::<Header>();
+                let (magic, capacity) = unsafe {
+                    let header = header.as_ref();
+                    // Acquire で読んだ magic が書かれていれば、capacity も書き終わっている
+                    (header.magic.load(Ordering::Acquire), header.capacity)
+                };
+                unsafe {
+                    libc::munmap(
+                        header.as_ptr().cast::<libc::c_void>(),
+                        header_size,
+                    )
+                };
+
+                match magic {
+                    MAGIC
+                        if capacity.is_multiple_of(page_size())
+                            && capacity > header_size =>
+                    {
+                        return Ok(capacity);
+                    }
+                    // まだ書かれていない
+                    0 => {}
+                    _ => {
+                        return Err(io::Error::from(
+                            io::ErrorKind::InvalidData,
+                        ));
+                    }
+                }
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        Err(io::Error::from(io::ErrorKind::TimedOut))
+    }
+
+    /// 作ったばかりの共有メモリにヘッダを書いて map する
+    unsafe fn init(fd: libc::c_int, capacity: usize) -> io::Result<Self> {
+        let header_size = Self::header_size();
+        let capacity = align_up(capacity, page_size());
+
+        let mapped = if capacity <= header_size {
+            Err(io::Error::from(io::ErrorKind::InvalidInput))
+        } else {
+            truncate(fd, header_size).and_then(|()| map(fd, capacity, None))
+        };
+        let ptr = match mapped {
+            Ok(ptr) => ptr,
+            Err(e) => {
+                unsafe { libc::close(fd) };
+                return Err(e);
+            }
+        };
+
+        let base = ptr.cast::<Header>();
+        // 開く側が同時に magic を読んでいるかもしれないので、ヘッダ全体は書かない。
+        // ftruncate で伸ばしたばかりのヘッダは 0 で埋まっていて、magic は 0 のまま
+        unsafe {
+            let header = base.as_ptr();
+            (&raw mut (*header).capacity).write(capacity);
+            (&raw mut (*header).len).write(AtomicUsize::new(header_size));
+            // 開く側は magic を見てから他のフィールドを読むので、最後に書く
+            (*header).magic.store(MAGIC, Ordering::Release);
+        }
+
+        Ok(Self { fd, base })
+    }
+
+    /// 共有メモリの fd。他のプロセスに渡して `from_fd` で map してもらう。
+    pub fn fd(&self) -> libc::c_int {
+        self.fd
+    }
+
+    /// このプロセスで map した共有メモリの先頭
+    pub fn base(&self) -> NonNull<u8> {
+        self.base.cast::<u8>()
+    }
+
+    /// map している大きさ
+    pub fn capacity(&self) -> usize {
+        self.header().capacity
+    }
+
+    /// チャンクとして切り出した範囲の末尾（ヘッダを含む）
+    pub fn used_bytes(&self) -> usize {
+        self.header().len.load(Ordering::Acquire)
+    }
+
+    /// `ptr` の共有メモリの先頭からのオフセット。共有メモリの外なら `None`。
+    pub fn offset_of<T>(&self, ptr: NonNull<T>) -> Option<usize> {
+        let offset =
+            ptr.as_ptr().addr().checked_sub(self.base.as_ptr().addr())?;
+        (offset < self.capacity()).then_some(offset)
+    }
+
+    /// オフセットを、このプロセスでのポインタに戻す。
+    pub fn ptr_at<T>(&self, offset: usize) -> Option<NonNull<T>> {
+        (offset < self.capacity())
+            .then(|| unsafe { self.base().add(offset).cast::<T>() })
+    }
+
+    fn header(&self) -> &Header {
+        unsafe { self.base.as_ref() }
+    }
+
+    fn header_size() -> usize {
+        align_up(mem::size_of::<Header>(), page_size())
+    }
+}
+
+impl MemorySource for SharedMemory {
+    unsafe fn request_chunk(
+        &mut self,
+        layout: Layout,
+    ) -> Option<NonNull<[u8]>> {
+        let page_size = page_size();
+        let align = layout.align().max(page_size);
+        let size = align_up(layout.size().max(1), page_size);
+        let base_addr = self.base.as_ptr().addr();
+        let capacity = self.capacity();
+
+        // 他のプロセスと取り合うので、切り出し位置は CAS で進める
+        let header = self.header();
+        let mut len = header.len.load(Ordering::Acquire);
+        let (start, end) = loop {
+            let start =
+                align_up(base_addr.checked_add(len)?, align) - base_addr;
+            let end = start.checked_add(size)?;
+            if end > capacity {
+                return None;
+            }
+
+            match header.len.compare_exchange_weak(
+                len,
+                end,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => break (start, end),
+                Err(current) => len = current,
+            }
+        };
+
+        // ftruncate と違って共有メモリを縮めることがないので、同時に伸ばしても安全
+        let ret = unsafe {
+            libc::fallocate(
+                self.fd,
+                0,
+                start as libc::off_t,
+                (end - start) as libc::off_t,
+            )
+        };
+        if ret != 0 {
+            let _ = header.len.compare_exchange(
+                end,
+                len,
+                Ordering::AcqRel,
+                Ordering::Relaxed,
+            );
+            return None;
+        }
+
+        let ptr = unsafe { self.base().add(start) };
+        Some(NonNull::slice_from_raw_parts(ptr, size))
+    }
+
+    unsafe fn release_chunk(&mut self, ptr: NonNull<u8>, layout: Layout) {
+        let Some(start) = self.offset_of(ptr) else {
+            debug_assert!(false, "chunk is not in this shared memory");
+            return;
+        };
+        let size = align_up(layout.size(), page_size());
+
+        unsafe {
+            libc::fallocate(
+                self.fd,
+                libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+                start as libc::off_t,
+                size as libc::off_t,
+            );
+        }
+
+        // 最後に切り出したチャンクなら切り出し位置を戻す
+        let _ = self.header().len.compare_exchange(
+            start + size,
+            start,
+            Ordering::AcqRel,
+            Ordering::Relaxed,
+        );
+    }
+}
+
+impl Drop for SharedMemory {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(
+                self.base.as_ptr().cast::<libc::c_void>(),
+                self.capacity(),
+            );
+            libc::close(self.fd);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::CString;
+
+    /// 子プロセスで `f` を実行し、終了コードを返す
+    fn in_child(f: impl FnOnce() -> i32) -> i32 {
+        let pid = unsafe { libc::fork() };
+        assert!(pid >= 0);
+
+        if pid == 0 {
+            let code = f();
+            unsafe { libc::_exit(code) };
+        }
+
+        let mut status = 0;
+        assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+        assert!(libc::WIFEXITED(status));
+        libc::WEXITSTATUS(status)
+    }
+
+    fn chunk(shm: &mut SharedMemory, size: usize) -> NonNull<u8> {
+        let layout = Layout::from_size_align(size, 8).unwrap();
+        unsafe { shm.request_chunk(layout).unwrap() }.cast::<u8>()
+    }
+
+    #[test]
+    fn child_writes_are_visible_to_the_parent() {
+        let mut shm = SharedMemory::memfd(c"rikualloc-test", 1 << 20).unwrap();
+        let p = chunk(&mut shm, 4096).cast::<u64>();
+
+        let code = in_child(|| {
+            unsafe { p.write_volatile(0xDEAD_BEEF) };
+            0
+        });
+        assert_eq!(code, 0);
+        assert_eq!(unsafe { p.read_volatile() }, 0xDEAD_BEEF);
+    }
+
+    #[test]
+    fn child_can_map_the_fd_and_take_its_own_chunks() {
+        let mut shm = SharedMemory::memfd(c"rikualloc-test", 1 << 20).unwrap();
+        // 子プロセスがチャンクのオフセットを書き込む場所
+        let mailbox = chunk(&mut shm, 8).cast::<usize>();
+        let mailbox_offset = shm.offset_of(mailbox).unwrap();
+        let fd = shm.fd();
+
+        let code = in_child(|| {
+            let Ok(mut other) =
+                (unsafe { SharedMemory::from_fd(libc::dup(fd)) })
+            else {
+                return 1;
+            };
+
+            let p = chunk(&mut other, 100);
+            unsafe { p.write_bytes(0x42, 100) };
+
+            let mailbox = other.ptr_at::<usize>(mailbox_offset).unwrap();
+            unsafe { mailbox.write_volatile(other.offset_of(p).unwrap()) };
+            0
+        });
+        assert_eq!(code, 0);
+
+        // 子プロセスが取ったチャンクは親が取ったチャンクと重ならない
+        let offset = unsafe { mailbox.read_volatile() };
+        assert!(offset > mailbox_offset);
+
+        let p = shm.ptr_at::<[u8; 100]>(offset).unwrap();
+        assert_eq!(unsafe { p.read() }, [0x42; 100]);
+
+        // 子プロセスの取った分も共有の切り出し位置に反映されている
+        let q = chunk(&mut shm, 8);
+        assert!(shm.offset_of(q).unwrap() > offset);
+    }
+
+    /// 並行して走る他のテストと名前がぶつからないよう、pid を入れた名前
+    fn shm_name(name: &str) -> CString {
+        CString::new(std::format!("/rikualloc-{}-{name}", std::process::id()))
+            .unwrap()
+    }
+
+    #[test]
+    fn named_shared_memory_is_shared_between_handles() {
+        let name = &shm_name("named");
+        let _ = SharedMemory::unlink(name);
+
+        let mut a = SharedMemory::open(name, 1 << 20).unwrap();
+        let p = chunk(&mut a, 4096);
+        unsafe { p.write_bytes(7, 4096) };
+        let offset = a.offset_of(p).unwrap();
+
+        let b = SharedMemory::open(name, 1 << 20).unwrap();
+        assert_eq!(b.capacity(), a.capacity());
+        assert_eq!(b.used_bytes(), a.used_bytes());
+        let q = b.ptr_at::<[u8; 4096]>(offset).unwrap();
+        assert_eq!(unsafe { q.read() }, [7; 4096]);
+
+        SharedMemory::unlink(name).unwrap();
+    }
+
+    #[test]
+    fn open_waits_for_the_creator_to_write_the_header() {
+        let name = &shm_name("race");
+        let _ = SharedMemory::unlink(name);
+
+        // open の O_EXCL が成功した直後、まだヘッダを書いていない状態を作る
+        let flags = libc::O_RDWR | libc::O_CREAT | libc::O_EXCL;
+        let fd = unsafe { libc::shm_open(name.as_ptr(), flags, 0o600) };
+        assert!(fd >= 0);
+
+        let creator = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            unsafe { SharedMemory::init(fd, 1 << 20) }.map(|_| ())
+        });
+
+        let shm = SharedMemory::open(name, 1 << 20).unwrap();
+        assert_eq!(shm.capacity(), 1 << 20);
+        assert_eq!(shm.used_bytes(), SharedMemory::header_size());
+
+        creator.join().unwrap().unwrap();
+        SharedMemory::unlink(name).unwrap();
+    }
+
+    #[test]
+    fn from_fd_rejects_other_files() {
+        let fd = unsafe { libc::memfd_create(c"rikualloc-test".as_ptr(), 0) };
+        assert!(fd >= 0);
+        truncate(fd, SharedMemory::header_size()).unwrap();
+        let garbage = [0xFFu8; 8];
+        let n = unsafe {
+            libc::pwrite(fd, garbage.as_ptr().cast::<libc::c_void>(), 8, 0)
+        };
+        assert_eq!(n, 8);
+
+        let err = unsafe { SharedMemory::from_fd(fd) }.err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn releasing_the_last_chunk_rewinds() {
+        let mut shm = SharedMemory::memfd(c"rikualloc-test", 1 << 20).unwrap();
+        let l = Layout::from_size_align(4096, 8).unwrap();
+
+        let p = chunk(&mut shm, 4096);
+        let used = shm.used_bytes();
+        let q = chunk(&mut shm, 4096);
+        unsafe { q.write_bytes(1, 4096) };
+
+        unsafe { shm.release_chunk(q, l) };
+        assert_eq!(shm.used_bytes(), used);
+
+        // 穴を開けたので中身は 0 に戻っている
+        let r = chunk(&mut shm, 4096);
+        assert_eq!(r, q);
+        assert_eq!(unsafe { r.read() }, 0);
+
+        unsafe {
+            shm.release_chunk(r, l);
+            shm.release_chunk(p, l);
+        }
+        assert_eq!(shm.used_bytes(), SharedMemory::header_size());
+    }
+}
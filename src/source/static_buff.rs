@@ -1,16 +1,31 @@
-use core::{
-    alloc::Layout,
-    cell::UnsafeCell,
-    mem::MaybeUninit,
-    ptr::NonNull,
-    sync::atomic::{AtomicBool, Ordering},
-};
+use core::{alloc::Layout, cell::UnsafeCell, mem::MaybeUninit, ptr::NonNull};
+
+use spin::Mutex;
 
 use crate::source::MemorySource;
 
+/// ビットマップで管理するページの最大数
+const MAX_PAGES: usize = 1024;
+
+/// ページの最小サイズ
+const MIN_PAGE_SIZE: usize = 64;
+
+const WORD_BITS: usize = u64::BITS as usize;
+
+/// 静的に確保したバッファを、ページ単位のチャンクに切り分けて渡す `MemorySource`。
+///
+/// バッファを `PAGE_SIZE` ごとのページに区切り、使用中のページをビットマップで管理する。
+/// `N` が `PAGE_SIZE` で割り切れなければ、最後のページは端数の大きさになる。
+/// `request_chunk` は要求を満たす連続した空きページを先頭から探して返し、
+/// `release_chunk` されたページは再び使えるようになる。
+///
+/// バッファは構造体の先頭に置くので、その先頭は `StaticBuffer` の align（`u64` の align）に揃う。
+/// それより大きな align のチャンクが必要なら、`StaticBuffer` を包む型で static 自体をアラインしておく。
+#[repr(C)]
 pub struct StaticBuffer<const N: usize> {
     buffer: UnsafeCell<[MaybeUninit<u8>; N]>,
-    taken: AtomicBool,
+    /// 使用中のページのビットマップ
+    used: Mutex<[u64; MAX_PAGES / WORD_BITS]>,
 }
 
 unsafe impl<const N: usize> Sync for StaticBuffer<N> {}
@@ -29,40 +44,87 @@ impl<const N: usize> MemorySource for &StaticBuffer<N> {
 }
 
 impl<const N: usize> StaticBuffer<N> {
+    /// ページ 1 つの大きさ。
+    /// ページ数が `MAX_PAGES` を超えないよう、`N` に応じて 2 の冪で決まる。
+    pub const PAGE_SIZE: usize = {
+        let size = N.div_ceil(MAX_PAGES).next_power_of_two();
+        if size < MIN_PAGE_SIZE {
+            MIN_PAGE_SIZE
+        } else {
+            size
+        }
+    };
+
+    /// ページの数（端数のページも含む）
+    const PAGES: usize = N.div_ceil(Self::PAGE_SIZE);
+
     pub const fn new() -> Self {
         Self {
             buffer: UnsafeCell::new([MaybeUninit::uninit(); N]),
-            taken: AtomicBool::new(false),
+            used: Mutex::new([0; MAX_PAGES / WORD_BITS]),
         }
     }
 
+    fn base(&self) -> *mut u8 {
+        self.buffer.get().cast::<u8>()
+    }
+
     unsafe fn request_chunk_impl(
         &self,
         layout: Layout,
     ) -> Option<NonNull<[u8]>> {
-        if self.taken.swap(true, Ordering::AcqRel) {
-            return None;
-        }
+        let pages = layout.size().max(1).div_ceil(Self::PAGE_SIZE);
+        let base = self.base();
 
-        let base = unsafe { (*self.buffer.get()).as_mut_ptr().cast::<u8>() };
+        let mut used = self.used.lock();
 
-        let pad = base.align_offset(layout.align());
-        if pad == usize::MAX || pad > N {
-            return None;
-        }
+        // 先頭から、アラインを満たす位置で始まる空きページの並びを探す
+        let mut start = 0;
+        while start + pages <= Self::PAGES {
+            let addr = base.addr() + start * Self::PAGE_SIZE;
+            if !addr.is_multiple_of(layout.align()) {
+                start += 1;
+                continue;
+            }
 
-        let avail = N - pad;
-        if avail < layout.size() {
-            return None;
-        }
+            // 端数のページで終わるときは、バッファの終わりまで
+            let offset = start * Self::PAGE_SIZE;
+            let len = ((start + pages) * Self::PAGE_SIZE).min(N) - offset;
+            if len < layout.size() {
+                // これより後ろから始めても足りない
+                return None;
+            }
+
+            match (start..start + pages).find(|&i| is_used(&*used, i)) {
+                Some(i) => start = i + 1,
+                None => {
+                    for i in start..start + pages {
+                        set_used(&mut *used, i, true);
+                    }
 
-        let start = unsafe { base.add(pad) };
-        let nn = NonNull::new(start)?;
+                    let ptr = unsafe { base.add(offset) };
+                    let nn = NonNull::new(ptr)?;
+                    return Some(NonNull::slice_from_raw_parts(nn, len));
+                }
+            }
+        }
 
-        Some(NonNull::slice_from_raw_parts(nn, avail))
+        None
     }
 
-    fn release_chunk_impl(&self, _ptr: NonNull<u8>, _layout: Layout) {}
+    fn release_chunk_impl(&self, ptr: NonNull<u8>, layout: Layout) {
+        let offset = ptr.as_ptr().addr() - self.base().addr();
+        debug_assert!(offset.is_multiple_of(Self::PAGE_SIZE));
+
+        let start = offset / Self::PAGE_SIZE;
+        let pages = layout.size().max(1).div_ceil(Self::PAGE_SIZE);
+
+        let mut used = self.used.lock();
+        for i in start..start + pages {
+            debug_assert!(is_used(&*used, i));
+            set_used(&mut *used, i, false);
+        }
+    }
 }
 
 impl<const N: usize> Default for StaticBuffer<N> {
@@ -70,3 +132,207 @@ impl<const N: usize> Default for StaticBuffer<N> {
         Self::new()
     }
 }
+
+fn is_used(bitmap: &[u64], page: usize) -> bool {
+    bitmap[page / WORD_BITS] & (1 << (page % WORD_BITS)) != 0
+}
+
+fn set_used(bitmap: &mut [u64], page: usize, used: bool) {
+    let bit = 1 << (page % WORD_BITS);
+    if used {
+        bitmap[page / WORD_BITS] |= bit;
+    } else {
+        bitmap[page / WORD_BITS] &= !bit;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::allocator::{MutAllocator, free_list::FreeList};
+    use core::mem;
+
+    fn addr(p: NonNull<[u8]>) -> usize {
+        p.cast::<u8>().as_ptr().addr()
+    }
+
+    #[test]
+    fn page_size_depends_on_buffer_size() {
+        assert_eq!(StaticBuffer::<4096>::PAGE_SIZE, MIN_PAGE_SIZE);
+        assert_eq!(StaticBuffer::<{ 1024 * 1024 }>::PAGE_SIZE, 1024);
+        assert_eq!(StaticBuffer::<{ 1024 * 1024 + 1 }>::PAGE_SIZE, 2048);
+    }
+
+    #[test]
+    fn hands_out_multiple_chunks() {
+        static BUFFER: StaticBuffer<{ 64 * 1024 }> = StaticBuffer::new();
+        let mut source = &BUFFER;
+        let page = StaticBuffer::<{ 64 * 1024 }>::PAGE_SIZE;
+
+        let l = Layout::from_size_align(page - 8, 8).unwrap();
+        let a = unsafe { source.request_chunk(l).unwrap() };
+        let b = unsafe { source.request_chunk(l).unwrap() };
+        assert_eq!(a.len(), page);
+        assert_eq!(addr(b), addr(a) + page);
+
+        // 使い切ると None
+        let rest = Layout::from_size_align(64 * 1024 - 2 * page, 8).unwrap();
+        let c = unsafe { source.request_chunk(rest).unwrap() };
+        assert!(unsafe { source.request_chunk(l) }.is_none());
+
+        unsafe {
+            source.release_chunk(
+                a.cast(),
+                Layout::from_size_align(a.len(), 8).unwrap(),
+            );
+            source.release_chunk(
+                b.cast(),
+                Layout::from_size_align(b.len(), 8).unwrap(),
+            );
+            source.release_chunk(
+                c.cast(),
+                Layout::from_size_align(c.len(), 8).unwrap(),
+            );
+        }
+    }
+
+    #[test]
+    fn released_chunks_are_reused() {
+        static BUFFER: StaticBuffer<{ 64 * 1024 }> = StaticBuffer::new();
+        let mut source = &BUFFER;
+
+        let l = Layout::from_size_align(300, 8).unwrap();
+        let a = unsafe { source.request_chunk(l).unwrap() };
+        let b = unsafe { source.request_chunk(l).unwrap() };
+
+        let actual = Layout::from_size_align(a.len(), 8).unwrap();
+        unsafe { source.release_chunk(a.cast(), actual) };
+        let c = unsafe { source.request_chunk(l).unwrap() };
+        assert_eq!(addr(c), addr(a));
+
+        // 隣り合う空きページはまとめて使える
+        unsafe {
+            source.release_chunk(c.cast(), actual);
+            source.release_chunk(b.cast(), actual);
+        }
+        let big = Layout::from_size_align(a.len() + b.len(), 8).unwrap();
+        let d = unsafe { source.request_chunk(big).unwrap() };
+        assert_eq!(addr(d), addr(a));
+        unsafe {
+            source.release_chunk(
+                d.cast(),
+                Layout::from_size_align(d.len(), 8).unwrap(),
+            )
+        };
+    }
+
+    #[test]
+    fn tiny_buffers_are_served_whole() {
+        static TINY: StaticBuffer<32> = StaticBuffer::new();
+        let mut source = &TINY;
+
+        let l = Layout::from_size_align(32, 8).unwrap();
+        let p = unsafe { source.request_chunk(l).unwrap() };
+        assert_eq!(p.len(), 32);
+        assert!(unsafe { source.request_chunk(l) }.is_none());
+
+        unsafe { source.release_chunk(p.cast(), l) };
+        assert!(unsafe { source.request_chunk(l) }.is_some());
+    }
+
+    #[test]
+    fn the_tail_page_is_usable() {
+        static BUFFER: StaticBuffer<100> = StaticBuffer::new();
+        let mut source = &BUFFER;
+        assert_eq!(StaticBuffer::<100>::PAGE_SIZE, 64);
+
+        // バッファ全体を 1 つのチャンクとして取れる
+        let whole = Layout::from_size_align(100, 4).unwrap();
+        let p = unsafe { source.request_chunk(whole).unwrap() };
+        assert_eq!(p.len(), 100);
+        unsafe { source.release_chunk(p.cast(), whole) };
+
+        // 1 ページ目を取ると、残りは端数の 36 バイト
+        let page = Layout::from_size_align(64, 4).unwrap();
+        let a = unsafe { source.request_chunk(page).unwrap() };
+        let tail = Layout::from_size_align(36, 4).unwrap();
+        let b = unsafe { source.request_chunk(tail).unwrap() };
+        assert_eq!(addr(b), addr(a) + 64);
+        assert_eq!(b.len(), 36);
+        assert!(unsafe { source.request_chunk(tail) }.is_none());
+
+        unsafe {
+            source.release_chunk(a.cast(), page);
+            source.release_chunk(b.cast(), tail);
+        }
+    }
+
+    #[test]
+    fn buffer_is_not_padded() {
+        let size = mem::size_of::<StaticBuffer<4096>>();
+        assert!(size < 4096 + 256, "{size}");
+        assert_eq!(
+            mem::align_of::<StaticBuffer<4096>>(),
+            mem::align_of::<u64>()
+        );
+    }
+
+    #[test]
+    fn chunks_respect_alignment() {
+        // 大きな align のチャンクを取れるよう、static 自体をアラインしておく
+        #[repr(align(65536))]
+        struct Aligned(StaticBuffer<{ 256 * 1024 }>);
+        static BUFFER: Aligned = Aligned(StaticBuffer::new());
+        let mut source = &BUFFER.0;
+
+        let small = Layout::from_size_align(1, 1).unwrap();
+        let first = unsafe { source.request_chunk(small).unwrap() };
+
+        for align in [4096, 16 * 1024, 64 * 1024] {
+            let l = Layout::from_size_align(1000, align).unwrap();
+            let p = unsafe { source.request_chunk(l).unwrap() };
+            assert_eq!(addr(p) % align, 0);
+            assert!(p.len() >= 1000);
+            unsafe {
+                source.release_chunk(
+                    p.cast(),
+                    Layout::from_size_align(p.len(), align).unwrap(),
+                )
+            };
+        }
+
+        unsafe {
+            source.release_chunk(
+                first.cast(),
+                Layout::from_size_align(first.len(), 1).unwrap(),
+            )
+        };
+    }
+
+    #[test]
+    fn free_list_can_take_more_than_one_chunk() {
+        static BUFFER: StaticBuffer<{ 64 * 1024 }> = StaticBuffer::new();
+        let mut list = FreeList::new(&BUFFER).with_retain(0);
+
+        let l = Layout::from_size_align(8 * 1024, 8).unwrap();
+        let ptrs: std::vec::Vec<_> =
+            (0..4).map(|_| unsafe { list.alloc(l).unwrap() }).collect();
+        for p in ptrs {
+            unsafe { list.dealloc(p.cast(), l) };
+        }
+
+        // dealloc で空いたチャンクはすぐ返されているので、アロケータが生きているうちに
+        // バッファの全体を取れる
+        let mut source = &BUFFER;
+        let whole = Layout::from_size_align(64 * 1024, 8).unwrap();
+        let p = unsafe { source.request_chunk(whole).unwrap() };
+        unsafe { source.release_chunk(p.cast(), whole) };
+
+        // アロケータも続けて使える
+        let q = unsafe { list.alloc(l).unwrap() };
+        unsafe { list.dealloc(q.cast(), l) };
+
+        drop(list);
+        assert!(unsafe { source.request_chunk(whole) }.is_some());
+    }
+}
@@ -0,0 +1,158 @@
+//! source の使用量を数える。
+
+use core::{alloc::Layout, ptr::NonNull};
+
+use crate::source::MemorySource;
+
+/// 内側の source への `request_chunk` と `release_chunk` を数える `MemorySource`。
+///
+/// アロケータに渡したあとで値を見るには、`&mut CountingSource` か
+/// `&Locked<CountingSource>` を source として渡す。
+pub struct CountingSource<S: MemorySource> {
+    source: S,
+    stats: SourceStats,
+}
+
+/// `CountingSource` が記録した値
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SourceStats {
+    /// 成功した `request_chunk` の回数
+    pub requests: usize,
+    /// `release_chunk` の回数
+    pub releases: usize,
+    /// 失敗した `request_chunk` の回数
+    pub failures: usize,
+
+    /// いま取っているチャンクの合計バイト数（実際の大きさ）
+    pub mapped_bytes: usize,
+    /// `mapped_bytes` の最大値
+    pub peak_mapped_bytes: usize,
+    /// これまでに取ったチャンクの合計バイト数
+    pub total_mapped_bytes: usize,
+}
+
+impl<S: MemorySource> CountingSource<S> {
+    pub const fn new(source: S) -> Self {
+        Self {
+            source,
+            stats: SourceStats {
+                requests: 0,
+                releases: 0,
+                failures: 0,
+                mapped_bytes: 0,
+                peak_mapped_bytes: 0,
+                total_mapped_bytes: 0,
+            },
+        }
+    }
+
+    /// いままでに記録した値
+    pub fn stats(&self) -> SourceStats {
+        self.stats
+    }
+
+    /// 内側の source
+    pub fn source(&mut self) -> &mut S {
+        &mut self.source
+    }
+}
+
+impl<S: MemorySource> MemorySource for CountingSource<S> {
+    unsafe fn request_chunk(
+        &mut self,
+        layout: Layout,
+    ) -> Option<NonNull<[u8]>> {
+        let Some(chunk) = (unsafe { self.source.request_chunk(layout) }) else {
+            self.stats.failures += 1;
+            return None;
+        };
+
+        let stats = &mut self.stats;
+        stats.requests += 1;
+        stats.mapped_bytes += chunk.len();
+        stats.total_mapped_bytes += chunk.len();
+        stats.peak_mapped_bytes =
+            stats.peak_mapped_bytes.max(stats.mapped_bytes);
+
+        Some(chunk)
+    }
+
+    unsafe fn release_chunk(&mut self, ptr: NonNull<u8>, layout: Layout) {
+        unsafe { self.source.release_chunk(ptr, layout) };
+
+        self.stats.releases += 1;
+        self.stats.mapped_bytes -= layout.size();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        allocator::{MutAllocator, bump::BumpAllocator, free_list::FreeList},
+        mutex::Locked,
+        source::{limit::Limited, os_heap::OsHeap},
+    };
+
+    #[test]
+    fn counts_requests_and_releases() {
+        let mut source = CountingSource::new(OsHeap);
+        let l = Layout::from_size_align(5000, 8).unwrap();
+
+        let a = unsafe { source.request_chunk(l).unwrap() };
+        let b = unsafe { source.request_chunk(l).unwrap() };
+        let actual = Layout::from_size_align(a.len(), 8).unwrap();
+        unsafe { source.release_chunk(a.cast(), actual) };
+
+        let s = source.stats();
+        assert_eq!((s.requests, s.releases, s.failures), (2, 1, 0));
+        assert_eq!(s.mapped_bytes, b.len());
+        assert_eq!(s.peak_mapped_bytes, a.len() + b.len());
+        assert_eq!(s.total_mapped_bytes, a.len() + b.len());
+
+        unsafe { source.release_chunk(b.cast(), actual) };
+        assert_eq!(source.stats().mapped_bytes, 0);
+    }
+
+    #[test]
+    fn counts_failures() {
+        let mut source = CountingSource::new(Limited::new(OsHeap, 4096));
+        let l = Layout::from_size_align(8192, 8).unwrap();
+
+        assert!(unsafe { source.request_chunk(l) }.is_none());
+        let s = source.stats();
+        assert_eq!((s.requests, s.failures, s.mapped_bytes), (0, 1, 0));
+    }
+
+    #[test]
+    fn sees_the_chunks_of_an_allocator() {
+        let mut source = CountingSource::new(OsHeap);
+        {
+            let mut bump = BumpAllocator::new(&mut source);
+            let l = Layout::from_size_align(3000, 8).unwrap();
+            for _ in 0..4 {
+                unsafe { bump.alloc(l).unwrap() };
+            }
+        }
+
+        let s = source.stats();
+        assert_eq!(s.requests, 4);
+        assert_eq!(s.releases, 4);
+        assert_eq!(s.mapped_bytes, 0);
+        assert!(s.peak_mapped_bytes >= 4 * 3000);
+    }
+
+    #[test]
+    fn works_behind_locked() {
+        let source = Locked::new(CountingSource::new(OsHeap));
+        let mut list = FreeList::new(&source).with_retain(0);
+
+        let l = Layout::from_size_align(100, 8).unwrap();
+        let p = unsafe { list.alloc(l).unwrap() };
+        assert_eq!(source.with_lock(|s| s.stats().mapped_bytes), 4096);
+
+        unsafe { list.dealloc(p.cast(), l) };
+        let s = source.with_lock(|s| s.stats());
+        assert_eq!((s.requests, s.releases, s.mapped_bytes), (1, 1, 0));
+    }
+}